@@ -2,31 +2,134 @@
 
 use std::cell::UnsafeCell;
 use std::convert::TryInto;
-use std::mem::size_of;
+use std::mem::{size_of, MaybeUninit};
 use std::sync::Arc;
 
 use crate::{DecodeError, PacketIdentifier};
 
 pub trait BufferPool {
+    /// Takes a buffer of at least `len` bytes out of the pool, for the buffers that this crate allocates itself,
+    /// eg in [`crate::ByteStr::new`].
+    ///
+    /// The default implementation allocates a new buffer of exactly `len` bytes.
+    fn take(&self, len: usize) -> Arc<[u8]> {
+        vec![0; len].into()
+    }
+
     fn put_back(&self, backing: Arc<[u8]>);
+
+    /// Like [`BufferPool::put_back`], but for a buffer that was given to [`UninitOwned::new`]
+    /// and so may still contain uninitialized bytes.
+    ///
+    /// The default implementation frees the buffer.
+    fn put_back_uninit(&self, backing: Arc<[MaybeUninit<u8>]>) {
+        drop(backing);
+    }
 }
 
 impl<T> BufferPool for std::rc::Rc<T>
 where
     T: BufferPool,
 {
+    fn take(&self, len: usize) -> Arc<[u8]> {
+        (**self).take(len)
+    }
+
     fn put_back(&self, backing: Arc<[u8]>) {
         (&**self).put_back(backing);
     }
+
+    fn put_back_uninit(&self, backing: Arc<[MaybeUninit<u8>]>) {
+        (**self).put_back_uninit(backing);
+    }
 }
 
 impl<T> BufferPool for Arc<T>
 where
     T: BufferPool,
 {
+    fn take(&self, len: usize) -> Arc<[u8]> {
+        (**self).take(len)
+    }
+
     fn put_back(&self, backing: Arc<[u8]>) {
         (&**self).put_back(backing);
     }
+
+    fn put_back_uninit(&self, backing: Arc<[MaybeUninit<u8>]>) {
+        (**self).put_back_uninit(backing);
+    }
+}
+
+/// A [`BufferPool`] that hands out buffers of a fixed length and reuses the ones that are put back into it.
+///
+/// The pool also tracks how many of its buffers are outstanding, and the peak of that number,
+/// which can be used to choose how many buffers to preallocate.
+///
+/// Use it through an `Arc` so that [`Owned`] and [`Shared`] can hold a reference to it.
+/// Buffers of other lengths may also be given to the pool, but buffers of its own length must have been taken from it.
+#[derive(Debug)]
+pub struct ReusableBufferPool {
+    buffer_len: usize,
+    state: std::sync::Mutex<ReusableBufferPoolState>,
+}
+
+#[derive(Debug, Default)]
+struct ReusableBufferPoolState {
+    free: Vec<Arc<[u8]>>,
+    outstanding: usize,
+    high_water_mark: usize,
+}
+
+impl ReusableBufferPool {
+    /// Constructs an empty pool whose buffers are `buffer_len` bytes long.
+    pub fn new(buffer_len: usize) -> Self {
+        ReusableBufferPool {
+            buffer_len,
+            state: Default::default(),
+        }
+    }
+
+    /// The number of buffers that have been taken out of this pool and not yet put back.
+    pub fn outstanding(&self) -> usize {
+        self.state.lock().unwrap().outstanding
+    }
+
+    /// The largest number of buffers that have been outstanding at the same time.
+    pub fn high_water_mark(&self) -> usize {
+        self.state.lock().unwrap().high_water_mark
+    }
+}
+
+impl BufferPool for ReusableBufferPool {
+    /// Takes a buffer out of the pool, or allocates a new one if the pool is empty.
+    ///
+    /// A buffer longer than the pool's buffers is allocated separately, and does not count as outstanding.
+    fn take(&self, len: usize) -> Arc<[u8]> {
+        if len > self.buffer_len {
+            return vec![0; len].into();
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.outstanding += 1;
+        state.high_water_mark = std::cmp::max(state.high_water_mark, state.outstanding);
+        match state.free.pop() {
+            Some(backing) => backing,
+            None => vec![0; self.buffer_len].into(),
+        }
+    }
+
+    fn put_back(&self, backing: Arc<[u8]>) {
+        // Buffers of other lengths were not taken from this pool, so they are neither counted nor reused
+        if backing.len() == self.buffer_len {
+            // A buffer of the same length from elsewhere can't be told apart from one of this pool's,
+            // so don't let it underflow the count.
+            let mut state = self.state.lock().unwrap();
+            debug_assert!(state.outstanding > 0);
+            state.outstanding = state.outstanding.saturating_sub(1);
+            state.free.push(backing);
+        }
+    }
 }
 
 /// Owns a particular range of the backing buffer.
@@ -58,12 +161,31 @@ where
     range: std::ops::Range<usize>,
 }
 
+/// Like [`Owned`], but for a backing buffer that need not be initialized, eg to avoid the cost of zeroing a new buffer.
+///
+/// Only the filled region can be accessed, with [`UninitOwned::filled`], and bytes can only be added to it by writing them
+/// through the [`ByteBuf`](crate::ByteBuf) impl. So uninitialized bytes can never be read.
+///
+/// An `UninitOwned` can be converted to a [`Shared`] via [`UninitOwned::freeze`]. The backing buffer is eventually
+/// given back to the pool with [`BufferPool::put_back_uninit`] instead of [`BufferPool::put_back`]
+pub struct UninitOwned<P>
+where
+    P: BufferPool,
+{
+    backing: Backing<P>,
+    len: usize,
+    filled: usize,
+}
+
 #[derive(Clone)]
 struct Backing<P>
 where
     P: BufferPool,
 {
-    inner: Option<Arc<UnsafeCell<[u8]>>>,
+    // Contains initialized bytes unless `initialized` is false.
+    // Owned and Shared only hand out references to parts of it that are initialized.
+    inner: Option<Arc<UnsafeCell<[MaybeUninit<u8>]>>>,
+    initialized: bool,
     pool: P,
 }
 
@@ -73,8 +195,25 @@ where
 {
     /// The given `backing` must be the sole owner of its contents,
     /// ie its strong refcount must be 1 and its weak refcount must be 0.
-    pub fn new(pool: P, mut backing: Arc<[u8]>) -> Self {
-        assert!(Arc::get_mut(&mut backing).is_some());
+    ///
+    /// Panics if it isn't. See [`Owned::try_new`] for a non-panicking alternative.
+    pub fn new(pool: P, backing: Arc<[u8]>) -> Self {
+        match Self::try_new(pool, backing) {
+            Ok(owned) => owned,
+            Err(backing) => panic!(
+                "backing buffer of Owned must not be shared, but its strong refcount is {} and its weak refcount is {}",
+                Arc::strong_count(&backing),
+                Arc::weak_count(&backing),
+            ),
+        }
+    }
+
+    /// Like [`Owned::new`], but returns the given `backing` back as an error
+    /// if it is not the sole owner of its contents.
+    pub fn try_new(pool: P, mut backing: Arc<[u8]>) -> Result<Self, Arc<[u8]>> {
+        if Arc::get_mut(&mut backing).is_none() {
+            return Err(backing);
+        }
 
         let len = backing.len();
 
@@ -82,20 +221,22 @@ where
             // Converting Arc<T> into Arc<UnsafeCell<T>> via into_raw() -> as -> from_raw() is sound,
             // because from_raw() with a different inner type is fine as long as
             // the new type has the same size and alignment as the original type,
-            // which is true for UnsafeCell<T> because it's repr(transparent).
+            // which is true for UnsafeCell<T> because it's repr(transparent),
+            // and for MaybeUninit<u8> because it has the same layout as u8.
             let backing: *const [u8] = Arc::into_raw(backing);
-            let backing = backing as *const UnsafeCell<[u8]>;
+            let backing = backing as *const UnsafeCell<[MaybeUninit<u8>]>;
             let backing = Arc::from_raw(backing);
             Backing {
                 inner: Some(backing),
+                initialized: true,
                 pool,
             }
         };
-        Owned {
+        Ok(Owned {
             backing,
             range: 0..len,
             filled: 0,
-        }
+        })
     }
 
     pub fn filled_len(&self) -> usize {
@@ -152,7 +293,7 @@ where
             // because we only own a part of the self.backing.inner buffer and thus the &[u8] could overlap with
             // a &mut [u8] from another Owned with the same self.backing.
             // So we need to calculate the start *mut u8 manually and construct a &[u8] from that.
-            let backing: *mut [u8] = self.backing.inner.as_ref().unwrap().get();
+            let backing: *mut [MaybeUninit<u8>] = self.backing.inner.as_ref().unwrap().get();
             let ptr: *const u8 = backing.cast();
             let ptr = ptr.add(self.range.start);
             ptr
@@ -164,7 +305,7 @@ where
     pub fn unfilled(&self) -> &[u8] {
         let start = unsafe {
             // See Owned::filled for soundness notes.
-            let backing: *mut [u8] = self.backing.inner.as_ref().unwrap().get();
+            let backing: *mut [MaybeUninit<u8>] = self.backing.inner.as_ref().unwrap().get();
             let ptr: *const u8 = backing.cast();
             let ptr = ptr.add(self.filled);
             ptr
@@ -176,7 +317,7 @@ where
     pub fn unfilled_mut(&mut self) -> &mut [u8] {
         let start = unsafe {
             // See Owned::filled for soundness notes.
-            let backing: *mut [u8] = self.backing.inner.as_mut().unwrap().get();
+            let backing: *mut [MaybeUninit<u8>] = self.backing.inner.as_mut().unwrap().get();
             let ptr: *mut u8 = backing.cast();
             let ptr = ptr.add(self.filled);
             ptr
@@ -184,6 +325,126 @@ where
 
         unsafe { std::slice::from_raw_parts_mut(start, self.range.end - self.filled) }
     }
+
+    pub(crate) fn filled_mut(&mut self) -> &mut [u8] {
+        let start = unsafe {
+            // See Owned::filled for soundness notes.
+            let backing: *mut [MaybeUninit<u8>] = self.backing.inner.as_mut().unwrap().get();
+            let ptr: *mut u8 = backing.cast();
+            let ptr = ptr.add(self.range.start);
+            ptr
+        };
+
+        unsafe { std::slice::from_raw_parts_mut(start, self.filled - self.range.start) }
+    }
+
+    /// Moves the given number of bytes from the end of the filled region back to the start of the unfilled region.
+    ///
+    /// This is the inverse of [`Owned::fill`]
+    pub(crate) fn unfill(&mut self, n: usize) {
+        assert!(self.range.start + n <= self.filled);
+
+        self.filled -= n;
+    }
+}
+
+impl<P> UninitOwned<P>
+where
+    P: BufferPool,
+{
+    /// The given `backing` must be the sole owner of its contents,
+    /// ie its strong refcount must be 1 and its weak refcount must be 0.
+    ///
+    /// Panics if it isn't. See [`UninitOwned::try_new`] for a non-panicking alternative.
+    pub fn new(pool: P, backing: Arc<[MaybeUninit<u8>]>) -> Self {
+        match Self::try_new(pool, backing) {
+            Ok(owned) => owned,
+            Err(backing) => panic!(
+                "backing buffer of UninitOwned must not be shared, but its strong refcount is {} and its weak refcount is {}",
+                Arc::strong_count(&backing),
+                Arc::weak_count(&backing),
+            ),
+        }
+    }
+
+    /// Like [`UninitOwned::new`], but returns the given `backing` back as an error
+    /// if it is not the sole owner of its contents.
+    pub fn try_new(
+        pool: P,
+        mut backing: Arc<[MaybeUninit<u8>]>,
+    ) -> Result<Self, Arc<[MaybeUninit<u8>]>> {
+        if Arc::get_mut(&mut backing).is_none() {
+            return Err(backing);
+        }
+
+        let len = backing.len();
+
+        let backing = unsafe {
+            // See Owned::try_new for soundness notes.
+            let backing: *const [MaybeUninit<u8>] = Arc::into_raw(backing);
+            let backing = backing as *const UnsafeCell<[MaybeUninit<u8>]>;
+            let backing = Arc::from_raw(backing);
+            Backing {
+                inner: Some(backing),
+                initialized: false,
+                pool,
+            }
+        };
+        Ok(UninitOwned {
+            backing,
+            len,
+            filled: 0,
+        })
+    }
+
+    pub fn filled_len(&self) -> usize {
+        self.filled
+    }
+
+    /// The number of bytes that can still be written to this buffer.
+    pub fn unfilled_len(&self) -> usize {
+        self.len - self.filled
+    }
+
+    pub fn filled(&self) -> &[u8] {
+        // See Owned::filled for soundness notes.
+        let backing: *mut [MaybeUninit<u8>] = self.backing.inner.as_ref().unwrap().get();
+        let start: *const u8 = backing.cast();
+
+        // This is sound because the filled region has been initialized by UninitOwned::try_put_slice
+        unsafe { std::slice::from_raw_parts(start, self.filled) }
+    }
+
+    pub fn freeze(self) -> Shared<P> {
+        Shared {
+            backing: self.backing,
+            range: (0..self.filled),
+        }
+    }
+}
+
+impl<P> crate::ByteBuf for UninitOwned<P>
+where
+    P: BufferPool,
+{
+    fn try_put_slice(&mut self, src: &[u8]) -> Result<(), crate::EncodeError> {
+        if src.len() > self.unfilled_len() {
+            return Err(crate::EncodeError::InsufficientBuffer);
+        }
+
+        unsafe {
+            // See Owned::filled for soundness notes.
+            //
+            // The bytes are written through a raw pointer, since creating a &mut [u8] to them would be unsound
+            // while they are still uninitialized.
+            let backing: *mut [MaybeUninit<u8>] = self.backing.inner.as_mut().unwrap().get();
+            let ptr: *mut u8 = backing.cast();
+            let ptr = ptr.add(self.filled);
+            std::ptr::copy_nonoverlapping(src.as_ptr(), ptr, src.len());
+        }
+        self.filled += src.len();
+        Ok(())
+    }
 }
 
 // Pretty-prints Owned like bytes::Bytes, ie as a str literal instead of [u8]
@@ -216,6 +477,11 @@ where
         self.range.end == self.range.start
     }
 
+    /// The bytes of this buffer. This is the same as [`AsRef::as_ref`]
+    pub fn bytes(&self) -> &[u8] {
+        self.as_ref()
+    }
+
     /// Retains the range i.. in self
     ///
     /// This is the same as [`Shared::split_to`] but does not require creating a new `Shared` for the range 0..i
@@ -242,6 +508,10 @@ where
         split
     }
 
+    pub(crate) fn pool(&self) -> &P {
+        &self.backing.pool
+    }
+
     pub(crate) fn try_get_u8(&mut self) -> Result<u8, DecodeError> {
         let b = self
             .as_ref()
@@ -285,7 +555,7 @@ where
     fn as_ref(&self) -> &[u8] {
         let start = unsafe {
             // See Owned::as_ref for soundness notes.
-            let backing: *mut [u8] = self.backing.inner.as_ref().unwrap().get();
+            let backing: *mut [MaybeUninit<u8>] = self.backing.inner.as_ref().unwrap().get();
             let ptr: *mut u8 = backing.cast();
             let ptr = ptr.add(self.range.start);
             ptr
@@ -355,17 +625,179 @@ where
             // Only put_back the buffer into the buffer pool if there are no more references to it,
             // just like when we got it in Owned::new
             //
-            // This is the inverse of Owned::new, so the conversion from Arc<UnsafeCell<T>> into Arc<T> is safe for the same reason.
+            // This is the inverse of Owned::new and UninitOwned::new, so the conversion from Arc<UnsafeCell<T>> into Arc<T> is safe
+            // for the same reason. Converting [MaybeUninit<u8>] into [u8] is only done if the buffer was initialized to begin with.
             // The only other possibility of unsoundness would be if self.inner was still shared by other Backings,
             // but we already know that it's not shared.
-            let inner = unsafe {
-                let inner: *const UnsafeCell<[u8]> = Arc::into_raw(inner);
-                let inner = inner as *const [u8];
-                let inner = Arc::from_raw(inner);
-                inner
-            };
-
-            self.pool.put_back(inner);
+            let inner: *const UnsafeCell<[MaybeUninit<u8>]> = Arc::into_raw(inner);
+            if self.initialized {
+                let inner = unsafe {
+                    let inner = inner as *const [u8];
+                    let inner = Arc::from_raw(inner);
+                    inner
+                };
+
+                self.pool.put_back(inner);
+            } else {
+                let inner = unsafe {
+                    let inner = inner as *const [MaybeUninit<u8>];
+                    let inner = Arc::from_raw(inner);
+                    inner
+                };
+
+                self.pool.put_back_uninit(inner);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::mem::MaybeUninit;
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use super::{BufferPool, Owned, ReusableBufferPool, UninitOwned};
+    use crate::tests::TestBufferPool;
+    use crate::{ByteBuf, EncodeError};
+
+    #[derive(Default)]
+    struct CountingPool {
+        put_back: Cell<usize>,
+        put_back_uninit: Cell<usize>,
+    }
+
+    impl BufferPool for CountingPool {
+        fn put_back(&self, _backing: Arc<[u8]>) {
+            self.put_back.set(self.put_back.get() + 1);
+        }
+
+        fn put_back_uninit(&self, backing: Arc<[MaybeUninit<u8>]>) {
+            assert_eq!(Arc::strong_count(&backing), 1);
+            self.put_back_uninit.set(self.put_back_uninit.get() + 1);
+        }
+    }
+
+    fn uninit(len: usize) -> Arc<[MaybeUninit<u8>]> {
+        std::iter::repeat_with(MaybeUninit::uninit)
+            .take(len)
+            .collect()
+    }
+
+    #[test]
+    fn try_new_shared() {
+        let pool = TestBufferPool;
+
+        let backing: Arc<[u8]> = pool.take(8);
+        let clone = backing.clone();
+        match Owned::try_new(pool, backing) {
+            Err(backing) => assert!(Arc::ptr_eq(&backing, &clone)),
+            result => panic!("{:?}", result),
+        }
+
+        drop(clone);
+        assert!(Owned::try_new(pool, pool.take(8)).is_ok());
+    }
+
+    #[test]
+    fn uninit_filled() {
+        let mut owned = UninitOwned::new(TestBufferPool, uninit(8));
+        assert_eq!(owned.filled(), b"");
+        assert_eq!(owned.unfilled_len(), 8);
+
+        owned.try_put_slice(b"abc").unwrap();
+        owned.try_put_u8(b'd').unwrap();
+        assert_eq!(owned.filled(), b"abcd");
+        assert_eq!(owned.filled_len(), 4);
+        assert_eq!(owned.unfilled_len(), 4);
+
+        match owned.try_put_slice(b"efghi") {
+            Err(EncodeError::InsufficientBuffer) => (),
+            result => panic!("{:?}", result),
+        }
+        assert_eq!(owned.filled(), b"abcd");
+
+        owned.try_put_slice(b"efgh").unwrap();
+        assert_eq!(owned.filled(), b"abcdefgh");
+        assert_eq!(owned.freeze().as_ref(), b"abcdefgh");
+    }
+
+    #[test]
+    fn uninit_freeze() {
+        let pool = Rc::new(CountingPool::default());
+
+        let mut owned = UninitOwned::new(pool.clone(), uninit(8));
+        owned.try_put_slice(b"abc").unwrap();
+        let shared = owned.freeze();
+        assert_eq!(shared.as_ref(), b"abc");
+
+        let clone = shared.clone();
+        drop(shared);
+        assert_eq!(pool.put_back_uninit.get(), 0);
+
+        drop(clone);
+        assert_eq!(pool.put_back.get(), 0);
+        assert_eq!(pool.put_back_uninit.get(), 1);
+    }
+
+    #[test]
+    fn uninit_try_new_shared() {
+        let backing = uninit(8);
+        let clone = backing.clone();
+        match UninitOwned::try_new(TestBufferPool, backing) {
+            Err(backing) => assert!(Arc::ptr_eq(&backing, &clone)),
+            Ok(_) => panic!("shared backing should have been rejected"),
+        }
+    }
+
+    #[test]
+    fn reusable_pool_high_water_mark() {
+        let pool = Arc::new(ReusableBufferPool::new(16));
+
+        let buffers: Vec<_> = (0..3)
+            .map(|_| Owned::new(pool.clone(), pool.take(16)))
+            .collect();
+        assert_eq!(pool.outstanding(), 3);
+        assert_eq!(pool.high_water_mark(), 3);
+
+        drop(buffers);
+        assert_eq!(pool.outstanding(), 0);
+        assert_eq!(pool.high_water_mark(), 3);
+
+        // Returned buffers are reused, and do not raise the peak
+        let _buffer = Owned::new(pool.clone(), pool.take(16));
+        assert_eq!(pool.outstanding(), 1);
+        assert_eq!(pool.high_water_mark(), 3);
+    }
+
+    #[test]
+    fn reusable_pool_foreign_buffer() {
+        let pool = Arc::new(ReusableBufferPool::new(16));
+        let _buffer = Owned::new(pool.clone(), pool.take(16));
+
+        // Neither a buffer that was allocated elsewhere nor one too large for the pool counts as outstanding
+        drop(Owned::new(pool.clone(), vec![0; 8].into()));
+        drop(Owned::new(pool.clone(), pool.take(32)));
+        assert_eq!(pool.outstanding(), 1);
+        assert_eq!(pool.high_water_mark(), 1);
+    }
+
+    #[test]
+    fn reusable_pool_foreign_buffer_of_pool_length() {
+        let pool = ReusableBufferPool::new(16);
+        let buffer = pool.take(16);
+
+        pool.put_back(vec![0; 16].into());
+        assert_eq!(pool.outstanding(), 0);
+
+        // Putting back one more buffer than was taken leaves the count at zero
+        #[cfg(not(debug_assertions))]
+        {
+            pool.put_back(buffer);
+            assert_eq!(pool.outstanding(), 0);
         }
+        #[cfg(debug_assertions)]
+        drop(buffer);
     }
 }
@@ -3,7 +3,7 @@
 use std::convert::TryInto;
 use std::mem::size_of;
 
-use crate::{BufferPool, EncodeError, Shared};
+use crate::{BufferPool, ByteBuf, DecodeError, EncodeError, Owned, Shared};
 
 /// Strings are prefixed with a two-byte big-endian length and are encoded as utf-8.
 ///
@@ -13,12 +13,50 @@ pub struct ByteStr<P>(Shared<P>)
 where
     P: BufferPool;
 
+/// The result of [`ByteStr::decode_detailed`]
+#[derive(Debug, Eq, PartialEq)]
+pub enum ByteStrDecode<P>
+where
+    P: BufferPool,
+{
+    /// The string was decoded completely.
+    Complete(ByteStr<P>),
+
+    /// The two-byte length prefix is truncated and needs this many more bytes.
+    IncompleteLength { needed: usize },
+
+    /// The length prefix was decoded but the string itself is truncated and needs this many more bytes.
+    IncompleteBody { needed: usize },
+}
+
 impl<P> ByteStr<P>
 where
     P: BufferPool,
 {
     pub const EMPTY: &'static [u8] = b"\x00\x00";
 
+    /// Constructs an empty string, with a buffer taken from the given pool.
+    pub fn empty(pool: P) -> Self {
+        let backing = pool.take(Self::EMPTY.len());
+        let mut s = Owned::new(pool, backing);
+        s.try_put_slice(Self::EMPTY).unwrap();
+        ByteStr(s.freeze())
+    }
+
+    /// Constructs a string with a copy of the given `str`.
+    ///
+    /// Like [`ByteStr::empty`], its buffer is taken from the given pool.
+    pub fn new(pool: P, s: &str) -> Result<Self, EncodeError> {
+        if s.len() > usize::from(u16::MAX) {
+            return Err(EncodeError::StringTooLarge(s.len()));
+        }
+
+        let backing = pool.take(size_of::<u16>() + s.len());
+        let mut buf = Owned::new(pool, backing);
+        buf.try_put_str(s)?;
+        Ok(ByteStr(buf.freeze()))
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         &self.0[size_of::<u16>()..]
     }
@@ -31,27 +69,115 @@ where
         self.0 == b"\x00\x00"[..]
     }
 
+    /// The number of bytes this string occupies when encoded, ie including its two-byte length prefix.
+    pub fn encoded_len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// The `/`-separated levels of this string when it is used as a topic name or topic filter.
+    ///
+    /// Leading, trailing and adjacent separators produce empty levels, eg `/a/` has the three levels `""`, `"a"` and `""`.
+    /// An empty string has a single empty level.
+    ///
+    /// Ref: 4.7.1.1 Topic level separator
+    pub fn levels(&self) -> impl Iterator<Item = &str> {
+        self.as_ref().split('/')
+    }
+
+    /// The number of levels returned by [`ByteStr::levels`]
+    pub fn level_count(&self) -> usize {
+        self.as_ref().matches('/').count() + 1
+    }
+
     pub fn into_buffer(self) -> Shared<P> {
         self.0
     }
 
     pub fn decode(src: &mut Shared<P>) -> Result<Option<ByteStr<P>>, super::DecodeError>
+    where
+        P: Clone,
+    {
+        match ByteStr::decode_detailed(src) {
+            ByteStrDecode::Complete(s) => Ok(Some(s)),
+            ByteStrDecode::IncompleteLength { .. } | ByteStrDecode::IncompleteBody { .. } => {
+                Ok(None)
+            }
+        }
+    }
+
+    /// Like [`ByteStr::decode`], but for a string in the body of a packet that has already been framed by its remaining length,
+    /// where a truncated string means the packet is malformed rather than that more bytes need to be read.
+    ///
+    /// Fails with [`DecodeError::StringLengthExceedsPacket`] if the length prefix claims more bytes than `src` has left,
+    /// and with [`DecodeError::IncompletePacket`] if `src` ends within the length prefix itself.
+    pub(crate) fn decode_in_packet(src: &mut Shared<P>) -> Result<ByteStr<P>, DecodeError>
+    where
+        P: Clone,
+    {
+        let remaining_length = src.len();
+        match ByteStr::decode_detailed(src) {
+            ByteStrDecode::Complete(s) => Ok(s),
+            ByteStrDecode::IncompleteLength { .. } => Err(DecodeError::IncompletePacket),
+            ByteStrDecode::IncompleteBody { needed } => {
+                Err(DecodeError::StringLengthExceedsPacket {
+                    string_length: remaining_length - size_of::<u16>() + needed,
+                    remaining_length: remaining_length - size_of::<u16>(),
+                })
+            }
+        }
+    }
+
+    /// Like [`ByteStr::decode`], but indicates which part of the string is truncated
+    /// and how many more bytes are needed if `src` does not contain the whole string.
+    pub fn decode_detailed(src: &mut Shared<P>) -> ByteStrDecode<P>
     where
         P: Clone,
     {
         let len: usize = match src.as_ref().get(..size_of::<u16>()) {
             Some(src) => u16::from_be_bytes(src.try_into().unwrap()).into(),
-            None => return Ok(None),
+            None => {
+                return ByteStrDecode::IncompleteLength {
+                    needed: size_of::<u16>() - src.len(),
+                }
+            }
         };
 
         if src.len() < size_of::<u16>() + len {
-            return Ok(None);
+            return ByteStrDecode::IncompleteBody {
+                needed: size_of::<u16>() + len - src.len(),
+            };
         }
 
         let s = src.split_to(size_of::<u16>() + len);
-        Ok(Some(ByteStr(s)))
+        ByteStrDecode::Complete(ByteStr(s))
     }
 
+    /// Constructs a string from a buffer that already contains a complete length-prefixed string, without copying it.
+    ///
+    /// Fails with [`DecodeError::IncompletePacket`] if `s` is shorter than its declared length,
+    /// with [`DecodeError::TrailingGarbage`] if it is longer, and with [`DecodeError::StringNotUtf8`] if it is not valid utf-8.
+    pub fn from_shared(s: Shared<P>) -> Result<ByteStr<P>, DecodeError> {
+        let len: usize = match s.as_ref().get(..size_of::<u16>()) {
+            Some(len) => u16::from_be_bytes(len.try_into().unwrap()).into(),
+            None => return Err(DecodeError::IncompletePacket),
+        };
+
+        match (size_of::<u16>() + len).cmp(&s.len()) {
+            std::cmp::Ordering::Less => return Err(DecodeError::TrailingGarbage),
+            std::cmp::Ordering::Equal => (),
+            std::cmp::Ordering::Greater => return Err(DecodeError::IncompletePacket),
+        }
+
+        std::str::from_utf8(&s[size_of::<u16>()..])?;
+
+        Ok(ByteStr(s))
+    }
+
+    /// Writes this string with its two-byte length prefix.
+    ///
+    /// The prefix is written as-is without checking the length again. This is fine because every way of constructing a `ByteStr`
+    /// ensures that the prefix matches the string, and so the string cannot be longer than 65535 bytes.
+    /// Longer strings are rejected when constructing them, eg with [`EncodeError::StringTooLarge`] by [`ByteStr::new`]
     pub fn encode<B>(self, dst: &mut B) -> Result<(), EncodeError>
     where
         B: super::ByteBuf,
@@ -91,10 +217,12 @@ impl<P> PartialEq for ByteStr<P>
 where
     P: BufferPool,
 {
+    // Compares the length prefixes first, so that strings of different lengths are rejected without looking at their contents,
+    // and then the remaining bytes directly. Two byte strings are equal iff their utf-8 encodings are equal,
+    // so this is the same as comparing them as `str`s.
     fn eq(&self, other: &Self) -> bool {
-        let s: &str = self.as_ref();
-        let other: &str = other.as_ref();
-        s.eq(other)
+        self.0[..size_of::<u16>()] == other.0[..size_of::<u16>()]
+            && self.as_bytes() == other.as_bytes()
     }
 }
 
@@ -107,6 +235,26 @@ where
     }
 }
 
+/// Compares the full encoded form of the string, ie including its length prefix, with the bytes of the `Shared`.
+impl<P> PartialEq<Shared<P>> for ByteStr<P>
+where
+    P: BufferPool,
+{
+    fn eq(&self, other: &Shared<P>) -> bool {
+        self.0.eq(other)
+    }
+}
+
+/// Compares the bytes of the `Shared` with the full encoded form of the string, ie including its length prefix.
+impl<P> PartialEq<ByteStr<P>> for Shared<P>
+where
+    P: BufferPool,
+{
+    fn eq(&self, other: &ByteStr<P>) -> bool {
+        self.eq(&other.0)
+    }
+}
+
 impl<P> Eq for ByteStr<P> where P: BufferPool {}
 
 impl<P> PartialOrd for ByteStr<P>
@@ -144,6 +292,8 @@ impl<P> std::hash::Hash for ByteStr<P>
 where
     P: BufferPool,
 {
+    // Hashes the string as `str::hash` does, ie its bytes without the length prefix, so that the hash of a `ByteStr`
+    // is the same as the hash of the equivalent `str` with the same hasher.
     fn hash<H>(&self, state: &mut H)
     where
         H: std::hash::Hasher,
@@ -151,3 +301,199 @@ where
         self.as_ref().hash(state)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ByteStr, ByteStrDecode};
+    use crate::tests::{byte_str, shared, TestBufferPool};
+    use crate::{DecodeError, EncodeError, Owned};
+
+    #[test]
+    fn empty() {
+        let s = ByteStr::empty(TestBufferPool);
+        assert!(s.is_empty());
+        assert_eq!(s.len(), 0);
+        assert_eq!(s, "");
+
+        let pool = std::sync::Arc::new(crate::ReusableBufferPool::new(16));
+        let s = ByteStr::empty(pool.clone());
+        assert_eq!(pool.outstanding(), 1);
+        drop(s);
+        assert_eq!(pool.outstanding(), 0);
+    }
+
+    #[test]
+    fn eq() {
+        assert_eq!(byte_str("a/b"), byte_str("a/b"));
+        assert_eq!(byte_str(""), ByteStr::empty(TestBufferPool));
+        assert_ne!(byte_str("a/b"), byte_str("a/c"));
+        assert_ne!(byte_str("a/b"), byte_str("a/b/"));
+        assert_ne!(byte_str("a/b/"), byte_str("a/b"));
+        assert_ne!(byte_str(""), byte_str("a"));
+
+        let long = "a/".repeat(1000);
+        assert_eq!(byte_str(&long), byte_str(&long));
+        assert_ne!(byte_str(&long), byte_str(&format!("{long}b")));
+
+        // Agrees with str equality for multi-byte characters
+        assert_eq!(byte_str("s\u{e9}"), byte_str("s\u{e9}"));
+        assert_ne!(byte_str("s\u{e9}"), byte_str("se\u{301}"));
+    }
+
+    #[test]
+    fn hash() {
+        fn hash<T>(value: &T) -> u64
+        where
+            T: std::hash::Hash + ?Sized,
+        {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            value.hash(&mut hasher);
+            std::hash::Hasher::finish(&hasher)
+        }
+
+        // Same string in buffers that start at different offsets
+        let a = byte_str("foo/bar");
+        let mut src = shared(b"\x00\x00\x00\x07foo/bar");
+        src.drain(2);
+        let b = ByteStr::from_shared(src).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.into_buffer().bytes(), b"\x00\x07foo/bar");
+
+        let a = byte_str("foo/bar");
+        assert_eq!(hash(&a), hash(&b));
+        assert_eq!(hash(&a), hash("foo/bar"));
+        assert_ne!(hash(&a), hash(&byte_str("foo/baz")));
+    }
+
+    #[test]
+    fn new() {
+        let s = ByteStr::new(TestBufferPool, "foo/bar").unwrap();
+        assert_eq!(s, byte_str("foo/bar"));
+
+        match ByteStr::new(TestBufferPool, &"a".repeat(0x1_0000)) {
+            Err(crate::EncodeError::StringTooLarge(0x1_0000)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn eq_shared() {
+        let pool = TestBufferPool;
+
+        let s = ByteStr::new(pool, "foo/bar").unwrap();
+        let mut bytes = Owned::new(pool, pool.take(s.encoded_len()));
+        s.clone().encode(&mut bytes).unwrap();
+        let encoded = bytes.freeze();
+
+        assert_eq!(s, encoded);
+        assert_eq!(encoded, s);
+
+        // Without the length prefix, it's just the contents of the string
+        assert_ne!(s, shared(b"foo/bar"));
+        assert_ne!(shared(b"foo/bar"), s);
+        assert_ne!(s, shared(b"\x00\x07foo/baz"));
+    }
+
+    #[test]
+    fn encoded_len() {
+        assert_eq!(byte_str("abc").encoded_len(), 5);
+        assert_eq!(ByteStr::empty(TestBufferPool).encoded_len(), 2);
+    }
+
+    #[test]
+    fn new_too_large() {
+        let pool = TestBufferPool;
+
+        let s = ByteStr::new(pool, &"a".repeat(0xFFFF)).unwrap();
+        assert_eq!(s.len(), 0xFFFF);
+        let mut bytes = Owned::new(pool, pool.take(0x1_0001));
+        s.encode(&mut bytes).unwrap();
+        assert_eq!(&bytes.filled()[..3], b"\xFF\xFFa");
+        assert_eq!(bytes.filled().len(), 0x1_0001);
+
+        match ByteStr::new(pool, &"a".repeat(0x1_0000)) {
+            Err(EncodeError::StringTooLarge(0x1_0000)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn levels() {
+        let s = byte_str("a/b/c");
+        assert_eq!(s.levels().collect::<Vec<_>>(), vec!["a", "b", "c"]);
+        assert_eq!(s.level_count(), 3);
+
+        let s = byte_str("/a/");
+        assert_eq!(s.levels().collect::<Vec<_>>(), vec!["", "a", ""]);
+        assert_eq!(s.level_count(), 3);
+
+        let s = byte_str("a//b");
+        assert_eq!(s.levels().collect::<Vec<_>>(), vec!["a", "", "b"]);
+        assert_eq!(s.level_count(), 3);
+
+        let s = ByteStr::empty(TestBufferPool);
+        assert_eq!(s.levels().collect::<Vec<_>>(), vec![""]);
+        assert_eq!(s.level_count(), 1);
+    }
+
+    #[test]
+    fn decode_detailed() {
+        let mut src = shared(b"");
+        assert_eq!(
+            ByteStr::decode_detailed(&mut src),
+            ByteStrDecode::IncompleteLength { needed: 2 }
+        );
+
+        let mut src = shared(b"\x00");
+        assert_eq!(
+            ByteStr::decode_detailed(&mut src),
+            ByteStrDecode::IncompleteLength { needed: 1 }
+        );
+
+        let mut src = shared(b"\x00\x05");
+        assert_eq!(
+            ByteStr::decode_detailed(&mut src),
+            ByteStrDecode::IncompleteBody { needed: 5 }
+        );
+
+        let mut src = shared(b"\x00\x05ab");
+        assert_eq!(
+            ByteStr::decode_detailed(&mut src),
+            ByteStrDecode::IncompleteBody { needed: 3 }
+        );
+        assert_eq!(src.len(), 4);
+
+        let mut src = shared(b"\x00\x05abcdefg");
+        assert_eq!(
+            ByteStr::decode_detailed(&mut src),
+            ByteStrDecode::Complete(byte_str("abcde"))
+        );
+        assert_eq!(src, b"fg"[..]);
+    }
+
+    #[test]
+    fn from_shared() {
+        let s = ByteStr::from_shared(shared(b"\x00\x03a/b")).unwrap();
+        assert_eq!(s, "a/b");
+
+        match ByteStr::from_shared(shared(b"\x00\x05a/b")) {
+            Err(DecodeError::IncompletePacket) => (),
+            result => panic!("{:?}", result),
+        }
+
+        match ByteStr::from_shared(shared(b"\x00\x02a/b")) {
+            Err(DecodeError::TrailingGarbage) => (),
+            result => panic!("{:?}", result),
+        }
+
+        match ByteStr::from_shared(shared(b"\x00")) {
+            Err(DecodeError::IncompletePacket) => (),
+            result => panic!("{:?}", result),
+        }
+
+        match ByteStr::from_shared(shared(b"\x00\x02\xC3\x28")) {
+            Err(DecodeError::StringNotUtf8(_)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use crate::{BufferPool, ByteBuf, EncodeError, Shared};
+
+/// The lookup table of the reflected CRC-32 (IEEE 802.3) polynomial, one entry per byte value.
+const TABLE: [u32; 256] = {
+    let mut table = [0_u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        #[allow(clippy::cast_possible_truncation)]
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 0 {
+                crc >> 1
+            } else {
+                (crc >> 1) ^ 0xEDB8_8320
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn update(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |crc, &b| {
+        TABLE[usize::from(crc.to_le_bytes()[0] ^ b)] ^ (crc >> 8)
+    })
+}
+
+impl<P> Shared<P>
+where
+    P: BufferPool,
+{
+    /// The CRC-32 (IEEE 802.3) checksum of the bytes of this buffer.
+    pub fn crc32(&self) -> u32 {
+        !update(!0, self.as_ref())
+    }
+}
+
+/// A [`ByteBuf`] that computes the CRC-32 (IEEE 802.3) checksum of the bytes of encoded packets instead of storing them.
+///
+/// The checksum of the bytes encoded so far is returned by [`CrcBuf::crc32`], and is the same as [`Shared::crc32`]
+/// of a buffer that the same packets were encoded into.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CrcBuf(u32);
+
+impl CrcBuf {
+    pub fn new() -> Self {
+        CrcBuf(!0)
+    }
+
+    /// The checksum of the bytes encoded so far.
+    pub fn crc32(&self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for CrcBuf {
+    fn default() -> Self {
+        CrcBuf::new()
+    }
+}
+
+impl ByteBuf for CrcBuf {
+    fn try_put_slice(&mut self, src: &[u8]) -> Result<(), EncodeError> {
+        self.0 = update(self.0, src);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CrcBuf;
+    use crate::tests::{shared, TestBufferPool};
+    use crate::v5::{Packet, PacketIdentifierDupQoS, Publish};
+    use crate::{Owned, TopicName};
+
+    #[test]
+    fn crc32() {
+        // The standard check value of CRC-32
+        assert_eq!(shared(b"123456789").crc32(), 0xCBF4_3926);
+        assert_eq!(shared(b"").crc32(), 0);
+    }
+
+    #[test]
+    fn streaming_crc_matches_encoded_bytes() {
+        let packet = || {
+            let topic_name = TopicName::new(crate::tests::byte_str("a/b")).unwrap();
+            Packet::Publish(Publish::new(
+                topic_name,
+                PacketIdentifierDupQoS::AtMostOnce,
+                false,
+                shared(b"hello world"),
+            ))
+        };
+
+        let pool = TestBufferPool;
+        let mut bytes = Owned::new(pool, pool.take(64));
+        crate::v5::encode(packet(), &mut bytes).unwrap();
+        let bytes = bytes.freeze();
+
+        let mut crc = CrcBuf::new();
+        crate::v5::encode(packet(), &mut crc).unwrap();
+        assert_eq!(crc.crc32(), bytes.crc32());
+    }
+}
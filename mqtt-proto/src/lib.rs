@@ -26,6 +26,8 @@
     clippy::too_many_lines
 )]
 
+use std::convert::TryInto;
+use std::mem::size_of;
 use std::time::Duration;
 
 const PROTOCOL_NAME: &[u8] = b"\x00\x04MQTT";
@@ -64,11 +66,96 @@ macro_rules! define_u8_code {
     };
 }
 
+/// Defines the tests of the PUBLISH helpers that v3 and v5 have in common, in the `tests` module of either version.
+#[cfg(test)]
+macro_rules! shared_publish_tests {
+    () => {
+        #[test]
+        fn publish_is_retained_delete() {
+            for (retain, payload, expected) in [
+                (true, &b""[..], true),
+                (true, &b"x"[..], false),
+                (false, &b""[..], false),
+            ] {
+                let packet = super::Publish::new(
+                    crate::TopicName::new(crate::tests::byte_str("a/b")).unwrap(),
+                    super::PacketIdentifierDupQoS::AtMostOnce,
+                    retain,
+                    crate::tests::shared(payload),
+                );
+                assert_eq!(packet.is_retained_delete(), expected);
+            }
+        }
+
+        #[test]
+        fn publish_qos_packet_identifier_dup() {
+            let packet_identifier = crate::PacketIdentifier::new(4).unwrap();
+
+            for (packet_identifier_dup_qos, qos, expected_packet_identifier, dup) in [
+                (
+                    super::PacketIdentifierDupQoS::AtMostOnce,
+                    crate::QoS::AtMostOnce,
+                    None,
+                    false,
+                ),
+                (
+                    super::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, true),
+                    crate::QoS::AtLeastOnce,
+                    Some(packet_identifier),
+                    true,
+                ),
+                (
+                    super::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, false),
+                    crate::QoS::ExactlyOnce,
+                    Some(packet_identifier),
+                    false,
+                ),
+            ] {
+                let packet = super::Publish::new(
+                    crate::TopicName::new(crate::tests::byte_str("a/b")).unwrap(),
+                    packet_identifier_dup_qos,
+                    false,
+                    crate::tests::shared(b""),
+                );
+                assert_eq!(packet.qos(), qos);
+                assert_eq!(packet.packet_identifier(), expected_packet_identifier);
+                assert_eq!(packet.is_dup(), dup);
+            }
+        }
+
+        #[test]
+        fn encode_to_vec() {
+            let packet = super::Packet::Publish(super::Publish::new(
+                crate::TopicName::new(crate::tests::byte_str("a/b")).unwrap(),
+                super::PacketIdentifierDupQoS::AtLeastOnce(
+                    crate::PacketIdentifier::new(3).unwrap(),
+                    false,
+                ),
+                true,
+                crate::tests::shared(b"hello"),
+            ));
+
+            let bytes = super::encode_to_vec(packet.clone()).unwrap();
+            assert_eq!(usize::from(bytes[1]), bytes.len() - 2);
+            let decoded = super::decode(bytes[0], crate::tests::shared(&bytes[2..])).unwrap();
+            assert_eq!(decoded, packet);
+        }
+    };
+}
+
 pub mod buffer;
-pub use buffer::{BufferPool, Owned, Shared};
+pub use buffer::{BufferPool, Owned, ReusableBufferPool, Shared, UninitOwned};
 
 mod byte_str;
-pub use byte_str::ByteStr;
+pub use byte_str::{ByteStr, ByteStrDecode};
+
+#[cfg(feature = "crc")]
+mod crc;
+#[cfg(feature = "crc")]
+pub use crc::CrcBuf;
+
+mod topic;
+pub use topic::{matching_subscriptions, topic_matches, TopicFilter, TopicName};
 
 pub mod v3;
 
@@ -115,6 +202,77 @@ where
     }
 }
 
+/// The rules that [`validate_client_id`] checks a client identifier against.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClientIdPolicy {
+    /// 1 to 23 bytes of `[0-9a-zA-Z]`, which every 3.1.1 server must accept.
+    ///
+    /// Ref: 3.1.1: 3.1.3.1 Client Identifier
+    Strict,
+
+    /// Any non-empty string that can be encoded as an MQTT string.
+    ///
+    /// Ref: 5.0: 3.1.3.1 Client Identifier
+    Lenient,
+}
+
+/// The reason [`validate_client_id`] rejected a client identifier.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClientIdError {
+    Empty,
+    IllegalCharacter(char),
+    TooLong(usize),
+}
+
+impl std::fmt::Display for ClientIdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientIdError::Empty => f.write_str("client ID is empty"),
+            ClientIdError::IllegalCharacter(c) => {
+                write!(f, "client ID contains illegal character {c:?}")
+            }
+            ClientIdError::TooLong(len) => write!(f, "client ID of length {len} is too long"),
+        }
+    }
+}
+
+impl std::error::Error for ClientIdError {}
+
+/// Checks that the given client identifier is allowed by the given policy.
+///
+/// An empty client identifier is always rejected. A client that wants the server to assign it an identifier
+/// uses [`ClientId::ServerGenerated`] instead.
+pub fn validate_client_id(id: &str, policy: ClientIdPolicy) -> Result<(), ClientIdError> {
+    if id.is_empty() {
+        return Err(ClientIdError::Empty);
+    }
+
+    match policy {
+        ClientIdPolicy::Strict => {
+            if let Some(c) = id.chars().find(|c| !c.is_ascii_alphanumeric()) {
+                return Err(ClientIdError::IllegalCharacter(c));
+            }
+
+            if id.len() > 23 {
+                return Err(ClientIdError::TooLong(id.len()));
+            }
+        }
+
+        ClientIdPolicy::Lenient => {
+            // Ref: 1.5.4 UTF-8 Encoded String
+            if let Some(c) = id.chars().find(|&c| c == '\0') {
+                return Err(ClientIdError::IllegalCharacter(c));
+            }
+
+            if id.len() > usize::from(u16::MAX) {
+                return Err(ClientIdError::TooLong(id.len()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
 /// Decode MQTT-format "remaining length" numbers.
 ///
 /// These numbers are encoded with a variable-length scheme that uses the MSB of each byte as a continuation bit.
@@ -178,6 +336,15 @@ where
     Ok(())
 }
 
+/// The total size of a packet with the given remaining length, including its fixed header.
+///
+/// Returns `None` if the remaining length is too high to be encoded.
+fn packet_size(remaining_length: usize) -> Option<usize> {
+    let mut counter: ByteCounter = Default::default();
+    encode_remaining_length(remaining_length, &mut counter).ok()?;
+    (1 + counter.0).checked_add(remaining_length)
+}
+
 /// A packet identifier. Two-byte unsigned integer that cannot be zero.
 ///
 /// Ref:
@@ -187,6 +354,11 @@ where
 pub struct PacketIdentifier(u16);
 
 impl PacketIdentifier {
+    /// Returns the smallest value that is a valid packet identifier, ie 1.
+    pub const fn first() -> Self {
+        PacketIdentifier(1)
+    }
+
     /// Returns the largest value that is a valid packet identifier.
     pub const fn max_value() -> Self {
         PacketIdentifier(u16::max_value())
@@ -204,6 +376,14 @@ impl PacketIdentifier {
     pub fn get(self) -> u16 {
         self.0
     }
+
+    /// Returns the packet identifier after this one, wrapping around to 1 after [`PacketIdentifier::max_value`]
+    ///
+    /// This is the same as `self + 1`
+    #[must_use]
+    pub fn next(self) -> Self {
+        self + 1
+    }
 }
 
 impl std::fmt::Display for PacketIdentifier {
@@ -247,14 +427,33 @@ pub enum DecodeError {
     // Common
     ConnectReservedSet,
     ConnectZeroLengthIdWithExistingSession,
+    EmptyTopicName,
+    FixedSizePacketTooLong {
+        packet_type: u8,
+    },
     IncompletePacket,
     Io(std::io::Error),
     NoTopics,
+    PacketTooLarge {
+        packet_type: u8,
+        remaining_length: usize,
+    },
+    PayloadTooLarge(usize),
     PublishDupAtMostOnce,
     RemainingLengthTooHigh,
+    StringLengthExceedsPacket {
+        string_length: usize,
+        remaining_length: usize,
+    },
     StringNotUtf8(std::str::Utf8Error),
+    TooManyReasonCodes(usize),
+    TooManyTopics(usize),
     TrailingGarbage,
     UnrecognizedConnAckFlags(u8),
+    UnrecognizedFixedHeaderFlags {
+        packet_type: u8,
+        flags: u8,
+    },
     UnrecognizedPacket {
         packet_type: u8,
         flags: u8,
@@ -266,14 +465,35 @@ pub enum DecodeError {
     ZeroPacketIdentifier,
 
     // Specific to v3
+    PacketTypeNotValidForVersion {
+        packet_type: u8,
+        version: u8,
+    },
 
     // Specific to v5
+    ConflictingDuplicateProperty(&'static str),
     DuplicateProperty(&'static str),
+    DuplicateSubscriptionIdentifier,
     MissingRequiredProperty(&'static str),
+    MissingWillPropertyLength,
+    NoLocalOnSharedSubscription,
+    PropertiesTooLarge(usize),
+    PropertyLengthExceedsPacket {
+        property_length: usize,
+        remaining_length: usize,
+    },
+    StreamPoisoned,
+    SubscriptionIdentifierTooLarge,
+    TooManyProperties(usize),
+    UnexpectedPacketType {
+        expected: u8,
+        got: u8,
+    },
     UnexpectedProperty,
     UnrecognizedPropertyIdentifier(u8),
 
     InvalidMaximumPacketSize(u32),
+    InvalidReceiveMaximum(u16),
     UnrecognizedAuthenticateReasonCode(u8),
     UnrecognizedConnectReasonCode(u8),
     UnrecognizedDisconnectReasonCode(u8),
@@ -305,20 +525,53 @@ impl std::fmt::Display for DecodeError {
             }
             DecodeError::ConnectZeroLengthIdWithExistingSession =>
                 f.write_str("a zero length client_id was received without the clean session flag set"),
+            DecodeError::EmptyTopicName => f.write_str("PUBLISH packet has an empty topic name"),
+            DecodeError::FixedSizePacketTooLong { packet_type } => write!(
+                f,
+                "packet with type 0x{packet_type:02X} has more bytes than its fixed size",
+            ),
             DecodeError::IncompletePacket => f.write_str("packet is truncated"),
             DecodeError::Io(err) => write!(f, "I/O error: {}", err),
             DecodeError::NoTopics => f.write_str("expected at least one topic but there were none"),
+            DecodeError::PacketTooLarge {
+                packet_type,
+                remaining_length,
+            } => write!(
+                f,
+                "packet with type 0x{packet_type:02X} and remaining length {remaining_length} exceeds the maximum packet size",
+            ),
+            DecodeError::PayloadTooLarge(payload_len) => write!(
+                f,
+                "PUBLISH payload of {payload_len} bytes exceeds the maximum payload length",
+            ),
             DecodeError::PublishDupAtMostOnce => {
                 f.write_str("PUBLISH packet has DUP flag set and QoS 0")
             }
             DecodeError::RemainingLengthTooHigh => {
                 f.write_str("remaining length is too high to be decoded")
             }
+            DecodeError::StringLengthExceedsPacket {
+                string_length,
+                remaining_length,
+            } => write!(
+                f,
+                "string length {string_length} exceeds the remaining {remaining_length} bytes of the packet",
+            ),
             DecodeError::StringNotUtf8(err) => err.fmt(f),
+            DecodeError::TooManyReasonCodes(maximum) => {
+                write!(f, "packet has more than {maximum} reason codes")
+            }
+            DecodeError::TooManyTopics(maximum) => {
+                write!(f, "packet has more than {maximum} topics")
+            }
             DecodeError::TrailingGarbage => f.write_str("packet has trailing garbage"),
             DecodeError::UnrecognizedConnAckFlags(flags) => {
                 write!(f, "could not parse CONNACK flags 0x{:02X}", flags)
             }
+            DecodeError::UnrecognizedFixedHeaderFlags { packet_type, flags } => write!(
+                f,
+                "packet type 0x{packet_type:02X} does not allow fixed header flags 0x{flags:02X}"
+            ),
             DecodeError::UnrecognizedPacket {
                 packet_type,
                 flags,
@@ -331,7 +584,7 @@ impl std::fmt::Display for DecodeError {
                 remaining_length,
             ),
             DecodeError::UnrecognizedProtocolName(name) => {
-                write!(f, "unexpected protocol name {:?}", name)
+                write!(f, "unexpected protocol name \"{}\"", name)
             }
             DecodeError::UnrecognizedProtocolVersion(version) => {
                 write!(f, "unexpected protocol version {:?}", version)
@@ -340,14 +593,57 @@ impl std::fmt::Display for DecodeError {
             DecodeError::ZeroPacketIdentifier => f.write_str("packet identifier is 0"),
 
             // Specific to v3
+            DecodeError::PacketTypeNotValidForVersion {
+                packet_type,
+                version,
+            } => write!(
+                f,
+                "packet type 0x{packet_type:02X} is not valid for protocol version {version}"
+            ),
 
             // Specific to v5
+            DecodeError::ConflictingDuplicateProperty(identifier) => {
+                write!(f, "duplicate property {identifier} with a different value")
+            }
             DecodeError::DuplicateProperty(identifier) => {
                 write!(f, "duplicate property {}", identifier)
             }
+            DecodeError::DuplicateSubscriptionIdentifier => {
+                f.write_str("subscription identifier specified more than once")
+            }
             DecodeError::MissingRequiredProperty(identifier) => {
                 write!(f, "required property {} is missing", identifier)
             }
+            DecodeError::MissingWillPropertyLength => {
+                f.write_str("CONNECT has the will flag set but ends before the will property length")
+            }
+            DecodeError::NoLocalOnSharedSubscription => {
+                f.write_str("no local option set on a shared subscription")
+            }
+            DecodeError::PropertiesTooLarge(property_length) => write!(
+                f,
+                "property block of {property_length} bytes exceeds the maximum property block length",
+            ),
+            DecodeError::PropertyLengthExceedsPacket {
+                property_length,
+                remaining_length,
+            } => write!(
+                f,
+                "property length {property_length} exceeds the remaining {remaining_length} bytes of the packet",
+            ),
+            DecodeError::StreamPoisoned => {
+                f.write_str("stream could not be decoded further after an earlier error")
+            }
+            DecodeError::SubscriptionIdentifierTooLarge => {
+                f.write_str("subscription identifier is too large to be decoded")
+            }
+            DecodeError::TooManyProperties(maximum) => {
+                write!(f, "property block has more than {maximum} properties")
+            }
+            DecodeError::UnexpectedPacketType { expected, got } => write!(
+                f,
+                "expected packet of type 0x{expected:02X} but got packet of type 0x{got:02X}"
+            ),
             DecodeError::UnexpectedProperty => f.write_str("unexpected property"),
             DecodeError::UnrecognizedPropertyIdentifier(identifier) => {
                 write!(f, "unrecognized property identifier 0x{:02x}", identifier)
@@ -358,6 +654,9 @@ impl std::fmt::Display for DecodeError {
                 "maximum packet size property set to invalid value {}",
                 value
             ),
+            DecodeError::InvalidReceiveMaximum(value) => {
+                write!(f, "receive maximum property set to invalid value {value}")
+            }
             DecodeError::UnrecognizedAuthenticateReasonCode(code) => {
                 write!(f, "unrecognized authenticate reason code 0x{:02x}", code)
             }
@@ -437,14 +736,22 @@ impl std::error::Error for DecodeError {
             // Common
             DecodeError::ConnectReservedSet => None,
             DecodeError::ConnectZeroLengthIdWithExistingSession => None,
+            DecodeError::EmptyTopicName => None,
+            DecodeError::FixedSizePacketTooLong { .. } => None,
             DecodeError::IncompletePacket => None,
             DecodeError::Io(err) => Some(err),
             DecodeError::NoTopics => None,
+            DecodeError::PacketTooLarge { .. } => None,
+            DecodeError::PayloadTooLarge(_) => None,
             DecodeError::PublishDupAtMostOnce => None,
             DecodeError::RemainingLengthTooHigh => None,
+            DecodeError::StringLengthExceedsPacket { .. } => None,
             DecodeError::StringNotUtf8(err) => Some(err),
+            DecodeError::TooManyReasonCodes(_) => None,
+            DecodeError::TooManyTopics(_) => None,
             DecodeError::TrailingGarbage => None,
             DecodeError::UnrecognizedConnAckFlags(_) => None,
+            DecodeError::UnrecognizedFixedHeaderFlags { .. } => None,
             DecodeError::UnrecognizedPacket { .. } => None,
             DecodeError::UnrecognizedProtocolName(_) => None,
             DecodeError::UnrecognizedProtocolVersion(_) => None,
@@ -452,14 +759,26 @@ impl std::error::Error for DecodeError {
             DecodeError::ZeroPacketIdentifier => None,
 
             // Specific to v3
+            DecodeError::PacketTypeNotValidForVersion { .. } => None,
 
             // Specific to v5
+            DecodeError::ConflictingDuplicateProperty(_) => None,
             DecodeError::DuplicateProperty(_) => None,
+            DecodeError::DuplicateSubscriptionIdentifier => None,
             DecodeError::MissingRequiredProperty(_) => None,
+            DecodeError::MissingWillPropertyLength => None,
+            DecodeError::NoLocalOnSharedSubscription => None,
+            DecodeError::PropertiesTooLarge(_) => None,
+            DecodeError::PropertyLengthExceedsPacket { .. } => None,
+            DecodeError::StreamPoisoned => None,
+            DecodeError::SubscriptionIdentifierTooLarge => None,
+            DecodeError::TooManyProperties(_) => None,
+            DecodeError::UnexpectedPacketType { .. } => None,
             DecodeError::UnexpectedProperty => None,
             DecodeError::UnrecognizedPropertyIdentifier(_) => None,
 
             DecodeError::InvalidMaximumPacketSize(_) => None,
+            DecodeError::InvalidReceiveMaximum(_) => None,
             DecodeError::UnrecognizedAuthenticateReasonCode(_) => None,
             DecodeError::UnrecognizedConnectReasonCode(_) => None,
             DecodeError::UnrecognizedDisconnectReasonCode(_) => None,
@@ -484,18 +803,79 @@ impl std::error::Error for DecodeError {
     }
 }
 
+impl DecodeError {
+    /// Wraps this error with the type of the packet that was being decoded, for more useful diagnostics.
+    pub fn with_packet_type(self, packet_type: v5::PacketType) -> ContextualDecodeError {
+        ContextualDecodeError {
+            error: self,
+            packet_type,
+        }
+    }
+}
+
 impl From<std::io::Error> for DecodeError {
     fn from(err: std::io::Error) -> Self {
         DecodeError::Io(err)
     }
 }
 
+impl From<std::str::Utf8Error> for DecodeError {
+    fn from(err: std::str::Utf8Error) -> Self {
+        DecodeError::StringNotUtf8(err)
+    }
+}
+
+/// A [`DecodeError`] along with the number of bytes of the packet body that had been consumed when it occurred.
+#[derive(Debug)]
+pub struct DecodeErrorAt {
+    pub error: DecodeError,
+    pub offset: usize,
+}
+
+impl std::fmt::Display for DecodeErrorAt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} at offset {} of the packet body",
+            self.error, self.offset
+        )
+    }
+}
+
+impl std::error::Error for DecodeErrorAt {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// A [`DecodeError`] along with the type of the packet that was being decoded when it occurred.
+///
+/// Constructed with [`DecodeError::with_packet_type`].
+#[derive(Debug)]
+pub struct ContextualDecodeError {
+    pub error: DecodeError,
+    pub packet_type: v5::PacketType,
+}
+
+impl std::fmt::Display for ContextualDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "while decoding {}: {}", self.packet_type, self.error)
+    }
+}
+
+impl std::error::Error for ContextualDecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
 #[derive(Debug)]
 pub enum EncodeError {
     // Common
     InsufficientBuffer,
     Io(std::io::Error),
     KeepAliveTooHigh(std::time::Duration),
+    NoTopics,
     RemainingLengthTooHigh(usize),
     StringTooLarge(usize),
     WillTooLarge(usize),
@@ -503,6 +883,7 @@ pub enum EncodeError {
     // Specific to v3
 
     // Specific to v5
+    AssignedClientIdNotServerGenerated,
     InvalidMaximumPacketSize(usize),
     InvalidMessageExpiryInterval(Duration),
     InvalidReceiveMaximum(usize),
@@ -510,6 +891,11 @@ pub enum EncodeError {
     InvalidSessionExpiryInterval(Duration),
     InvalidTopicAlias(u16),
     InvalidWillDelayInterval(Duration),
+    MissingPacketIdentifier(QoS),
+    PacketTooLarge(usize),
+    QoSNotSupported(QoS),
+    ResponseInformationNotRequested,
+    RetainNotSupported,
 }
 
 impl std::fmt::Display for EncodeError {
@@ -521,6 +907,7 @@ impl std::fmt::Display for EncodeError {
             EncodeError::KeepAliveTooHigh(keep_alive) => {
                 write!(f, "keep-alive {:?} is too high", keep_alive)
             }
+            EncodeError::NoTopics => f.write_str("expected at least one topic but there were none"),
             EncodeError::RemainingLengthTooHigh(len) => {
                 write!(f, "remaining length {} is too high to be encoded", len)
             }
@@ -536,6 +923,9 @@ impl std::fmt::Display for EncodeError {
             // Specific to v3
 
             // Specific to v5
+            EncodeError::AssignedClientIdNotServerGenerated => f.write_str(
+                "assigned client identifier property set but the client did not ask for one",
+            ),
             EncodeError::InvalidMaximumPacketSize(value) => write!(
                 f,
                 "maximum packet size property set to invalid value {}",
@@ -567,6 +957,20 @@ impl std::fmt::Display for EncodeError {
                 "will delay interval property set to invalid value {}s",
                 interval.as_secs()
             ),
+            EncodeError::MissingPacketIdentifier(qos) => {
+                write!(f, "{qos:?} publication requires a packet identifier")
+            }
+            EncodeError::PacketTooLarge(len) => write!(
+                f,
+                "packet of size {len} exceeds the receiver's maximum packet size"
+            ),
+            EncodeError::QoSNotSupported(qos) => {
+                write!(f, "{qos:?} exceeds the receiver's maximum QoS")
+            }
+            EncodeError::ResponseInformationNotRequested => {
+                f.write_str("response information property set but the client did not request it")
+            }
+            EncodeError::RetainNotSupported => f.write_str("receiver does not support retain"),
         }
     }
 }
@@ -579,6 +983,7 @@ impl std::error::Error for EncodeError {
             EncodeError::InsufficientBuffer => None,
             EncodeError::Io(err) => Some(err),
             EncodeError::KeepAliveTooHigh(_) => None,
+            EncodeError::NoTopics => None,
             EncodeError::RemainingLengthTooHigh(_) => None,
             EncodeError::StringTooLarge(_) => None,
             EncodeError::WillTooLarge(_) => None,
@@ -586,6 +991,7 @@ impl std::error::Error for EncodeError {
             // Specific to v3
 
             // Specific to v5
+            EncodeError::AssignedClientIdNotServerGenerated => None,
             EncodeError::InvalidMaximumPacketSize(_) => None,
             EncodeError::InvalidMessageExpiryInterval(_) => None,
             EncodeError::InvalidReceiveMaximum(_) => None,
@@ -593,6 +999,11 @@ impl std::error::Error for EncodeError {
             EncodeError::InvalidSessionExpiryInterval(_) => None,
             EncodeError::InvalidTopicAlias(_) => None,
             EncodeError::InvalidWillDelayInterval(_) => None,
+            EncodeError::MissingPacketIdentifier(_) => None,
+            EncodeError::PacketTooLarge(_) => None,
+            EncodeError::QoSNotSupported(_) => None,
+            EncodeError::ResponseInformationNotRequested => None,
+            EncodeError::RetainNotSupported => None,
         }
     }
 }
@@ -623,6 +1034,18 @@ pub trait ByteBuf {
         self.try_put_u16_be(packet_identifier.0)
     }
 
+    /// Writes the given string as an MQTT UTF-8 encoded string, ie prefixed with its two-byte big-endian length.
+    ///
+    /// Ref: 1.5.3 UTF-8 encoded strings
+    fn try_put_str(&mut self, s: &str) -> Result<(), EncodeError> {
+        let len: u16 = s
+            .len()
+            .try_into()
+            .map_err(|_| EncodeError::StringTooLarge(s.len()))?;
+        self.try_put_u16_be(len)?;
+        self.try_put_slice(s.as_bytes())
+    }
+
     fn try_put_bytes<P>(&mut self, src: Shared<P>) -> Result<(), EncodeError>
     where
         P: BufferPool,
@@ -651,6 +1074,13 @@ where
 #[derive(Default)]
 struct ByteCounter(usize);
 
+impl ByteBuf for Vec<u8> {
+    fn try_put_slice(&mut self, src: &[u8]) -> Result<(), EncodeError> {
+        self.extend_from_slice(src);
+        Ok(())
+    }
+}
+
 impl ByteBuf for ByteCounter {
     fn try_put_slice(&mut self, src: &[u8]) -> Result<(), EncodeError> {
         self.0 += src.len();
@@ -658,6 +1088,26 @@ impl ByteBuf for ByteCounter {
     }
 }
 
+/// A [`ByteBuf`] that feeds the bytes of encoded packets into a [`std::hash::Hasher`] instead of storing them,
+/// eg to compute a content hash of a packet without allocating a buffer for it.
+///
+/// Encoding writes the bytes of a packet in several pieces, so the hasher should only depend on
+/// the concatenation of the bytes it is given and not on how they are split up, like [`std::collections::hash_map::DefaultHasher`].
+/// But the algorithm of `DefaultHasher` may change between Rust releases, so a hash that is stored or compared
+/// across processes needs a hasher with a fixed algorithm, eg FNV-1a.
+#[derive(Debug, Default)]
+pub struct Hasher<H>(pub H);
+
+impl<H> ByteBuf for Hasher<H>
+where
+    H: std::hash::Hasher,
+{
+    fn try_put_slice(&mut self, src: &[u8]) -> Result<(), EncodeError> {
+        self.0.write(src);
+        Ok(())
+    }
+}
+
 /// Decode the fixed header of an MQTT packet.
 ///
 /// Ref:
@@ -678,6 +1128,108 @@ pub fn decode_fixed_header(src: &mut &[u8]) -> Result<Option<(u8, usize)>, Decod
     Ok(Some((first_byte, remaining_length)))
 }
 
+/// Computes the total length of the packet that starts at the beginning of the given bytes, including its fixed header,
+/// so that a buffer large enough to hold the whole packet can be allocated up front.
+///
+/// `header_peek` only needs to contain the fixed header, ie between two and five bytes. Returns `Ok(None)` if it doesn't
+/// contain the complete fixed header yet.
+///
+/// Ref:
+/// - 3.1.1: 2 MQTT Control Packet format
+/// - 5.0:   2 MQTT Control Packet format
+pub fn full_packet_len(header_peek: &[u8]) -> Result<Option<usize>, DecodeError> {
+    let mut src = header_peek;
+    let Some((_, remaining_length)) = decode_fixed_header(&mut src)? else {
+        return Ok(None);
+    };
+
+    let header_len = header_peek.len() - src.len();
+    Ok(Some(header_len + remaining_length))
+}
+
+/// The fixed header of a packet found by [`scan_packets`], and where the packet is in the scanned buffer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PacketHeader {
+    /// The upper four bits of the first byte of the packet.
+    pub packet_type: u8,
+
+    /// The offset of the start of the packet in the scanned buffer.
+    pub offset: usize,
+
+    /// The length of the fixed header, ie the first byte and the encoded remaining length.
+    pub header_len: usize,
+
+    /// The length of the body of the packet after its fixed header.
+    pub remaining_length: usize,
+}
+
+/// Iterates over the fixed headers of the consecutive packets in the given buffer without decoding their bodies,
+/// eg to build an index into a captured stream.
+///
+/// Iteration stops at a packet that is not completely contained in the buffer, or after the first error.
+///
+/// Ref:
+/// - 3.1.1: 2 MQTT Control Packet format
+/// - 5.0:   2 MQTT Control Packet format
+pub fn scan_packets(buf: &[u8]) -> impl Iterator<Item = Result<PacketHeader, DecodeError>> + '_ {
+    let mut offset = 0;
+    let mut failed = false;
+
+    std::iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+
+        let mut src = &buf[offset..];
+        let (first_byte, remaining_length) = match decode_fixed_header(&mut src) {
+            Ok(Some(fixed_header)) => fixed_header,
+            Ok(None) => return None,
+            Err(err) => {
+                failed = true;
+                return Some(Err(err));
+            }
+        };
+
+        let header_len = buf.len() - offset - src.len();
+        if src.len() < remaining_length {
+            return None;
+        }
+
+        let header = PacketHeader {
+            packet_type: first_byte & 0xF0,
+            offset,
+            header_len,
+            remaining_length,
+        };
+        offset += header_len + remaining_length;
+        Some(Ok(header))
+    })
+}
+
+/// Checks that the given flags are legal in the fixed header of a packet of the given type.
+///
+/// `packet_type` is the upper four bits of the first byte of the fixed header, and `flags` is the lower four bits.
+/// The flags of a PUBLISH packet are always accepted here, since they carry its DUP flag, quality of service and RETAIN flag,
+/// which are validated when the packet itself is decoded. Every other packet type requires one particular value.
+///
+/// Ref:
+/// - 3.1.1: 2.2.2 Flags
+/// - 5.0:   2.1.3 Flags
+pub fn validate_fixed_header_flags(packet_type: u8, flags: u8) -> Result<(), DecodeError> {
+    let required_flags = match packet_type {
+        0x30 => return Ok(()),
+        0x60 | 0x80 | 0xA0 => 0x02,
+        0x10 | 0x20 | 0x40 | 0x50 | 0x70 | 0x90 | 0xB0 | 0xC0 | 0xD0 | 0xE0 | 0xF0 => 0x00,
+        _ => return Err(DecodeError::UnrecognizedFixedHeaderFlags { packet_type, flags }),
+    };
+
+    if flags == required_flags {
+        Ok(())
+    } else {
+        Err(DecodeError::UnrecognizedFixedHeaderFlags { packet_type, flags })
+    }
+}
+
 /// Metadata about a packet
 trait PacketMeta<P>: Clone + Sized
 where
@@ -687,7 +1239,7 @@ where
     const PACKET_TYPE: u8;
 
     /// Decodes this packet from the given buffer
-    fn decode(flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError>;
+    fn decode(flags: u8, src: &mut Shared<P>, ctx: &DecodeContext<P>) -> Result<Self, DecodeError>;
 
     /// Encodes the variable header and payload corresponding to this packet into the given buffer.
     /// The buffer is expected to already have the packet type and body length encoded into it,
@@ -695,6 +1247,156 @@ where
     fn encode<B>(self, dst: &mut B) -> Result<(), EncodeError>
     where
         B: ByteBuf;
+
+    /// The length of the variable header and payload of this packet, if it can be computed without encoding it.
+    ///
+    /// If this returns `None`, the length is computed by encoding a clone of this packet into a [`ByteCounter`]
+    fn encoded_len(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// The buffer pool and limits used when decoding packets.
+///
+/// The limits are all unset by default, ie decoding is as permissive as the spec allows.
+#[derive(Clone, Debug)]
+pub struct DecodeContext<P>
+where
+    P: BufferPool,
+{
+    pub pool: P,
+
+    /// The maximum size of a packet, including its fixed header.
+    ///
+    /// Packets larger than this fail to decode with [`DecodeError::PacketTooLarge`]
+    pub maximum_packet_size: Option<usize>,
+
+    /// The maximum number of properties in a single v5 property block.
+    ///
+    /// Property blocks with more properties than this fail to decode with [`DecodeError::TooManyProperties`]
+    pub maximum_properties: Option<usize>,
+
+    /// The maximum length of a single v5 property block, not including its property length.
+    ///
+    /// Property blocks longer than this fail to decode with [`DecodeError::PropertiesTooLarge`]
+    pub maximum_properties_len: Option<usize>,
+
+    /// The maximum number of topic filters in a single SUBSCRIBE or UNSUBSCRIBE.
+    ///
+    /// Packets with more topic filters than this fail to decode with [`DecodeError::TooManyTopics`]
+    pub maximum_topics: Option<usize>,
+
+    /// The maximum number of reason codes in a single SUBACK or UNSUBACK.
+    ///
+    /// Packets with more reason codes than this fail to decode with [`DecodeError::TooManyReasonCodes`]
+    pub maximum_reason_codes: Option<usize>,
+
+    /// The maximum length of the payload of a PUBLISH, ie the part of its body after the topic name, packet identifier and properties.
+    ///
+    /// PUBLISH packets with a longer payload than this fail to decode with [`DecodeError::PayloadTooLarge`]
+    pub maximum_payload_len: Option<usize>,
+
+    /// Whether a v5 property block may repeat a single-valued property as long as every occurrence has the same value.
+    ///
+    /// If this is `false`, any repeated single-valued property fails to decode with [`DecodeError::DuplicateProperty`].
+    /// If this is `true`, only repetitions with a different value fail to decode,
+    /// with [`DecodeError::ConflictingDuplicateProperty`].
+    pub allow_identical_duplicate_properties: bool,
+}
+
+impl<P> DecodeContext<P>
+where
+    P: BufferPool,
+{
+    pub fn new(pool: P) -> Self {
+        DecodeContext {
+            pool,
+            maximum_packet_size: None,
+            maximum_properties: None,
+            maximum_properties_len: None,
+            maximum_topics: None,
+            maximum_reason_codes: None,
+            maximum_payload_len: None,
+            allow_identical_duplicate_properties: false,
+        }
+    }
+
+    /// Checks the size of a packet with the given first byte and body length against [`DecodeContext::maximum_packet_size`]
+    fn check_packet_size(&self, first_byte: u8, body_len: usize) -> Result<(), DecodeError> {
+        if let Some(maximum_packet_size) = self.maximum_packet_size {
+            match packet_size(body_len) {
+                Some(packet_size) if packet_size <= maximum_packet_size => (),
+                _ => {
+                    return Err(DecodeError::PacketTooLarge {
+                        packet_type: first_byte & 0xF0,
+                        remaining_length: body_len,
+                    })
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the number of topic filters decoded so far against [`DecodeContext::maximum_topics`]
+    pub(crate) fn check_topics(&self, count: usize) -> Result<(), DecodeError> {
+        match self.maximum_topics {
+            Some(maximum_topics) if count > maximum_topics => {
+                Err(DecodeError::TooManyTopics(maximum_topics))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the length of a PUBLISH payload against [`DecodeContext::maximum_payload_len`]
+    pub(crate) fn check_payload_len(&self, payload_len: usize) -> Result<(), DecodeError> {
+        match self.maximum_payload_len {
+            Some(maximum_payload_len) if payload_len > maximum_payload_len => {
+                Err(DecodeError::PayloadTooLarge(payload_len))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the number of reason codes in a packet against [`DecodeContext::maximum_reason_codes`]
+    pub(crate) fn check_reason_codes(&self, count: usize) -> Result<(), DecodeError> {
+        match self.maximum_reason_codes {
+            Some(maximum_reason_codes) if count > maximum_reason_codes => {
+                Err(DecodeError::TooManyReasonCodes(maximum_reason_codes))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A decoded packet along with the original bytes it was decoded from,
+/// so that it can be forwarded verbatim instead of being re-encoded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DecodedPacket<T, P>
+where
+    P: BufferPool,
+{
+    pub packet: T,
+    pub first_byte: u8,
+    pub raw_body: Shared<P>,
+}
+
+impl<T, P> DecodedPacket<T, P>
+where
+    P: BufferPool,
+{
+    /// The number of bytes the body of the packet occupied, ie its remaining length.
+    pub fn body_len(&self) -> usize {
+        self.raw_body.len()
+    }
+
+    /// The number of bytes the whole packet occupied, including its fixed header.
+    ///
+    /// Panics if [`DecodedPacket::raw_body`] is longer than the body of any packet can be,
+    /// which can't happen for a value returned by a `decode_with_raw_body` function.
+    pub fn packet_len(&self) -> usize {
+        packet_size(self.body_len()).expect("decoded packet body cannot be too large to encode")
+    }
 }
 
 pub enum Connect<P>
@@ -705,14 +1407,28 @@ where
     V5(v5::Connect<P>),
 }
 
+impl<P> Connect<P>
+where
+    P: BufferPool,
+{
+    /// Whether this packet requested a new session, ie whether its Clean Session (3.1.1) or Clean Start (5.0) flag is set.
+    pub fn clean_start(&self) -> bool {
+        match self {
+            Connect::V3(connect) => connect.clean_start(),
+            Connect::V5(connect) => connect.clean_start(),
+        }
+    }
+}
+
 impl<P> Connect<P>
 where
     P: Clone + BufferPool,
 {
     pub fn decode(flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+        let ctx = DecodeContext::new(src.pool().clone());
         match decode_connect_start(flags, src)? {
             v3::PROTOCOL_LEVEL => Ok(Connect::V3(v3::Connect::decode_rest(src)?)),
-            v5::PROTOCOL_VERSION => Ok(Connect::V5(v5::Connect::decode_rest(src)?)),
+            v5::PROTOCOL_VERSION => Ok(Connect::V5(v5::Connect::decode_rest(src, &ctx)?)),
             protocol_version => Err(DecodeError::UnrecognizedProtocolVersion(protocol_version)),
         }
     }
@@ -722,6 +1438,37 @@ fn decode_connect_start<P>(flags: u8, src: &mut Shared<P>) -> Result<u8, DecodeE
 where
     P: Clone + BufferPool,
 {
+    let (protocol_level, len) = decode_connect_start_inner(flags, &src[..])?;
+    src.drain(len);
+    Ok(protocol_level)
+}
+
+/// Get the protocol version of a CONNECT packet without decoding the rest of it.
+///
+/// This validates the packet type and the protocol name, and returns the protocol level (3.1.1) or protocol version (5.0) byte.
+///
+/// Ref:
+/// - 3.1.1: 3.1.2.2 Protocol Level
+/// - 5.0:   3.1.2.2 Protocol Version
+pub fn peek_protocol_version<P>(first_byte: u8, body: &Shared<P>) -> Result<u8, DecodeError>
+where
+    P: BufferPool,
+{
+    let packet_type = first_byte & 0xF0;
+    if packet_type != 0x10 {
+        return Err(DecodeError::UnrecognizedPacket {
+            packet_type,
+            flags: first_byte & 0x0F,
+            remaining_length: body.len(),
+        });
+    }
+
+    let (protocol_level, _) = decode_connect_start_inner(first_byte & 0x0F, body.as_ref())?;
+    Ok(protocol_level)
+}
+
+/// Returns the protocol level and the number of bytes of `src` that it and the protocol name occupied.
+fn decode_connect_start_inner(flags: u8, src: &[u8]) -> Result<(u8, usize), DecodeError> {
     if flags != 0 {
         return Err(DecodeError::UnrecognizedPacket {
             packet_type: 0x10,
@@ -730,19 +1477,32 @@ where
         });
     }
 
-    let protocol_name = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
+    let protocol_name_len: usize = match src.get(..size_of::<u16>()) {
+        Some(src) => u16::from_be_bytes(src.try_into().unwrap()).into(),
+        None => return Err(DecodeError::IncompletePacket),
+    };
+    let protocol_name = src
+        .get(..(size_of::<u16>() + protocol_name_len))
+        .ok_or(DecodeError::IncompletePacket)?;
     if protocol_name != PROTOCOL_NAME {
-        return Err(DecodeError::UnrecognizedProtocolName(
-            protocol_name.as_ref().to_owned(),
-        ));
+        // The name need not be valid UTF-8, so non-printable and non-ASCII bytes are escaped as `\xNN`
+        // rather than being replaced, which would make different names indistinguishable.
+        let protocol_name = protocol_name[size_of::<u16>()..]
+            .iter()
+            .flat_map(|&b| std::ascii::escape_default(b))
+            .map(char::from)
+            .collect();
+        return Err(DecodeError::UnrecognizedProtocolName(protocol_name));
     }
 
-    let protocol_level = src.try_get_u8()?;
-    Ok(protocol_level)
+    let protocol_level = *src
+        .get(protocol_name.len())
+        .ok_or(DecodeError::IncompletePacket)?;
+    Ok((protocol_level, protocol_name.len() + 1))
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     #[test]
@@ -797,20 +1557,210 @@ mod tests {
         }
     }
 
-    #[derive(Clone, Copy)]
-    struct TestBufferPool;
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    pub(crate) struct TestBufferPool;
 
     impl TestBufferPool {
         #[allow(clippy::trivially_copy_pass_by_ref, clippy::unused_self)]
-        fn take(&self, len: usize) -> std::sync::Arc<[u8]> {
+        pub(crate) fn take(&self, len: usize) -> std::sync::Arc<[u8]> {
             vec![0_u8; len].into_iter().collect()
         }
     }
 
+    /// Copies the given bytes into a new `Shared` backed by a [`TestBufferPool`]
+    pub(crate) fn shared(bytes: &[u8]) -> Shared<TestBufferPool> {
+        let pool = TestBufferPool;
+        let mut owned = Owned::new(pool, pool.take(bytes.len()));
+        ByteBuf::try_put_slice(&mut owned, bytes).unwrap();
+        owned.freeze()
+    }
+
+    /// Decodes a `ByteStr` that is the entirety of the given bytes
+    pub(crate) fn byte_str(s: &str) -> ByteStr<TestBufferPool> {
+        let len: u16 = std::convert::TryInto::try_into(s.len()).unwrap();
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&len.to_be_bytes());
+        bytes.extend_from_slice(s.as_bytes());
+        ByteStr::decode(&mut shared(&bytes)).unwrap().unwrap()
+    }
+
     impl BufferPool for TestBufferPool {
         fn put_back(&self, _backing: std::sync::Arc<[u8]>) {}
     }
 
+    /// Wraps the system allocator to count the allocations made by each thread, for [`count_allocations`]
+    struct CountingAllocator;
+
+    std::thread_local! {
+        static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout);
+        }
+
+        unsafe fn realloc(
+            &self,
+            ptr: *mut u8,
+            layout: std::alloc::Layout,
+            new_size: usize,
+        ) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+            std::alloc::System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Calls the given function and returns its result along with the number of allocations and reallocations it made
+    /// on the current thread.
+    pub(crate) fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+        let before = ALLOCATIONS.with(std::cell::Cell::get);
+        let result = f();
+        let after = ALLOCATIONS.with(std::cell::Cell::get);
+        (result, after - before)
+    }
+
+    #[test]
+    fn full_packet_len() {
+        let cases: &[(&[u8], usize)] = &[
+            (b"\x30\x00", 2),
+            (b"\x30\x7F", 2 + 0x7F),
+            (b"\x30\x80\x01", 3 + 0x80),
+            (b"\x30\xFF\x7F", 3 + 0x3FFF),
+            (b"\x30\x80\x80\x01", 4 + 0x4000),
+            (b"\x30\xFF\xFF\x7F", 4 + 0x001F_FFFF),
+            (b"\x30\x80\x80\x80\x01", 5 + 0x0020_0000),
+            (b"\x30\xFF\xFF\xFF\x7F", 5 + 0x0FFF_FFFF),
+        ];
+
+        for &(header, expected) in cases {
+            // Every strict prefix of the fixed header is incomplete
+            for len in 0..header.len() {
+                assert_eq!(super::full_packet_len(&header[..len]).unwrap(), None);
+            }
+
+            assert_eq!(super::full_packet_len(header).unwrap(), Some(expected));
+
+            // Trailing bytes of the body don't count towards the fixed header
+            let mut peek = header.to_vec();
+            peek.extend_from_slice(b"\x00\x01\x02");
+            assert_eq!(super::full_packet_len(&peek).unwrap(), Some(expected));
+        }
+
+        match super::full_packet_len(b"\x30\xFF\xFF\xFF\xFF\x01") {
+            Err(super::DecodeError::RemainingLengthTooHigh) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn scan_packets() {
+        let mut buf = vec![];
+        buf.extend_from_slice(b"\xC0\x00");
+        buf.extend_from_slice(b"\x30\x0A\x00\x03a/bhello");
+        let mut large_publish = vec![0x30, 0x80, 0x01];
+        large_publish.extend_from_slice(&[0; 0x80]);
+        buf.extend_from_slice(&large_publish);
+        buf.extend_from_slice(b"\xE0\x00");
+
+        let expected = vec![
+            PacketHeader {
+                packet_type: 0xC0,
+                offset: 0,
+                header_len: 2,
+                remaining_length: 0,
+            },
+            PacketHeader {
+                packet_type: 0x30,
+                offset: 2,
+                header_len: 2,
+                remaining_length: 10,
+            },
+            PacketHeader {
+                packet_type: 0x30,
+                offset: 14,
+                header_len: 3,
+                remaining_length: 0x80,
+            },
+            PacketHeader {
+                packet_type: 0xE0,
+                offset: 14 + 3 + 0x80,
+                header_len: 2,
+                remaining_length: 0,
+            },
+        ];
+
+        let headers: Vec<_> = super::scan_packets(&buf).map(Result::unwrap).collect();
+        assert_eq!(headers, expected);
+
+        // A partial trailing frame is not returned
+        buf.extend_from_slice(b"\x30\x0A\x00\x03");
+        let headers: Vec<_> = super::scan_packets(&buf).map(Result::unwrap).collect();
+        assert_eq!(headers, expected);
+
+        // Neither is anything after an invalid fixed header
+        let mut buf = b"\xC0\x00\x30\xFF\xFF\xFF\xFF\x01\xC0\x00".to_vec();
+        let mut headers = super::scan_packets(&buf);
+        assert_eq!(headers.next().unwrap().unwrap(), expected[0]);
+        match headers.next() {
+            Some(Err(DecodeError::RemainingLengthTooHigh)) => (),
+            result => panic!("{:?}", result),
+        }
+        assert!(headers.next().is_none());
+        drop(headers);
+
+        buf.clear();
+        assert!(super::scan_packets(&buf).next().is_none());
+    }
+
+    #[test]
+    fn validate_fixed_header_flags() {
+        let cases: &[(u8, Option<u8>)] = &[
+            (0x00, None),
+            (0x10, Some(0x00)),
+            (0x20, Some(0x00)),
+            (0x40, Some(0x00)),
+            (0x50, Some(0x00)),
+            (0x60, Some(0x02)),
+            (0x70, Some(0x00)),
+            (0x80, Some(0x02)),
+            (0x90, Some(0x00)),
+            (0xA0, Some(0x02)),
+            (0xB0, Some(0x00)),
+            (0xC0, Some(0x00)),
+            (0xD0, Some(0x00)),
+            (0xE0, Some(0x00)),
+            (0xF0, Some(0x00)),
+        ];
+
+        for &(packet_type, required_flags) in cases {
+            for flags in 0x00..=0x0F {
+                match super::validate_fixed_header_flags(packet_type, flags) {
+                    Ok(()) if required_flags == Some(flags) => (),
+                    Err(super::DecodeError::UnrecognizedFixedHeaderFlags {
+                        packet_type: actual_packet_type,
+                        flags: actual_flags,
+                    }) if actual_packet_type == packet_type
+                        && actual_flags == flags
+                        && required_flags != Some(flags) => {}
+                    result => panic!("0x{:02X} 0x{:02X}: {:?}", packet_type, flags, result),
+                }
+            }
+        }
+
+        for flags in 0x00..=0x0F {
+            super::validate_fixed_header_flags(0x30, flags).unwrap();
+        }
+    }
+
     #[test]
     fn remaining_length_decode() {
         remaining_length_decode_inner_ok(&[0x00], 0x00);
@@ -854,4 +1804,141 @@ mod tests {
         let actual = decode_remaining_length(&mut bytes).unwrap();
         assert_eq!(actual, None);
     }
+
+    #[test]
+    fn put_str() {
+        let pool = TestBufferPool;
+
+        let mut bytes = Owned::new(pool, pool.take(16));
+        bytes.try_put_str("foo/bar").unwrap();
+        assert_eq!(bytes.filled(), b"\x00\x07foo/bar");
+
+        let mut src = bytes.freeze();
+        let s = ByteStr::decode(&mut src).unwrap().unwrap();
+        assert_eq!(s, "foo/bar");
+        assert!(src.is_empty());
+
+        let long = "a".repeat(0x1_0000);
+        let mut bytes = Owned::new(pool, pool.take(0x1_0010));
+        match bytes.try_put_str(&long) {
+            Err(EncodeError::StringTooLarge(0x1_0000)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn packet_identifier_next() {
+        assert_eq!(PacketIdentifier::first().get(), 1);
+        assert_eq!(PacketIdentifier::first().next().get(), 2);
+        assert_eq!(
+            PacketIdentifier::max_value().next(),
+            PacketIdentifier::first()
+        );
+    }
+
+    #[test]
+    fn validate_client_id() {
+        for policy in [ClientIdPolicy::Strict, ClientIdPolicy::Lenient] {
+            assert_eq!(
+                super::validate_client_id("", policy),
+                Err(ClientIdError::Empty)
+            );
+            assert_eq!(super::validate_client_id("client1", policy), Ok(()));
+        }
+
+        let id = "abcdefghijklmnopqrstuvwx";
+        assert_eq!(id.len(), 24);
+        assert_eq!(
+            super::validate_client_id(id, ClientIdPolicy::Strict),
+            Err(ClientIdError::TooLong(24))
+        );
+        assert_eq!(
+            super::validate_client_id(id, ClientIdPolicy::Lenient),
+            Ok(())
+        );
+
+        assert_eq!(
+            super::validate_client_id("edge-device", ClientIdPolicy::Strict),
+            Err(ClientIdError::IllegalCharacter('-'))
+        );
+        assert_eq!(
+            super::validate_client_id("edge-device", ClientIdPolicy::Lenient),
+            Ok(())
+        );
+
+        assert_eq!(
+            super::validate_client_id("a\0b", ClientIdPolicy::Lenient),
+            Err(ClientIdError::IllegalCharacter('\0'))
+        );
+    }
+
+    #[test]
+    fn decode_error_from_utf8_error() {
+        fn decode(bytes: &[u8]) -> Result<&str, DecodeError> {
+            Ok(std::str::from_utf8(bytes)?)
+        }
+
+        assert_eq!(decode(b"foo").unwrap(), "foo");
+        match decode(b"\xC3\x28") {
+            Err(DecodeError::StringNotUtf8(err)) => assert_eq!(err.valid_up_to(), 0),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn decode_error_with_packet_type() {
+        let err = DecodeError::TrailingGarbage.with_packet_type(crate::v5::PacketType::Subscribe);
+        assert_eq!(
+            err.to_string(),
+            "while decoding SUBSCRIBE: packet has trailing garbage"
+        );
+        assert!(std::error::Error::source(&err).is_some());
+    }
+
+    #[test]
+    fn peek_protocol_version() {
+        let v3_body = shared(b"\x00\x04MQTT\x04\x02\x00\x1E\x00\x03abc");
+        assert_eq!(super::peek_protocol_version(0x10, &v3_body).unwrap(), 0x04);
+
+        let v5_body = shared(b"\x00\x04MQTT\x05\x02\x00\x1E\x00\x00\x03abc");
+        assert_eq!(super::peek_protocol_version(0x10, &v5_body).unwrap(), 0x05);
+
+        let bad_body = shared(b"\x00\x06MQIsdp\x03\x02\x00\x1E\x00\x03abc");
+        match super::peek_protocol_version(0x10, &bad_body) {
+            Err(DecodeError::UnrecognizedProtocolName(name)) => assert_eq!(name, "MQIsdp"),
+            result => panic!("{:?}", result),
+        }
+
+        let non_utf8_body = shared(b"\x00\x04MQ\xFF\x80\x05\x02\x00\x1E\x00\x00\x03abc");
+        match super::peek_protocol_version(0x10, &non_utf8_body) {
+            Err(DecodeError::UnrecognizedProtocolName(name)) => assert_eq!(name, "MQ\\xff\\x80"),
+            result => panic!("{:?}", result),
+        }
+
+        match super::peek_protocol_version(0x30, &v3_body) {
+            Err(DecodeError::UnrecognizedPacket {
+                packet_type: 0x30, ..
+            }) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn decode_with_raw_body() {
+        let body = shared(b"\x00\x0Bfoo/bar/baz\x00\x01hello");
+
+        let decoded = v3::decode_with_raw_body(0x32, body.clone()).unwrap();
+        assert_eq!(decoded.first_byte, 0x32);
+        assert_eq!(decoded.raw_body, body);
+        assert!(matches!(decoded.packet, v3::Packet::Publish(_)));
+
+        let body = shared(b"\x00\x0Bfoo/bar/baz\x00\x01\x00hello");
+
+        let decoded = v5::decode_with_raw_body(0x32, body.clone()).unwrap();
+        assert_eq!(decoded.first_byte, 0x32);
+        assert_eq!(decoded.raw_body, body);
+        assert!(matches!(decoded.packet, v5::Packet::Publish(_)));
+        assert_eq!(decoded.body_len(), body.len());
+        assert_eq!(decoded.packet_len(), 2 + body.len());
+    }
 }
@@ -0,0 +1,216 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use crate::{BufferPool, ByteStr};
+
+/// A topic name, ie a non-empty string without wildcard characters that a message can be published to.
+///
+/// Constructors of PUBLISH packets that take a `TopicName` cannot be given a [`TopicFilter`] by mistake:
+///
+/// ```compile_fail
+/// # use mqtt_proto::{BufferPool, Shared, TopicFilter};
+/// # use mqtt_proto::v5::{PacketIdentifierDupQoS, Publish};
+/// fn publish<P>(topic_filter: TopicFilter<P>, payload: Shared<P>) -> Publish<P>
+/// where
+///     P: Clone + BufferPool,
+/// {
+///     Publish::new(topic_filter, PacketIdentifierDupQoS::AtMostOnce, false, payload)
+/// }
+/// ```
+///
+/// Ref:
+/// - 3.1.1: 4.7 Topic Names and Topic Filters
+/// - 5.0:   4.7 Topic Names and Topic Filters
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct TopicName<P>(ByteStr<P>)
+where
+    P: BufferPool;
+
+impl<P> TopicName<P>
+where
+    P: BufferPool,
+{
+    /// Returns the given string back as an error if it is not a valid topic name, ie if it is empty or contains `+` or `#`.
+    pub fn new(topic_name: ByteStr<P>) -> Result<Self, ByteStr<P>> {
+        if !is_valid_topic_name(topic_name.as_ref()) {
+            return Err(topic_name);
+        }
+
+        Ok(TopicName(topic_name))
+    }
+
+    pub fn as_byte_str(&self) -> &ByteStr<P> {
+        &self.0
+    }
+
+    pub fn into_byte_str(self) -> ByteStr<P> {
+        self.0
+    }
+}
+
+/// Whether the given string is a valid topic name. See [`TopicName::new`]
+pub(crate) fn is_valid_topic_name(topic_name: &str) -> bool {
+    !topic_name.is_empty() && !topic_name.contains(&['+', '#'][..])
+}
+
+/// A topic filter, ie a non-empty string that may contain wildcards that a subscription can be made to.
+///
+/// Ref:
+/// - 3.1.1: 4.7 Topic Names and Topic Filters
+/// - 5.0:   4.7 Topic Names and Topic Filters
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct TopicFilter<P>(ByteStr<P>)
+where
+    P: BufferPool;
+
+impl<P> TopicFilter<P>
+where
+    P: BufferPool,
+{
+    /// Returns the given string back as an error if it is not a valid topic filter, ie if it is empty,
+    /// or if it has a `+` that is not a whole level or a `#` that is not the whole last level.
+    pub fn new(topic_filter: ByteStr<P>) -> Result<Self, ByteStr<P>> {
+        let s: &str = topic_filter.as_ref();
+        if s.is_empty() {
+            return Err(topic_filter);
+        }
+
+        let mut levels = s.split('/').peekable();
+        while let Some(level) = levels.next() {
+            let valid = match level {
+                "+" => true,
+                "#" => levels.peek().is_none(),
+                level => !level.contains(&['+', '#'][..]),
+            };
+            if !valid {
+                return Err(topic_filter);
+            }
+        }
+
+        Ok(TopicFilter(topic_filter))
+    }
+
+    pub fn as_byte_str(&self) -> &ByteStr<P> {
+        &self.0
+    }
+
+    pub fn into_byte_str(self) -> ByteStr<P> {
+        self.0
+    }
+}
+
+/// Returns whether the given topic name matches the given topic filter.
+///
+/// Topic names starting with `$` are not matched by filters starting with a wildcard.
+///
+/// Ref:
+/// - 3.1.1: 4.7 Topic Names and Topic Filters
+/// - 5.0:   4.7 Topic Names and Topic Filters
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            // "#" matches the parent level too, eg "a/#" matches "a"
+            (Some("#"), _) => return filter_levels.next().is_none(),
+            (Some("+"), Some(_)) => (),
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => (),
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Returns the subscriptions whose topic filter matches the given topic name.
+pub fn matching_subscriptions<'a, P, S>(
+    topic: &'a str,
+    subscriptions: &'a [(TopicFilter<P>, S)],
+) -> impl Iterator<Item = &'a S> + 'a
+where
+    P: BufferPool,
+{
+    subscriptions
+        .iter()
+        .filter(move |(filter, _)| topic_matches(filter.as_byte_str().as_ref(), topic))
+        .map(|(_, subscription)| subscription)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{matching_subscriptions, topic_matches, TopicFilter, TopicName};
+    use crate::tests::byte_str;
+
+    #[test]
+    fn topic_name() {
+        for &valid in &["a", "a/b", "/", "a//b", "$SYS/x"] {
+            assert_eq!(
+                TopicName::new(byte_str(valid)).unwrap().into_byte_str(),
+                valid
+            );
+        }
+
+        for &invalid in &["", "a/+", "#", "a/b#"] {
+            assert_eq!(TopicName::new(byte_str(invalid)), Err(byte_str(invalid)));
+        }
+    }
+
+    #[test]
+    fn topic_filter() {
+        for &valid in &["a", "a/b", "+", "#", "a/+/b", "a/#", "+/+/#", "/"] {
+            assert_eq!(
+                TopicFilter::new(byte_str(valid)).unwrap().into_byte_str(),
+                valid
+            );
+        }
+
+        for &invalid in &["", "a+", "a/b+/c", "#/a", "a/#/b", "a#"] {
+            assert_eq!(TopicFilter::new(byte_str(invalid)), Err(byte_str(invalid)));
+        }
+    }
+
+    #[test]
+    fn matches() {
+        assert!(topic_matches("a/b/c", "a/b/c"));
+        assert!(!topic_matches("a/b/c", "a/b"));
+        assert!(!topic_matches("a/b", "a/b/c"));
+
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(topic_matches("a/+", "a/"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+        assert!(!topic_matches("a/+", "a"));
+
+        assert!(topic_matches("a/#", "a"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(topic_matches("#", "a/b/c"));
+        assert!(!topic_matches("a/#", "b/c"));
+
+        assert!(!topic_matches("#", "$SYS/broker"));
+        assert!(!topic_matches("+/broker", "$SYS/broker"));
+        assert!(topic_matches("$SYS/#", "$SYS/broker"));
+    }
+
+    #[test]
+    fn overlapping_wildcards() {
+        let subscriptions: Vec<_> = vec![
+            ("sensors/+/temperature", 1),
+            ("sensors/#", 2),
+            ("#", 3),
+            ("sensors/room1/humidity", 4),
+            ("sensors/room1/temperature", 5),
+        ]
+        .into_iter()
+        .map(|(filter, subscription)| (TopicFilter::new(byte_str(filter)).unwrap(), subscription))
+        .collect();
+
+        let matched: Vec<_> =
+            matching_subscriptions("sensors/room1/temperature", &subscriptions).collect();
+        assert_eq!(matched, vec![&1, &2, &3, &5]);
+
+        let matched: Vec<_> = matching_subscriptions("$SYS/uptime", &subscriptions).collect();
+        assert!(matched.is_empty());
+    }
+}
@@ -5,8 +5,8 @@ use std::time::Duration;
 
 use super::{decode_connect_start, encode_remaining_length};
 use crate::{
-    BufferPool, ByteBuf, ByteCounter, ByteStr, ClientId, DecodeError, EncodeError,
-    PacketIdentifier, PacketMeta, QoS, Shared,
+    BufferPool, ByteBuf, ByteCounter, ByteStr, ClientId, DecodeContext, DecodeError, DecodeErrorAt,
+    DecodedPacket, EncodeError, PacketIdentifier, PacketMeta, QoS, Shared, TopicName,
 };
 
 pub(crate) const PROTOCOL_LEVEL: u8 = 0x04;
@@ -65,6 +65,37 @@ impl From<ConnectReturnCode> for u8 {
     }
 }
 
+impl std::fmt::Display for ConnectReturnCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectReturnCode::Accepted {
+                session_present: false,
+            } => f.write_str("connection accepted"),
+            ConnectReturnCode::Accepted {
+                session_present: true,
+            } => f.write_str("connection accepted with existing session"),
+            ConnectReturnCode::Refused(reason) => write!(f, "connection refused: {reason}"),
+        }
+    }
+}
+
+impl std::fmt::Display for ConnectionRefusedReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionRefusedReason::UnacceptableProtocolVersion => {
+                f.write_str("unacceptable protocol version")
+            }
+            ConnectionRefusedReason::IdentifierRejected => f.write_str("identifier rejected"),
+            ConnectionRefusedReason::ServerUnavailable => f.write_str("server unavailable"),
+            ConnectionRefusedReason::BadUserNameOrPassword => {
+                f.write_str("bad user name or password")
+            }
+            ConnectionRefusedReason::NotAuthorized => f.write_str("not authorized"),
+            ConnectionRefusedReason::Other(code) => write!(f, "reserved return code 0x{code:02X}"),
+        }
+    }
+}
+
 /// An MQTT packet
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Packet<P>
@@ -114,6 +145,47 @@ where
     Unsubscribe(Unsubscribe<P>),
 }
 
+impl<P> Packet<P>
+where
+    P: BufferPool,
+{
+    /// Returns whether sending or receiving this packet ends the connection,
+    /// ie whether it's a DISCONNECT or a CONNACK that refuses the connection.
+    pub fn closes_connection(&self) -> bool {
+        match self {
+            Packet::ConnAck(ConnAck { return_code }) => {
+                matches!(return_code, ConnectReturnCode::Refused(_))
+            }
+            Packet::Disconnect(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the packet identifier of this packet, if it has one.
+    ///
+    /// A PUBLISH only has a packet identifier if its quality of service is higher than at-most-once.
+    pub fn packet_identifier(&self) -> Option<PacketIdentifier> {
+        match self {
+            Packet::ConnAck(_)
+            | Packet::Connect(_)
+            | Packet::Disconnect(_)
+            | Packet::PingReq(_)
+            | Packet::PingResp(_) => None,
+
+            Packet::Publish(packet) => packet.packet_identifier(),
+
+            Packet::PubAck(packet) => Some(packet.packet_identifier),
+            Packet::PubComp(packet) => Some(packet.packet_identifier),
+            Packet::PubRec(packet) => Some(packet.packet_identifier),
+            Packet::PubRel(packet) => Some(packet.packet_identifier),
+            Packet::SubAck(packet) => Some(packet.packet_identifier),
+            Packet::Subscribe(packet) => Some(packet.packet_identifier),
+            Packet::UnsubAck(packet) => Some(packet.packet_identifier),
+            Packet::Unsubscribe(packet) => Some(packet.packet_identifier),
+        }
+    }
+}
+
 /// Ref: 3.2 CONNACK – Acknowledge connection request
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ConnAck {
@@ -126,7 +198,11 @@ where
 {
     const PACKET_TYPE: u8 = 0x20;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        _ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let connack_flags = src.try_get_u8()?;
         let session_present = match connack_flags {
             0x00 => false,
@@ -177,6 +253,21 @@ where
     pub keep_alive: Duration,
 }
 
+impl<P> Connect<P>
+where
+    P: BufferPool,
+{
+    /// Whether this packet requested a new session, ie whether its Clean Session flag is set.
+    ///
+    /// Ref: 3.1.2.4 Clean Session
+    pub fn clean_start(&self) -> bool {
+        match &self.client_id {
+            ClientId::ServerGenerated | ClientId::IdWithCleanSession(_) => true,
+            ClientId::IdWithExistingSession(_) => false,
+        }
+    }
+}
+
 impl<P> Connect<P>
 where
     P: Clone + BufferPool,
@@ -189,7 +280,7 @@ where
 
         let keep_alive = Duration::from_secs(u64::from(src.try_get_u16_be()?));
 
-        let client_id = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
+        let client_id = ByteStr::decode_in_packet(src)?;
         let client_id = if client_id.is_empty() {
             if connect_flags & 0x02 == 0 {
                 return Err(DecodeError::ConnectZeroLengthIdWithExistingSession);
@@ -204,7 +295,7 @@ where
         let will = if connect_flags & 0x04 == 0 {
             None
         } else {
-            let topic_name = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
+            let topic_name = ByteStr::decode_in_packet(src)?;
 
             let qos = match connect_flags & 0x18 {
                 0x00 => QoS::AtMostOnce,
@@ -232,13 +323,13 @@ where
         let username = if connect_flags & 0x80 == 0 {
             None
         } else {
-            Some(ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?)
+            Some(ByteStr::decode_in_packet(src)?)
         };
 
         let password = if connect_flags & 0x40 == 0 {
             None
         } else {
-            Some(ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?)
+            Some(ByteStr::decode_in_packet(src)?)
         };
 
         Ok(Connect {
@@ -271,7 +362,11 @@ where
 {
     const PACKET_TYPE: u8 = 0x10;
 
-    fn decode(flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        flags: u8,
+        src: &mut Shared<P>,
+        _ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let protocol_level = decode_connect_start(flags, src)?;
         if protocol_level != PROTOCOL_LEVEL {
             return Err(DecodeError::UnrecognizedProtocolVersion(protocol_level));
@@ -376,7 +471,11 @@ where
 {
     const PACKET_TYPE: u8 = 0xE0;
 
-    fn decode(_flags: u8, _src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        _src: &mut Shared<P>,
+        _ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         Ok(Disconnect)
     }
 
@@ -386,6 +485,10 @@ where
     {
         Ok(())
     }
+
+    fn encoded_len(&self) -> Option<usize> {
+        Some(0)
+    }
 }
 
 /// Ref: 3.12 PINGREQ – PING request
@@ -398,7 +501,11 @@ where
 {
     const PACKET_TYPE: u8 = 0xC0;
 
-    fn decode(_flags: u8, _src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        _src: &mut Shared<P>,
+        _ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         Ok(PingReq)
     }
 
@@ -408,6 +515,10 @@ where
     {
         Ok(())
     }
+
+    fn encoded_len(&self) -> Option<usize> {
+        Some(0)
+    }
 }
 
 /// Ref: 3.13 PINGRESP – PING response
@@ -420,7 +531,11 @@ where
 {
     const PACKET_TYPE: u8 = 0xD0;
 
-    fn decode(_flags: u8, _src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        _src: &mut Shared<P>,
+        _ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         Ok(PingResp)
     }
 
@@ -430,6 +545,10 @@ where
     {
         Ok(())
     }
+
+    fn encoded_len(&self) -> Option<usize> {
+        Some(0)
+    }
 }
 
 /// Ref: 3.4 PUBACK – Publish acknowledgement
@@ -444,7 +563,11 @@ where
 {
     const PACKET_TYPE: u8 = 0x40;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        _ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let packet_identifier = src.try_get_packet_identifier()?;
 
         Ok(PubAck { packet_identifier })
@@ -473,7 +596,11 @@ where
 {
     const PACKET_TYPE: u8 = 0x70;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        _ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let packet_identifier = src.try_get_packet_identifier()?;
 
         Ok(PubComp { packet_identifier })
@@ -501,17 +628,91 @@ where
     pub payload: Shared<P>,
 }
 
+impl<P> Publish<P>
+where
+    P: BufferPool,
+{
+    /// Constructs a `Publish` to the given topic name.
+    pub fn new(
+        topic_name: TopicName<P>,
+        packet_identifier_dup_qos: PacketIdentifierDupQoS,
+        retain: bool,
+        payload: Shared<P>,
+    ) -> Self {
+        Publish {
+            packet_identifier_dup_qos,
+            retain,
+            topic_name: topic_name.into_byte_str(),
+            payload,
+        }
+    }
+
+    /// The quality of service this message is published with.
+    pub fn qos(&self) -> QoS {
+        match self.packet_identifier_dup_qos {
+            PacketIdentifierDupQoS::AtMostOnce => QoS::AtMostOnce,
+            PacketIdentifierDupQoS::AtLeastOnce(_, _) => QoS::AtLeastOnce,
+            PacketIdentifierDupQoS::ExactlyOnce(_, _) => QoS::ExactlyOnce,
+        }
+    }
+
+    /// The packet identifier of this packet, or `None` if it is published with [`QoS::AtMostOnce`].
+    pub fn packet_identifier(&self) -> Option<PacketIdentifier> {
+        match self.packet_identifier_dup_qos {
+            PacketIdentifierDupQoS::AtMostOnce => None,
+            PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, _)
+            | PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, _) => Some(packet_identifier),
+        }
+    }
+
+    /// Whether the DUP flag of this packet is set, ie whether it may be a redelivery.
+    pub fn is_dup(&self) -> bool {
+        match self.packet_identifier_dup_qos {
+            PacketIdentifierDupQoS::AtMostOnce => false,
+            PacketIdentifierDupQoS::AtLeastOnce(_, dup)
+            | PacketIdentifierDupQoS::ExactlyOnce(_, dup) => dup,
+        }
+    }
+
+    /// Whether this packet asks the server to delete the retained message of its topic,
+    /// ie whether it has the RETAIN flag set and an empty payload.
+    ///
+    /// Ref: 3.3.1.3 RETAIN
+    pub fn is_retained_delete(&self) -> bool {
+        self.retain && self.payload.is_empty()
+    }
+
+    /// Returns whether this and the other `Publish` are the same message, ie they are equal
+    /// except possibly for the DUP flag. Useful for recognizing redeliveries.
+    pub fn same_message(&self, other: &Self) -> bool {
+        let Publish {
+            packet_identifier_dup_qos,
+            retain,
+            topic_name,
+            payload,
+        } = self;
+
+        packet_identifier_dup_qos.without_dup() == other.packet_identifier_dup_qos.without_dup()
+            && *retain == other.retain
+            && *topic_name == other.topic_name
+            && *payload == other.payload
+    }
+}
+
 impl<P> PacketMeta<P> for Publish<P>
 where
     P: Clone + BufferPool,
 {
     const PACKET_TYPE: u8 = 0x30;
 
-    fn decode(flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(flags: u8, src: &mut Shared<P>, ctx: &DecodeContext<P>) -> Result<Self, DecodeError> {
         let dup = (flags & 0x08) != 0;
         let retain = (flags & 0x01) != 0;
 
-        let topic_name = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
+        let topic_name = ByteStr::decode_in_packet(src)?;
+        if topic_name.is_empty() {
+            return Err(DecodeError::EmptyTopicName);
+        }
 
         let packet_identifier_dup_qos = match (flags & 0x06) >> 1 {
             0x00 if dup => return Err(DecodeError::PublishDupAtMostOnce),
@@ -531,6 +732,7 @@ where
             qos => return Err(DecodeError::UnrecognizedQoS(qos)),
         };
 
+        ctx.check_payload_len(src.len())?;
         let payload = src.split_to(src.len());
 
         Ok(Publish {
@@ -582,7 +784,11 @@ where
 {
     const PACKET_TYPE: u8 = 0x50;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        _ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let packet_identifier = src.try_get_packet_identifier()?;
 
         Ok(PubRec { packet_identifier })
@@ -611,7 +817,11 @@ where
 {
     const PACKET_TYPE: u8 = 0x60;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        _ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let packet_identifier = src.try_get_packet_identifier()?;
 
         Ok(PubRel { packet_identifier })
@@ -640,9 +850,15 @@ where
 {
     const PACKET_TYPE: u8 = 0x90;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let packet_identifier = src.try_get_packet_identifier()?;
 
+        ctx.check_reason_codes(src.len())?;
+
         let qos: Result<Vec<_>, _> = src
             .as_ref()
             .iter()
@@ -702,13 +918,17 @@ where
 {
     const PACKET_TYPE: u8 = 0x80;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let packet_identifier = src.try_get_packet_identifier()?;
 
         let mut subscribe_to = vec![];
 
         while !src.is_empty() {
-            let topic_filter = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
+            let topic_filter = ByteStr::decode_in_packet(src)?;
             let qos = match src.try_get_u8()? {
                 0x00 => QoS::AtMostOnce,
                 0x01 => QoS::AtLeastOnce,
@@ -716,6 +936,7 @@ where
                 qos => return Err(DecodeError::UnrecognizedQoS(qos)),
             };
             subscribe_to.push(SubscribeTo { topic_filter, qos });
+            ctx.check_topics(subscribe_to.len())?;
         }
 
         if subscribe_to.is_empty() {
@@ -760,7 +981,11 @@ where
 {
     const PACKET_TYPE: u8 = 0xB0;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        _ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let packet_identifier = src.try_get_packet_identifier()?;
 
         Ok(UnsubAck { packet_identifier })
@@ -786,19 +1011,47 @@ where
     pub unsubscribe_from: Vec<ByteStr<P>>,
 }
 
+impl<P> Unsubscribe<P>
+where
+    P: BufferPool,
+{
+    /// Constructs an UNSUBSCRIBE from the given topic filters.
+    ///
+    /// Returns an error if there are no topic filters.
+    pub fn new(
+        packet_identifier: PacketIdentifier,
+        filters: impl IntoIterator<Item = ByteStr<P>>,
+    ) -> Result<Self, EncodeError> {
+        let unsubscribe_from: Vec<_> = filters.into_iter().collect();
+        if unsubscribe_from.is_empty() {
+            return Err(EncodeError::NoTopics);
+        }
+
+        Ok(Unsubscribe {
+            packet_identifier,
+            unsubscribe_from,
+        })
+    }
+}
+
 impl<P> PacketMeta<P> for Unsubscribe<P>
 where
     P: Clone + BufferPool,
 {
     const PACKET_TYPE: u8 = 0xA0;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let packet_identifier = src.try_get_packet_identifier()?;
 
         let mut unsubscribe_from = vec![];
 
         while !src.is_empty() {
-            unsubscribe_from.push(ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?);
+            unsubscribe_from.push(ByteStr::decode_in_packet(src)?);
+            ctx.check_topics(unsubscribe_from.len())?;
         }
 
         if unsubscribe_from.is_empty() {
@@ -840,6 +1093,21 @@ pub enum PacketIdentifierDupQoS {
     ExactlyOnce(PacketIdentifier, bool),
 }
 
+impl PacketIdentifierDupQoS {
+    /// Returns this value with the DUP flag cleared.
+    fn without_dup(self) -> Self {
+        match self {
+            PacketIdentifierDupQoS::AtMostOnce => PacketIdentifierDupQoS::AtMostOnce,
+            PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, _) => {
+                PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, false)
+            }
+            PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, _) => {
+                PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, false)
+            }
+        }
+    }
+}
+
 /// A subscription request.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct SubscribeTo<P>
@@ -897,62 +1165,117 @@ where
 /// Decode the body (variable header + payload) of an MQTT packet.
 ///
 /// Ref: 2 MQTT Control Packet format
-pub fn decode<P>(first_byte: u8, mut body: Shared<P>) -> Result<Packet<P>, DecodeError>
+pub fn decode<P>(first_byte: u8, body: Shared<P>) -> Result<Packet<P>, DecodeError>
+where
+    P: Clone + BufferPool,
+{
+    let ctx = DecodeContext::new(body.pool().clone());
+    decode_with_context(&ctx, first_byte, body)
+}
+
+/// Decode the body (variable header + payload) of an MQTT packet like [`decode`],
+/// subject to the limits of the given context.
+pub fn decode_with_context<P>(
+    ctx: &DecodeContext<P>,
+    first_byte: u8,
+    mut body: Shared<P>,
+) -> Result<Packet<P>, DecodeError>
+where
+    P: Clone + BufferPool,
+{
+    decode_inner(ctx, first_byte, &mut body)
+}
+
+/// Decode the body (variable header + payload) of an MQTT packet like [`decode_with_context`],
+/// and on failure also report how many bytes of the body had been consumed when decoding failed.
+///
+/// This is meant for debugging malformed packets. The offset is that of the start of the field that failed to decode,
+/// except that an error inside a property is reported at the end of the property block.
+pub fn decode_debug<P>(
+    ctx: &DecodeContext<P>,
+    first_byte: u8,
+    mut body: Shared<P>,
+) -> Result<Packet<P>, DecodeErrorAt>
 where
     P: Clone + BufferPool,
 {
+    let body_len = body.len();
+    decode_inner(ctx, first_byte, &mut body).map_err(|error| DecodeErrorAt {
+        error,
+        offset: body_len - body.len(),
+    })
+}
+
+fn decode_inner<P>(
+    ctx: &DecodeContext<P>,
+    first_byte: u8,
+    body: &mut Shared<P>,
+) -> Result<Packet<P>, DecodeError>
+where
+    P: Clone + BufferPool,
+{
+    ctx.check_packet_size(first_byte, body.len())?;
+
     let packet_type = first_byte & 0xF0;
     let flags = first_byte & 0x0F;
 
     let packet = match (packet_type, flags) {
         (<ConnAck as PacketMeta<P>>::PACKET_TYPE, 0) => {
-            Packet::ConnAck(ConnAck::decode(flags, &mut body)?)
+            Packet::ConnAck(ConnAck::decode(flags, body, ctx)?)
         }
 
-        (Connect::<P>::PACKET_TYPE, 0) => Packet::Connect(Connect::decode(flags, &mut body)?),
+        (Connect::<P>::PACKET_TYPE, 0) => Packet::Connect(Connect::decode(flags, body, ctx)?),
 
         (<Disconnect as PacketMeta<P>>::PACKET_TYPE, 0) => {
-            Packet::Disconnect(Disconnect::decode(flags, &mut body)?)
+            Packet::Disconnect(Disconnect::decode(flags, body, ctx)?)
         }
 
         (<PingReq as PacketMeta<P>>::PACKET_TYPE, 0) => {
-            Packet::PingReq(PingReq::decode(flags, &mut body)?)
+            Packet::PingReq(PingReq::decode(flags, body, ctx)?)
         }
 
         (<PingResp as PacketMeta<P>>::PACKET_TYPE, 0) => {
-            Packet::PingResp(PingResp::decode(flags, &mut body)?)
+            Packet::PingResp(PingResp::decode(flags, body, ctx)?)
         }
 
         (<PubAck as PacketMeta<P>>::PACKET_TYPE, 0) => {
-            Packet::PubAck(PubAck::decode(flags, &mut body)?)
+            Packet::PubAck(PubAck::decode(flags, body, ctx)?)
         }
 
         (<PubComp as PacketMeta<P>>::PACKET_TYPE, 0) => {
-            Packet::PubComp(PubComp::decode(flags, &mut body)?)
+            Packet::PubComp(PubComp::decode(flags, body, ctx)?)
         }
 
-        (Publish::<P>::PACKET_TYPE, flags) => Packet::Publish(Publish::decode(flags, &mut body)?),
+        (Publish::<P>::PACKET_TYPE, flags) => Packet::Publish(Publish::decode(flags, body, ctx)?),
 
         (<PubRec as PacketMeta<P>>::PACKET_TYPE, 0) => {
-            Packet::PubRec(PubRec::decode(flags, &mut body)?)
+            Packet::PubRec(PubRec::decode(flags, body, ctx)?)
         }
 
         (<PubRel as PacketMeta<P>>::PACKET_TYPE, 2) => {
-            Packet::PubRel(PubRel::decode(flags, &mut body)?)
+            Packet::PubRel(PubRel::decode(flags, body, ctx)?)
         }
 
         (<SubAck as PacketMeta<P>>::PACKET_TYPE, 0) => {
-            Packet::SubAck(SubAck::decode(flags, &mut body)?)
+            Packet::SubAck(SubAck::decode(flags, body, ctx)?)
         }
 
-        (Subscribe::<P>::PACKET_TYPE, 2) => Packet::Subscribe(Subscribe::decode(flags, &mut body)?),
+        (Subscribe::<P>::PACKET_TYPE, 2) => Packet::Subscribe(Subscribe::decode(flags, body, ctx)?),
 
         (<UnsubAck as PacketMeta<P>>::PACKET_TYPE, 0) => {
-            Packet::UnsubAck(UnsubAck::decode(flags, &mut body)?)
+            Packet::UnsubAck(UnsubAck::decode(flags, body, ctx)?)
         }
 
         (Unsubscribe::<P>::PACKET_TYPE, 2) => {
-            Packet::Unsubscribe(Unsubscribe::decode(flags, &mut body)?)
+            Packet::Unsubscribe(Unsubscribe::decode(flags, body, ctx)?)
+        }
+
+        // AUTH only exists in v5
+        (0xF0, _) => {
+            return Err(DecodeError::PacketTypeNotValidForVersion {
+                packet_type,
+                version: PROTOCOL_LEVEL,
+            });
         }
 
         (packet_type, flags) => {
@@ -965,12 +1288,41 @@ where
     };
 
     if !body.is_empty() {
-        return Err(DecodeError::TrailingGarbage);
+        return Err(match packet {
+            Packet::ConnAck(_)
+            | Packet::Disconnect(_)
+            | Packet::PingReq(_)
+            | Packet::PingResp(_)
+            | Packet::PubAck(_)
+            | Packet::PubComp(_)
+            | Packet::PubRec(_)
+            | Packet::PubRel(_)
+            | Packet::UnsubAck(_) => DecodeError::FixedSizePacketTooLong { packet_type },
+            _ => DecodeError::TrailingGarbage,
+        });
     }
 
     Ok(packet)
 }
 
+/// Decode the body (variable header + payload) of an MQTT packet like [`decode`],
+/// and also return the original body so that it can be forwarded verbatim.
+pub fn decode_with_raw_body<P>(
+    first_byte: u8,
+    body: Shared<P>,
+) -> Result<DecodedPacket<Packet<P>, P>, DecodeError>
+where
+    P: Clone + BufferPool,
+{
+    let raw_body = body.clone();
+    let packet = decode(first_byte, body)?;
+    Ok(DecodedPacket {
+        packet,
+        first_byte,
+        raw_body,
+    })
+}
+
 pub fn encode<B, P>(item: Packet<P>, dst: &mut B) -> Result<(), EncodeError>
 where
     B: ByteBuf,
@@ -986,11 +1338,22 @@ where
         P: Clone + BufferPool,
         TPacket: PacketMeta<P>,
     {
-        let mut counter: ByteCounter = Default::default();
-        packet.clone().encode(&mut counter)?;
-        let body_len = counter.0;
+        let body_len = if let Some(body_len) = packet.encoded_len() {
+            body_len
+        } else {
+            let mut counter: ByteCounter = Default::default();
+            packet.clone().encode(&mut counter)?;
+            counter.0
+        };
 
         dst.try_put_u8(TPacket::PACKET_TYPE | flags)?;
+
+        // Packets with an empty body like PINGREQ are just the packet type and a zero remaining length
+        if body_len == 0 {
+            dst.try_put_u8(0x00)?;
+            return Ok(());
+        }
+
         encode_remaining_length(body_len, dst)?;
         packet.encode(dst)?;
 
@@ -1026,3 +1389,268 @@ where
         Packet::Unsubscribe(packet) => encode_inner(packet, 0x02, dst),
     }
 }
+
+/// Encode an MQTT packet like [`encode`], into a new `Vec`.
+///
+/// This is convenient for tests and simple clients. Encoding into a reused buffer avoids allocating for every packet.
+pub fn encode_to_vec<P>(item: Packet<P>) -> Result<Vec<u8>, EncodeError>
+where
+    P: Clone + BufferPool,
+{
+    let mut dst = vec![];
+    encode(item, &mut dst)?;
+    Ok(dst)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        ConnAck, Connect, ConnectReturnCode, ConnectionRefusedReason, Disconnect, Packet,
+        PacketIdentifierDupQoS, PingReq, PingResp, PubAck, Publish, Unsubscribe,
+    };
+    use crate::tests::{byte_str, shared, TestBufferPool};
+    use crate::{ClientId, DecodeContext, DecodeError, EncodeError, Owned, PacketIdentifier};
+
+    shared_publish_tests!();
+
+    #[test]
+    fn connect_clean_start() {
+        for (client_id, clean_start) in [
+            (ClientId::ServerGenerated, true),
+            (ClientId::IdWithCleanSession(byte_str("id")), true),
+            (ClientId::IdWithExistingSession(byte_str("id")), false),
+        ] {
+            let packet = Connect {
+                username: None,
+                password: None,
+                will: None,
+                client_id,
+                keep_alive: Duration::from_secs(30),
+            };
+            assert_eq!(packet.clean_start(), clean_start);
+        }
+    }
+
+    #[test]
+    fn decode_auth() {
+        match super::decode(0xF0, shared(b"\x00\x00")) {
+            Err(DecodeError::PacketTypeNotValidForVersion {
+                packet_type: 0xF0,
+                version: 0x04,
+            }) => (),
+            result => panic!("{:?}", result),
+        }
+
+        match super::decode(0x00, shared(b"")) {
+            Err(DecodeError::UnrecognizedPacket {
+                packet_type: 0x00, ..
+            }) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn publish_new() {
+        let topic_name = crate::TopicName::new(byte_str("a/b")).unwrap();
+        let packet = Publish::new(
+            topic_name,
+            PacketIdentifierDupQoS::AtMostOnce,
+            false,
+            shared(b"hello"),
+        );
+        assert_eq!(
+            packet,
+            Publish {
+                packet_identifier_dup_qos: PacketIdentifierDupQoS::AtMostOnce,
+                retain: false,
+                topic_name: byte_str("a/b"),
+                payload: shared(b"hello"),
+            }
+        );
+    }
+
+    #[test]
+    fn decode_empty_topic_name() {
+        match super::decode(0x30, shared(b"\x00\x00hello")) {
+            Err(DecodeError::EmptyTopicName) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn fixed_size_packet_too_long() {
+        match super::decode::<TestBufferPool>(0xC0, shared(b"\x00")) {
+            Err(DecodeError::FixedSizePacketTooLong { packet_type: 0xC0 }) => (),
+            result => panic!("{:?}", result),
+        }
+
+        match super::decode::<TestBufferPool>(0x40, shared(b"\x00\x01\x00")) {
+            Err(DecodeError::FixedSizePacketTooLong { packet_type: 0x40 }) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn decode_debug_truncated_topic_filter() {
+        let body = b"\x00\x01\x00\x01a\x01\x00\x03b";
+
+        let ctx = DecodeContext::new(TestBufferPool);
+        match super::decode_debug(&ctx, 0x82, shared(body)) {
+            Err(crate::DecodeErrorAt {
+                error:
+                    DecodeError::StringLengthExceedsPacket {
+                        string_length: 3,
+                        remaining_length: 1,
+                    },
+                offset: 6,
+            }) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn maximum_payload_len() {
+        let body = b"\x00\x03a/bhello";
+
+        let mut ctx = DecodeContext::new(TestBufferPool);
+        ctx.maximum_payload_len = Some(5);
+        match super::decode_with_context(&ctx, 0x30, shared(body)) {
+            Ok(Packet::Publish(packet)) => assert_eq!(packet.payload, shared(b"hello")),
+            result => panic!("{:?}", result),
+        }
+
+        ctx.maximum_payload_len = Some(4);
+        match super::decode_with_context(&ctx, 0x30, shared(body)) {
+            Err(DecodeError::PayloadTooLarge(5)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn too_many_topics() {
+        let body = b"\x00\x01\x00\x01a\x01\x00\x01b\x01\x00\x01c\x01";
+
+        let mut ctx = DecodeContext::new(TestBufferPool);
+        ctx.maximum_topics = Some(2);
+        match super::decode_with_context(&ctx, 0x82, shared(body)) {
+            Err(DecodeError::TooManyTopics(2)) => (),
+            result => panic!("{:?}", result),
+        }
+
+        ctx.maximum_reason_codes = Some(2);
+        match super::decode_with_context(&ctx, 0x90, shared(b"\x00\x01\x00\x01\x02")) {
+            Err(DecodeError::TooManyReasonCodes(2)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn encode_empty_body() {
+        let pool = TestBufferPool;
+
+        for (packet, expected) in [
+            (Packet::PingReq(PingReq), b"\xC0\x00"),
+            (Packet::PingResp(PingResp), b"\xD0\x00"),
+            (Packet::Disconnect(Disconnect), b"\xE0\x00"),
+        ] {
+            let mut bytes = Owned::new(pool, pool.take(2));
+            super::encode::<_, TestBufferPool>(packet, &mut bytes).unwrap();
+            assert_eq!(bytes.filled(), expected);
+        }
+    }
+
+    #[test]
+    fn packet_identifier() {
+        for (first_byte, body, expected) in [
+            (0x40, &b"\x00\x01"[..], Some(1)),
+            (0x50, b"\x00\x02", Some(2)),
+            (0x62, b"\x00\x03", Some(3)),
+            (0x70, b"\x00\x04", Some(4)),
+            (0x90, b"\x00\x05\x00", Some(5)),
+            (0xB0, b"\x00\x06", Some(6)),
+            (0x82, b"\x00\x07\x00\x01a\x00", Some(7)),
+            (0xA2, b"\x00\x08\x00\x01a", Some(8)),
+            (0x32, b"\x00\x01a\x00\x09", Some(9)),
+            (0x30, b"\x00\x01a", None),
+            (0xC0, b"", None),
+            (0xE0, b"", None),
+        ] {
+            let packet = super::decode(first_byte, shared(body)).unwrap();
+            assert_eq!(
+                packet.packet_identifier(),
+                expected.map(|id| PacketIdentifier::new(id).unwrap()),
+                "{packet:?}",
+            );
+        }
+    }
+
+    #[test]
+    fn connect_return_code_display() {
+        assert_eq!(
+            ConnectionRefusedReason::BadUserNameOrPassword.to_string(),
+            "bad user name or password"
+        );
+        assert_eq!(
+            ConnectReturnCode::Refused(ConnectionRefusedReason::Other(0x2A)).to_string(),
+            "connection refused: reserved return code 0x2A"
+        );
+        assert_eq!(
+            ConnectReturnCode::Accepted {
+                session_present: true
+            }
+            .to_string(),
+            "connection accepted with existing session"
+        );
+    }
+
+    #[test]
+    fn closes_connection() {
+        let cases: Vec<(Packet<TestBufferPool>, bool)> = vec![
+            (Packet::Disconnect(Disconnect), true),
+            (
+                Packet::ConnAck(ConnAck {
+                    return_code: ConnectReturnCode::Refused(ConnectionRefusedReason::NotAuthorized),
+                }),
+                true,
+            ),
+            (
+                Packet::ConnAck(ConnAck {
+                    return_code: ConnectReturnCode::Accepted {
+                        session_present: false,
+                    },
+                }),
+                false,
+            ),
+            (Packet::PingReq(PingReq), false),
+            (
+                Packet::PubAck(PubAck {
+                    packet_identifier: PacketIdentifier::new(1).unwrap(),
+                }),
+                false,
+            ),
+        ];
+
+        for (packet, expected) in cases {
+            assert_eq!(packet.closes_connection(), expected, "{packet:?}");
+        }
+    }
+
+    #[test]
+    fn unsubscribe_new() {
+        let packet_identifier = PacketIdentifier::new(5).unwrap();
+
+        let packet =
+            Unsubscribe::new(packet_identifier, vec![byte_str("a/b"), byte_str("c/#")]).unwrap();
+        let pool = TestBufferPool;
+        let mut bytes = Owned::new(pool, pool.take(32));
+        crate::v3::encode(Packet::Unsubscribe(packet), &mut bytes).unwrap();
+        assert_eq!(bytes.filled(), b"\xA2\x0C\x00\x05\x00\x03a/b\x00\x03c/#");
+
+        match Unsubscribe::<TestBufferPool>::new(packet_identifier, vec![]) {
+            Err(EncodeError::NoTopics) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+}
@@ -1,9 +1,10 @@
 // Copyright (c) Microsoft. All rights reserved.
 
 use std::convert::TryInto;
+use std::mem::size_of;
 
-use super::{PacketMeta, Property};
-use crate::{BufferPool, ByteBuf, ByteStr, DecodeError, EncodeError, Shared};
+use super::{DisconnectReasonCode, PacketMeta, Property};
+use crate::{BufferPool, ByteBuf, ByteStr, DecodeContext, DecodeError, EncodeError, Shared};
 
 /// Ref: 3.2 CONNACK – Acknowledge connection request
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -13,6 +14,7 @@ where
 {
     pub reason_code: AuthenticateReasonCode,
     pub authentication_method: Option<ByteStr<P>>,
+    /// Note: Value includes the two-byte length prefix. Use [`Auth::authentication_data_bytes`] to get just the data.
     pub authentication_data: Option<Shared<P>>,
     pub reason_string: Option<ByteStr<P>>,
     pub user_properties: Vec<(ByteStr<P>, ByteStr<P>)>,
@@ -27,19 +29,97 @@ define_u8_code! {
     ReAuthenticate = 0x19,
 }
 
+/// Checks that the given `Auth` is valid for a connection whose CONNECT specified the given authentication method.
+///
+/// Returns the reason code that the connection should be disconnected with if it isn't.
+///
+/// Ref: 4.12 Enhanced authentication
+pub fn validate_auth<P>(
+    auth: &Auth<P>,
+    connect_auth_method: Option<&ByteStr<P>>,
+) -> Result<(), DisconnectReasonCode>
+where
+    P: BufferPool,
+{
+    let connect_auth_method = connect_auth_method.ok_or(DisconnectReasonCode::ProtocolError)?;
+
+    let auth_method = auth
+        .authentication_method
+        .as_ref()
+        .ok_or(DisconnectReasonCode::ProtocolError)?;
+
+    if auth_method != connect_auth_method {
+        return Err(DisconnectReasonCode::BadAuthenticationMethod);
+    }
+
+    Ok(())
+}
+
+impl<P> Auth<P>
+where
+    P: BufferPool,
+{
+    /// Constructs an AUTH that continues an enhanced authentication exchange with the given method and data.
+    ///
+    /// Like [`Auth::authentication_data`], `data` includes its two-byte length prefix.
+    ///
+    /// Ref: 4.12 Enhanced authentication
+    pub fn continue_auth(method: ByteStr<P>, data: Option<Shared<P>>) -> Self {
+        Self::with_reason_code(AuthenticateReasonCode::ContinueAuthentication, method, data)
+    }
+
+    /// Constructs an AUTH that starts re-authenticating an established connection with the given method and data.
+    ///
+    /// Like [`Auth::authentication_data`], `data` includes its two-byte length prefix.
+    ///
+    /// Ref: 4.12.1 Re-authentication
+    pub fn reauthenticate(method: ByteStr<P>, data: Option<Shared<P>>) -> Self {
+        Self::with_reason_code(AuthenticateReasonCode::ReAuthenticate, method, data)
+    }
+
+    fn with_reason_code(
+        reason_code: AuthenticateReasonCode,
+        method: ByteStr<P>,
+        data: Option<Shared<P>>,
+    ) -> Self {
+        Auth {
+            reason_code,
+            authentication_method: Some(method),
+            authentication_data: data,
+            reason_string: None,
+            user_properties: vec![],
+        }
+    }
+
+    /// The authentication data of this packet, without its two-byte length prefix.
+    ///
+    /// Returns `None` if the value is too short to even have the length prefix, eg if it was constructed without one.
+    pub fn authentication_data_bytes(&self) -> Option<&[u8]> {
+        self.authentication_data
+            .as_ref()
+            .and_then(|authentication_data| authentication_data.as_ref().get(size_of::<u16>()..))
+    }
+}
+
 impl<P> PacketMeta<P> for Auth<P>
 where
     P: Clone + BufferPool,
 {
     const PACKET_TYPE: u8 = 0xF0;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         match src.try_get_u8() {
             Ok(reason_code) => {
                 let reason_code = reason_code.try_into()?;
 
                 decode_properties!(
+                    @optional_length
                     src,
+                    ctx,
                     authentication_method: AuthenticationMethod,
                     authentication_data: AuthenticationData,
                     reason_string: ReasonString,
@@ -101,3 +181,77 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_auth, Auth, AuthenticateReasonCode};
+    use crate::tests::{byte_str, shared, TestBufferPool};
+    use crate::v5::{DisconnectReasonCode, Packet};
+    use crate::Owned;
+
+    fn auth(authentication_method: Option<&str>) -> Auth<TestBufferPool> {
+        Auth {
+            reason_code: AuthenticateReasonCode::ContinueAuthentication,
+            authentication_method: authentication_method.map(byte_str),
+            authentication_data: None,
+            reason_string: None,
+            user_properties: vec![],
+        }
+    }
+
+    #[test]
+    fn validate_auth_method() {
+        let method = byte_str("SCRAM-SHA-1");
+
+        assert_eq!(
+            validate_auth(&auth(Some("SCRAM-SHA-1")), Some(&method)),
+            Ok(())
+        );
+
+        assert_eq!(
+            validate_auth(&auth(Some("GS2-KRB5")), Some(&method)),
+            Err(DisconnectReasonCode::BadAuthenticationMethod),
+        );
+
+        assert_eq!(
+            validate_auth(&auth(None), Some(&method)),
+            Err(DisconnectReasonCode::ProtocolError),
+        );
+
+        assert_eq!(
+            validate_auth(&auth(Some("SCRAM-SHA-1")), None),
+            Err(DisconnectReasonCode::ProtocolError),
+        );
+    }
+
+    #[test]
+    fn continue_auth_and_reauthenticate() {
+        let pool = TestBufferPool;
+
+        let mut bytes = Owned::new(pool, pool.take(32));
+        let packet = Auth::continue_auth(byte_str("SCRAM"), None);
+        crate::v5::encode(Packet::Auth(packet), &mut bytes).unwrap();
+        assert_eq!(bytes.filled(), b"\xF0\x0A\x18\x08\x15\x00\x05SCRAM");
+
+        let mut bytes = Owned::new(pool, pool.take(32));
+        let packet = Auth::reauthenticate(byte_str("SCRAM"), Some(shared(b"\x00\x02ab")));
+        assert_eq!(packet.authentication_data_bytes(), Some(&b"ab"[..]));
+        crate::v5::encode(Packet::Auth(packet), &mut bytes).unwrap();
+        assert_eq!(
+            bytes.filled(),
+            b"\xF0\x0F\x19\x0D\x15\x00\x05SCRAM\x16\x00\x02ab"
+        );
+    }
+
+    #[test]
+    fn authentication_data_bytes() {
+        let mut packet = auth(Some("SCRAM-SHA-1"));
+        assert_eq!(packet.authentication_data_bytes(), None);
+
+        packet.authentication_data = Some(shared(b"\x00\x03abc"));
+        assert_eq!(packet.authentication_data_bytes(), Some(&b"abc"[..]));
+
+        packet.authentication_data = Some(shared(b"\x00"));
+        assert_eq!(packet.authentication_data_bytes(), None);
+    }
+}
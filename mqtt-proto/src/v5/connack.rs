@@ -3,8 +3,10 @@
 use std::convert::TryInto;
 use std::time::Duration;
 
-use super::{PacketMeta, Property};
-use crate::{BufferPool, ByteBuf, ByteStr, DecodeError, EncodeError, QoS, Shared};
+use super::{Connect, PacketMeta, Property, RedirectReason, ServerRedirect};
+use crate::{
+    BufferPool, ByteBuf, ByteStr, ClientId, DecodeContext, DecodeError, EncodeError, QoS, Shared,
+};
 
 /// Ref: 3.2 CONNACK – Acknowledge connection request
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -25,6 +27,8 @@ where
     pub wildcard_subscription_available: bool,
     pub shared_subscription_available: bool,
     pub subscription_identifier_available: bool,
+    /// Values longer than 65535 seconds fail to encode with [`EncodeError::InvalidServerKeepAlive`],
+    /// so a keep-alive computed from a policy should be passed through [`clamp_server_keep_alive`] first.
     pub server_keep_alive: Option<Duration>,
     pub response_information: Option<ByteStr<P>>,
     pub server_reference: Option<ByteStr<P>>,
@@ -32,6 +36,32 @@ where
     pub authentication_data: Option<Shared<P>>,
 }
 
+/// The capabilities a server advertises to its clients in a [`ConnAck`].
+///
+/// The default value corresponds to the values a client assumes when the corresponding properties are absent.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ServerCapabilities {
+    pub maximum_qos: QoS,
+    pub retain_available: bool,
+    pub maximum_packet_size: Option<usize>,
+    pub wildcard_subscription_available: bool,
+    pub shared_subscription_available: bool,
+    pub subscription_identifier_available: bool,
+}
+
+impl Default for ServerCapabilities {
+    fn default() -> Self {
+        ServerCapabilities {
+            maximum_qos: QoS::ExactlyOnce,
+            retain_available: true,
+            maximum_packet_size: None,
+            wildcard_subscription_available: true,
+            shared_subscription_available: true,
+            subscription_identifier_available: true,
+        }
+    }
+}
+
 /// Ref: 3.2.2.2 Connect Reason Code
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ConnectReasonCode {
@@ -66,13 +96,152 @@ define_u8_code! {
     ConnectionRateExceeded = 0x9F,
 }
 
+/// Clamps the given server keep-alive to the longest one that can be encoded, 65535 seconds.
+///
+/// Ref: 3.2.2.3.14 Server Keep Alive
+pub fn clamp_server_keep_alive(keep_alive: Duration) -> Duration {
+    std::cmp::min(keep_alive, Duration::from_secs(u16::MAX.into()))
+}
+
+impl<P> ConnAck<P>
+where
+    P: BufferPool,
+{
+    /// Constructs a `ConnAck` that advertises the given server capabilities.
+    ///
+    /// All other properties are left at their default values.
+    pub fn from_capabilities(
+        return_code: ConnectReasonCode,
+        capabilities: &ServerCapabilities,
+    ) -> Self {
+        let ServerCapabilities {
+            maximum_qos,
+            retain_available,
+            maximum_packet_size,
+            wildcard_subscription_available,
+            shared_subscription_available,
+            subscription_identifier_available,
+        } = *capabilities;
+
+        ConnAck {
+            return_code,
+            session_expiry_interval: None,
+            receive_maximum: usize::from(u16::MAX),
+            maximum_qos,
+            retain_available,
+            maximum_packet_size,
+            assigned_client_id: None,
+            topic_alias_maximum: 0,
+            reason_string: None,
+            user_properties: vec![],
+            wildcard_subscription_available,
+            shared_subscription_available,
+            subscription_identifier_available,
+            server_keep_alive: None,
+            response_information: None,
+            server_reference: None,
+            authentication_method: None,
+            authentication_data: None,
+        }
+    }
+
+    /// Constructs a `ConnAck` that refuses the connection with the given reason and optional reason string.
+    ///
+    /// All other properties are left at their default values, since a client only acts on them for an accepted connection.
+    pub fn refuse(reason: ConnectionRefusedReason, reason_string: Option<ByteStr<P>>) -> Self {
+        ConnAck {
+            reason_string,
+            ..ConnAck::from_capabilities(
+                ConnectReasonCode::Refused(reason),
+                &ServerCapabilities::default(),
+            )
+        }
+    }
+
+    /// Sets the client identifier that the server assigned to a client that connected with an empty one.
+    ///
+    /// Ref: 3.2.2.3.7 Assigned Client Identifier
+    #[must_use]
+    pub fn assign_client_id(mut self, id: ByteStr<P>) -> Self {
+        self.assigned_client_id = Some(id);
+        self
+    }
+
+    /// Checks that this `ConnAck` only contains the assigned client identifier property
+    /// if the given `Connect` asked the server to generate a client identifier.
+    ///
+    /// Ref: 3.2.2.3.7 Assigned Client Identifier
+    pub fn validate_assigned_client_id(&self, connect: &Connect<P>) -> Result<(), EncodeError> {
+        let server_generated = matches!(connect.client_id, ClientId::ServerGenerated);
+        if self.assigned_client_id.is_some() && !server_generated {
+            return Err(EncodeError::AssignedClientIdNotServerGenerated);
+        }
+
+        Ok(())
+    }
+
+    /// Checks that this `ConnAck` only contains the response information property
+    /// if the given `Connect` requested it.
+    ///
+    /// Ref: 3.1.2.11.6 Request Response Information
+    pub fn validate_response_information(&self, connect: &Connect<P>) -> Result<(), EncodeError> {
+        if self.response_information.is_some() && !connect.request_response_information {
+            return Err(EncodeError::ResponseInformationNotRequested);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the server capabilities advertised by this `ConnAck`.
+    pub fn capabilities(&self) -> ServerCapabilities {
+        ServerCapabilities {
+            maximum_qos: self.maximum_qos,
+            retain_available: self.retain_available,
+            maximum_packet_size: self.maximum_packet_size,
+            wildcard_subscription_available: self.wildcard_subscription_available,
+            shared_subscription_available: self.shared_subscription_available,
+            subscription_identifier_available: self.subscription_identifier_available,
+        }
+    }
+}
+
+impl<P> ConnAck<P>
+where
+    P: Clone + BufferPool,
+{
+    /// Returns the server that the client should connect to instead,
+    /// if this packet refuses the connection with a redirect reason code and has a server reference.
+    ///
+    /// Ref: 4.11 Server redirection
+    pub fn server_redirect(&self) -> Option<ServerRedirect<P>> {
+        let reason = match self.return_code {
+            ConnectReasonCode::Refused(ConnectionRefusedReason::UseAnotherServer) => {
+                RedirectReason::UseAnotherServer
+            }
+            ConnectReasonCode::Refused(ConnectionRefusedReason::ServerMoved) => {
+                RedirectReason::ServerMoved
+            }
+            _ => return None,
+        };
+
+        Some(ServerRedirect {
+            reason,
+            reference: self.server_reference.clone()?,
+        })
+    }
+}
+
 impl<P> PacketMeta<P> for ConnAck<P>
 where
     P: Clone + BufferPool,
 {
     const PACKET_TYPE: u8 = 0x20;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let connack_flags = src.try_get_u8()?;
         let session_present = match connack_flags {
             0x00 => false,
@@ -86,6 +255,7 @@ where
 
         decode_properties!(
             src,
+            ctx,
             session_expiry_interval: SessionExpiryInterval,
             receive_maximum: ReceiveMaximum,
             maximum_qos: MaximumQoS,
@@ -165,10 +335,13 @@ where
 
         dst.try_put_u8(return_code.into())?;
 
+        // The property is only encoded when it isn't the default of 65535
+        let receive_maximum = Some(receive_maximum).filter(|&value| value != usize::from(u16::MAX));
+
         encode_properties!(
             dst,
             session_expiry_interval: Option<SessionExpiryInterval>,
-            receive_maximum: ReceiveMaximum,
+            receive_maximum: Option<ReceiveMaximum>,
             maximum_qos: MaximumQoS,
             retain_available: RetainAvailable,
             maximum_packet_size: Option<MaximumPacketSize>,
@@ -207,3 +380,164 @@ impl From<ConnectReasonCode> for u8 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        clamp_server_keep_alive, ConnAck, ConnectReasonCode, ConnectionRefusedReason,
+        ServerCapabilities,
+    };
+    use crate::tests::{byte_str, TestBufferPool};
+    use crate::v5::{RedirectReason, ServerRedirect};
+    use crate::{ClientId, EncodeError, Owned, QoS};
+
+    #[test]
+    fn clamp_server_keep_alive_within_range() {
+        assert_eq!(
+            clamp_server_keep_alive(Duration::from_secs(100_000)),
+            Duration::from_secs(65535),
+        );
+        assert_eq!(
+            clamp_server_keep_alive(Duration::from_secs(30)),
+            Duration::from_secs(30),
+        );
+
+        let pool = TestBufferPool;
+        let mut packet: ConnAck<TestBufferPool> = ConnAck::from_capabilities(
+            ConnectReasonCode::Success {
+                session_present: false,
+            },
+            &ServerCapabilities::default(),
+        );
+        packet.server_keep_alive = Some(clamp_server_keep_alive(Duration::from_secs(100_000)));
+        let mut bytes = Owned::new(pool, pool.take(32));
+        crate::v5::encode(crate::v5::Packet::ConnAck(packet), &mut bytes).unwrap();
+    }
+
+    #[test]
+    fn refuse() {
+        let pool = TestBufferPool;
+
+        let packet: ConnAck<TestBufferPool> =
+            ConnAck::refuse(ConnectionRefusedReason::NotAuthorized, None);
+        let mut bytes = Owned::new(pool, pool.take(32));
+        crate::v5::encode(crate::v5::Packet::ConnAck(packet), &mut bytes).unwrap();
+        assert_eq!(bytes.filled(), b"\x20\x03\x00\x87\x00");
+
+        let packet = ConnAck::refuse(ConnectionRefusedReason::ServerBusy, Some(byte_str("busy")));
+        let mut bytes = Owned::new(pool, pool.take(32));
+        crate::v5::encode(crate::v5::Packet::ConnAck(packet), &mut bytes).unwrap();
+        assert_eq!(bytes.filled(), b"\x20\x0A\x00\x89\x07\x1F\x00\x04busy");
+    }
+
+    #[test]
+    fn from_capabilities() {
+        let capabilities = ServerCapabilities {
+            maximum_qos: QoS::AtLeastOnce,
+            retain_available: false,
+            maximum_packet_size: Some(1024),
+            wildcard_subscription_available: true,
+            shared_subscription_available: false,
+            subscription_identifier_available: false,
+        };
+
+        let packet: ConnAck<TestBufferPool> = ConnAck::from_capabilities(
+            ConnectReasonCode::Success {
+                session_present: true,
+            },
+            &capabilities,
+        );
+        assert_eq!(
+            packet.return_code,
+            ConnectReasonCode::Success {
+                session_present: true
+            }
+        );
+        assert_eq!(packet.capabilities(), capabilities);
+
+        let pool = TestBufferPool;
+        let mut bytes = Owned::new(pool, pool.take(64));
+        crate::v5::encode(crate::v5::Packet::ConnAck(packet.clone()), &mut bytes).unwrap();
+        let body = crate::tests::shared(&bytes.filled()[2..]);
+        let decoded = crate::v5::decode(bytes.filled()[0], body).unwrap();
+        assert_eq!(decoded, crate::v5::Packet::ConnAck(packet));
+    }
+
+    #[test]
+    fn server_redirect() {
+        let mut packet: ConnAck<TestBufferPool> = ConnAck::from_capabilities(
+            ConnectReasonCode::Refused(ConnectionRefusedReason::ServerMoved),
+            &ServerCapabilities::default(),
+        );
+        assert_eq!(packet.server_redirect(), None);
+
+        packet.server_reference = Some(byte_str("other.example.com:1883"));
+        assert_eq!(
+            packet.server_redirect(),
+            Some(ServerRedirect {
+                reason: RedirectReason::ServerMoved,
+                reference: byte_str("other.example.com:1883"),
+            })
+        );
+
+        packet.return_code = ConnectReasonCode::Refused(ConnectionRefusedReason::ServerBusy);
+        assert_eq!(packet.server_redirect(), None);
+    }
+
+    #[test]
+    fn validate_response_information() {
+        let mut connack: ConnAck<TestBufferPool> = ConnAck::from_capabilities(
+            ConnectReasonCode::Success {
+                session_present: false,
+            },
+            &ServerCapabilities::default(),
+        );
+        connack.response_information = Some(byte_str("response/prefix"));
+
+        let mut connect = crate::v5::connect::tests::connect(ClientId::ServerGenerated);
+
+        connect.request_response_information = true;
+        connack.validate_response_information(&connect).unwrap();
+
+        connect.request_response_information = false;
+        match connack.validate_response_information(&connect) {
+            Err(EncodeError::ResponseInformationNotRequested) => (),
+            result => panic!("{:?}", result),
+        }
+
+        connack.response_information = None;
+        connack.validate_response_information(&connect).unwrap();
+    }
+
+    #[test]
+    fn assign_client_id() {
+        let connack: ConnAck<TestBufferPool> = ConnAck::from_capabilities(
+            ConnectReasonCode::Success {
+                session_present: false,
+            },
+            &ServerCapabilities::default(),
+        )
+        .assign_client_id(byte_str("generated-1"));
+        assert_eq!(connack.assigned_client_id, Some(byte_str("generated-1")));
+
+        let connect = crate::v5::connect::tests::connect(ClientId::ServerGenerated);
+        connack.validate_assigned_client_id(&connect).unwrap();
+
+        let connect =
+            crate::v5::connect::tests::connect(ClientId::IdWithCleanSession(byte_str("client1")));
+        match connack.validate_assigned_client_id(&connect) {
+            Err(EncodeError::AssignedClientIdNotServerGenerated) => (),
+            result => panic!("{:?}", result),
+        }
+
+        let pool = TestBufferPool;
+        let mut bytes = Owned::new(pool, pool.take(32));
+        crate::v5::encode(crate::v5::Packet::ConnAck(connack), &mut bytes).unwrap();
+        assert_eq!(
+            bytes.filled(),
+            b"\x20\x11\x00\x00\x0E\x12\x00\x0Bgenerated-1"
+        );
+    }
+}
@@ -1,11 +1,15 @@
 // Copyright (c) Microsoft. All rights reserved.
 
 use std::convert::TryInto;
+use std::num::NonZeroU16;
 use std::time::Duration;
 
 use super::{decode_connect_start, PacketMeta, Property, PROTOCOL_VERSION};
-use crate::v5::Publication;
-use crate::{BufferPool, ByteBuf, ByteStr, ClientId, DecodeError, EncodeError, QoS, Shared};
+use crate::v5::{DisconnectReasonCode, Publication};
+use crate::{
+    BufferPool, ByteBuf, ByteStr, ClientId, DecodeContext, DecodeError, EncodeError, Owned, QoS,
+    Shared,
+};
 
 /// Ref: 3.1 CONNECT – Client requests a connection to a Server
 #[derive(Clone, Eq, PartialEq)]
@@ -19,7 +23,10 @@ where
     pub client_id: ClientId<P>,
     pub keep_alive: Duration,
     pub session_expiry_interval: Option<Duration>,
-    pub receive_maximum: usize,
+    /// `None` if the packet has no receive maximum property, in which case the receive maximum is 65535.
+    ///
+    /// Ref: 3.1.2.11.3 Receive Maximum
+    pub receive_maximum: Option<NonZeroU16>,
     pub maximum_packet_size: Option<usize>,
     pub topic_alias_maximum: u16,
     pub request_response_information: bool,
@@ -29,23 +36,263 @@ where
     pub authentication_data: Option<Shared<P>>,
 }
 
+/// The parts of a [`Connect`] that are relevant to the session state persisted by a server.
+///
+/// Transient parts of the connection like the keep-alive and authentication data are not included.
+#[derive(Debug)]
+pub struct SessionParts<'a, P>
+where
+    P: BufferPool,
+{
+    pub client_id: &'a ClientId<P>,
+    pub clean_start: bool,
+    pub session_expiry_interval: Option<Duration>,
+    pub will: Option<&'a (Publication<P>, Duration)>,
+}
+
 impl<P> Connect<P>
+where
+    P: BufferPool,
+{
+    /// Returns a borrowed view of the parts of this packet that are relevant to the session.
+    pub fn session_parts(&self) -> SessionParts<'_, P> {
+        SessionParts {
+            client_id: &self.client_id,
+            clean_start: self.clean_start(),
+            session_expiry_interval: self.session_expiry_interval,
+            will: self.will.as_ref(),
+        }
+    }
+
+    /// Whether this packet requested a new session, ie whether its Clean Start flag is set.
+    ///
+    /// Ref: 3.1.2.4 Clean Start
+    pub fn clean_start(&self) -> bool {
+        match &self.client_id {
+            ClientId::ServerGenerated | ClientId::IdWithCleanSession(_) => true,
+            ClientId::IdWithExistingSession(_) => false,
+        }
+    }
+
+    /// Sets whether the server may send a reason string and user properties in packets other than
+    /// PUBLISH, CONNACK and DISCONNECT.
+    ///
+    /// The property is only encoded when this is `false`, since `true` is the default.
+    ///
+    /// Ref: 3.1.2.11.7 Request Problem Information
+    #[must_use]
+    pub fn request_problem_information(mut self, requested: bool) -> Self {
+        self.request_problem_information = requested;
+        self
+    }
+
+    /// Sets the will of this packet, to be published the given delay after the connection is closed.
+    ///
+    /// Ref: 3.1.3.2.2 Will Delay Interval
+    pub fn set_will(&mut self, publication: Publication<P>, delay: Duration) {
+        self.will = Some((publication, delay));
+    }
+
+    /// Removes the will of this packet, if it has one.
+    pub fn clear_will(&mut self) {
+        self.will = None;
+    }
+
+    /// Returns how long after the connection is closed the will of this packet should be published, if it has one.
+    ///
+    /// This is the will delay interval, unless the session expires before it elapses.
+    /// An absent session expiry interval means the session ends when the connection is closed,
+    /// and the maximum session expiry interval of `0xFFFFFFFF` seconds means the session never expires.
+    ///
+    /// Ref: 3.1.3.2.2 Will Delay Interval
+    pub fn effective_will_delay(&self) -> Option<Duration> {
+        let (_, will_delay_interval) = self.will.as_ref()?;
+
+        let session_expiry_interval = self.session_expiry_interval.unwrap_or_default();
+        if session_expiry_interval.as_secs() >= u64::from(u32::MAX) {
+            return Some(*will_delay_interval);
+        }
+
+        Some(std::cmp::min(*will_delay_interval, session_expiry_interval))
+    }
+
+    /// Checks the rules of this packet that decoding does not enforce by itself.
+    ///
+    /// - Authentication data is only allowed along with an authentication method.
+    /// - The will, if any, must be published to a topic name without wildcards, and not with a topic alias.
+    ///
+    /// Returns the reason code that the connection should be disconnected with if a rule is broken.
+    ///
+    /// Ref:
+    /// - 3.1.2.5 Will Flag
+    /// - 3.1.2.11.10 Authentication Data
+    /// - 3.1.3.3 Will Topic
+    pub fn validate(&self) -> Result<(), DisconnectReasonCode> {
+        if self.authentication_data.is_some() && self.authentication_method.is_none() {
+            return Err(DisconnectReasonCode::ProtocolError);
+        }
+
+        if let Some((publication, _)) = &self.will {
+            if publication.topic_alias.is_some()
+                || !crate::topic::is_valid_topic_name(publication.topic_name.as_ref())
+            {
+                return Err(DisconnectReasonCode::ProtocolError);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Converts this packet into a 3.1.1 CONNECT with the same client identifier, keep-alive, credentials and will,
+    /// eg to forward it over a bridge to a 3.1.1 server.
+    ///
+    /// The properties of the packet and of its will, including the will delay interval, are dropped.
+    /// Fails if the packet uses a feature that 3.1.1 can't represent.
+    pub fn to_v3(&self) -> Result<crate::v3::Connect<P>, DowngradeError>
+    where
+        P: Clone,
+    {
+        if self.authentication_method.is_some() {
+            return Err(DowngradeError::EnhancedAuthentication);
+        }
+
+        // Ref: 3.1.1: 3.1.2.9 Password Flag
+        if self.password.is_some() && self.username.is_none() {
+            return Err(DowngradeError::PasswordWithoutUsername);
+        }
+
+        Ok(crate::v3::Connect {
+            username: self.username.clone(),
+            password: self.password.clone(),
+            will: self.will.as_ref().map(|(will, _)| crate::v3::Publication {
+                topic_name: will.topic_name.clone(),
+                qos: will.qos,
+                retain: will.retain,
+                payload: will.payload.clone(),
+            }),
+            client_id: self.client_id.clone(),
+            keep_alive: self.keep_alive,
+        })
+    }
+}
+
+/// The reason [`Connect::to_v3`] could not convert a CONNECT.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DowngradeError {
+    /// The packet has an authentication method, and 3.1.1 has no enhanced authentication.
+    EnhancedAuthentication,
+
+    /// The packet has a password but no username, which 3.1.1 does not allow.
+    PasswordWithoutUsername,
+}
+
+impl std::fmt::Display for DowngradeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DowngradeError::EnhancedAuthentication => {
+                f.write_str("enhanced authentication cannot be represented in 3.1.1")
+            }
+            DowngradeError::PasswordWithoutUsername => {
+                f.write_str("a password without a username cannot be represented in 3.1.1")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DowngradeError {}
+
+/// Constructs a CONNECT with a clean session and default properties from plain strings,
+/// copying them into buffers taken from the given pool.
+///
+/// An empty `client_id` asks the server to assign one. The will, if any, is published without delay
+/// and without being retained.
+pub fn connect_from_strs<P>(
+    pool: P,
+    client_id: &str,
+    keep_alive: Duration,
+    will: Option<(&str, &[u8], QoS)>,
+) -> Result<Connect<P>, EncodeError>
 where
     P: Clone + BufferPool,
 {
-    pub(crate) fn decode_rest(src: &mut Shared<P>) -> Result<Self, DecodeError> {
-        let protocol_name = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
-        if protocol_name != crate::PROTOCOL_NAME {
-            return Err(DecodeError::UnrecognizedProtocolName(
-                protocol_name.as_ref().to_owned(),
-            ));
+    let will = match will {
+        Some((topic_name, payload, qos)) => {
+            let mut buf = Owned::new(pool.clone(), pool.take(payload.len()));
+            buf.try_put_slice(payload)?;
+
+            let will = Publication {
+                topic_name: ByteStr::new(pool.clone(), topic_name)?,
+                qos,
+                retain: false,
+                payload_is_utf8: false,
+                message_expiry_interval: None,
+                topic_alias: None,
+                response_topic: None,
+                correlation_data: None,
+                user_properties: vec![],
+                content_type: None,
+                payload: buf.freeze(),
+            };
+            Some((will, Duration::from_secs(0)))
         }
+        None => None,
+    };
+
+    let client_id = if client_id.is_empty() {
+        ClientId::ServerGenerated
+    } else {
+        ClientId::IdWithCleanSession(ByteStr::new(pool, client_id)?)
+    };
+
+    Ok(Connect {
+        username: None,
+        password: None,
+        will,
+        client_id,
+        keep_alive,
+        session_expiry_interval: None,
+        receive_maximum: None,
+        maximum_packet_size: None,
+        topic_alias_maximum: 0,
+        request_response_information: false,
+        request_problem_information: true,
+        user_properties: vec![],
+        authentication_method: None,
+        authentication_data: None,
+    })
+}
 
-        let protocol_version = src.try_get_u8()?;
+impl<P> Connect<P>
+where
+    P: Clone + BufferPool,
+{
+    /// Decodes a CONNECT like [`PacketMeta::decode`], and also returns the properties of its will, if it has one,
+    /// exactly as they were decoded, including their property length.
+    pub(crate) fn decode_with_raw_will_properties(
+        flags: u8,
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<(Self, Option<Shared<P>>), DecodeError> {
+        let protocol_version = decode_connect_start(flags, src)?;
         if protocol_version != PROTOCOL_VERSION {
             return Err(DecodeError::UnrecognizedProtocolVersion(protocol_version));
         }
 
+        Self::decode_rest_with_raw_will_properties(src, ctx)
+    }
+
+    pub(crate) fn decode_rest(
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
+        let (connect, _) = Self::decode_rest_with_raw_will_properties(src, ctx)?;
+        Ok(connect)
+    }
+
+    fn decode_rest_with_raw_will_properties(
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<(Self, Option<Shared<P>>), DecodeError> {
         let connect_flags = src.try_get_u8()?;
         if connect_flags & 0b0000_0001 != 0 {
             return Err(DecodeError::ConnectReservedSet);
@@ -55,6 +302,7 @@ where
 
         decode_properties!(
             src,
+            ctx,
             session_expiry_interval: SessionExpiryInterval,
             receive_maximum: ReceiveMaximum,
             maximum_packet_size: MaximumPacketSize,
@@ -66,7 +314,7 @@ where
             authentication_data: AuthenticationData,
         );
 
-        let client_id = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
+        let client_id = ByteStr::decode_in_packet(src)?;
         let client_id = if client_id.is_empty() {
             ClientId::ServerGenerated
         } else if connect_flags & 0b0000_0010 == 0 {
@@ -75,11 +323,24 @@ where
             ClientId::IdWithCleanSession(client_id)
         };
 
+        let mut raw_will_properties = None;
+
         let will = if connect_flags & 0b0000_0100 == 0 {
             None
         } else {
+            // Unlike the property length of eg a DISCONNECT, the will property length is never optional,
+            // even if the will has no properties.
+            //
+            // Ref: 3.1.3.2.1 Property Length
+            if src.is_empty() {
+                return Err(DecodeError::MissingWillPropertyLength);
+            }
+
+            let will_properties_start = src.clone();
+
             decode_properties!(
                 src,
+                ctx,
                 will_delay_interval: WillDelayInterval,
                 will_payload_is_utf8: PayloadIsUtf8,
                 will_message_expiry_interval: MessageExpiryInterval,
@@ -89,7 +350,10 @@ where
                 will_user_properties: Vec<UserProperty>,
             );
 
-            let topic_name = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
+            let mut will_properties = will_properties_start;
+            raw_will_properties = Some(will_properties.split_to(will_properties.len() - src.len()));
+
+            let topic_name = ByteStr::decode_in_packet(src)?;
 
             let qos = match connect_flags & 0b0001_1000 {
                 0x00 => QoS::AtMostOnce,
@@ -127,23 +391,24 @@ where
         let username = if connect_flags & 0b1000_0000 == 0 {
             None
         } else {
-            Some(ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?)
+            Some(ByteStr::decode_in_packet(src)?)
         };
 
         let password = if connect_flags & 0b0100_0000 == 0 {
             None
         } else {
-            Some(ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?)
+            Some(ByteStr::decode_in_packet(src)?)
         };
 
-        Ok(Connect {
+        let connect = Connect {
             username,
             password,
             will,
             client_id,
             keep_alive,
             session_expiry_interval,
-            receive_maximum: receive_maximum.unwrap_or_else(|| usize::from(u16::max_value())),
+            receive_maximum: receive_maximum
+                .and_then(|value| value.try_into().ok().and_then(NonZeroU16::new)),
             maximum_packet_size,
             topic_alias_maximum: topic_alias_maximum.unwrap_or(0),
             request_response_information: request_response_information.unwrap_or(false),
@@ -151,7 +416,9 @@ where
             user_properties,
             authentication_method,
             authentication_data,
-        })
+        };
+
+        Ok((connect, raw_will_properties))
     }
 }
 
@@ -175,13 +442,13 @@ where
 {
     const PACKET_TYPE: u8 = 0x10;
 
-    fn decode(flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(flags: u8, src: &mut Shared<P>, ctx: &DecodeContext<P>) -> Result<Self, DecodeError> {
         let protocol_version = decode_connect_start(flags, src)?;
         if protocol_version != PROTOCOL_VERSION {
             return Err(DecodeError::UnrecognizedProtocolVersion(protocol_version));
         }
 
-        Self::decode_rest(src)
+        Self::decode_rest(src, ctx)
     }
 
     fn encode<B>(self, dst: &mut B) -> Result<(), EncodeError>
@@ -240,10 +507,12 @@ where
                 .map_err(|_| EncodeError::KeepAliveTooHigh(keep_alive))?,
         )?;
 
+        let receive_maximum = receive_maximum.map(|value| usize::from(value.get()));
+
         encode_properties! {
             dst,
             session_expiry_interval: Option<SessionExpiryInterval>,
-            receive_maximum: ReceiveMaximum,
+            receive_maximum: Option<ReceiveMaximum>,
             maximum_packet_size: Option<MaximumPacketSize>,
             topic_alias_maximum: TopicAliasMaximum,
             request_response_information: RequestResponseInformation,
@@ -310,3 +579,370 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::num::NonZeroU16;
+    use std::time::Duration;
+
+    use super::{Connect, DowngradeError};
+    use crate::tests::{byte_str, shared, TestBufferPool};
+    use crate::v5::{DisconnectReasonCode, Packet, Publication};
+    use crate::{ClientId, DecodeContext, DecodeError, Owned, QoS};
+
+    pub(crate) fn connect(client_id: ClientId<TestBufferPool>) -> Connect<TestBufferPool> {
+        Connect {
+            username: None,
+            password: None,
+            will: None,
+            client_id,
+            keep_alive: Duration::from_secs(30),
+            session_expiry_interval: None,
+            receive_maximum: None,
+            maximum_packet_size: None,
+            topic_alias_maximum: 0,
+            request_response_information: false,
+            request_problem_information: true,
+            user_properties: vec![],
+            authentication_method: None,
+            authentication_data: None,
+        }
+    }
+
+    pub(crate) fn publication(topic_name: &str, payload: &[u8]) -> Publication<TestBufferPool> {
+        Publication {
+            topic_name: byte_str(topic_name),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            payload_is_utf8: false,
+            message_expiry_interval: None,
+            topic_alias: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: vec![],
+            content_type: None,
+            payload: shared(payload),
+        }
+    }
+
+    #[test]
+    fn clean_start() {
+        assert!(connect(ClientId::ServerGenerated).clean_start());
+        assert!(connect(ClientId::IdWithCleanSession(byte_str("id"))).clean_start());
+        assert!(!connect(ClientId::IdWithExistingSession(byte_str("id"))).clean_start());
+    }
+
+    #[test]
+    fn effective_will_delay() {
+        let mut packet = connect(ClientId::IdWithExistingSession(byte_str("client1")));
+        assert_eq!(packet.effective_will_delay(), None);
+
+        packet.will = Some((publication("will/topic", b"gone"), Duration::from_secs(5)));
+
+        // Will delay shorter than session expiry
+        packet.session_expiry_interval = Some(Duration::from_secs(30));
+        assert_eq!(packet.effective_will_delay(), Some(Duration::from_secs(5)));
+
+        // Session expiry shorter than will delay
+        packet.session_expiry_interval = Some(Duration::from_secs(2));
+        assert_eq!(packet.effective_will_delay(), Some(Duration::from_secs(2)));
+
+        // No session expiry, so the session ends with the connection
+        packet.session_expiry_interval = None;
+        assert_eq!(packet.effective_will_delay(), Some(Duration::from_secs(0)));
+
+        // Session never expires
+        packet.session_expiry_interval = Some(Duration::from_secs(u64::from(u32::MAX)));
+        assert_eq!(packet.effective_will_delay(), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn set_and_clear_will() {
+        let mut packet = connect(ClientId::IdWithExistingSession(byte_str("client1")));
+
+        packet.set_will(publication("will/topic", b"gone"), Duration::from_secs(5));
+        assert_eq!(
+            packet.will,
+            Some((publication("will/topic", b"gone"), Duration::from_secs(5)))
+        );
+
+        packet.clear_will();
+        assert_eq!(packet.will, None);
+    }
+
+    #[test]
+    fn validate() {
+        let mut packet = connect(ClientId::IdWithExistingSession(byte_str("client1")));
+        assert_eq!(packet.validate(), Ok(()));
+
+        // Authentication data without a method
+        packet.authentication_data = Some(shared(b"\x00\x02ab"));
+        assert_eq!(packet.validate(), Err(DisconnectReasonCode::ProtocolError));
+
+        // Authentication data with a method
+        packet.authentication_method = Some(byte_str("SCRAM-SHA-1"));
+        assert_eq!(packet.validate(), Ok(()));
+
+        packet.will = Some((publication("will/topic", b"gone"), Duration::from_secs(5)));
+        assert_eq!(packet.validate(), Ok(()));
+
+        // Will topic with a wildcard
+        packet.will = Some((publication("will/#", b"gone"), Duration::from_secs(5)));
+        assert_eq!(packet.validate(), Err(DisconnectReasonCode::ProtocolError));
+    }
+
+    #[test]
+    fn session_parts() {
+        let mut packet = connect(ClientId::IdWithExistingSession(byte_str("client1")));
+        packet.session_expiry_interval = Some(Duration::from_secs(1000));
+        packet.will = Some((publication("will/topic", b"gone"), Duration::from_secs(5)));
+        packet.authentication_method = Some(byte_str("SCRAM-SHA-1"));
+
+        let parts = packet.session_parts();
+        assert_eq!(
+            parts.client_id,
+            &ClientId::IdWithExistingSession(byte_str("client1"))
+        );
+        assert!(!parts.clean_start);
+        assert_eq!(
+            parts.session_expiry_interval,
+            Some(Duration::from_secs(1000))
+        );
+        let (will, will_delay_interval) = parts.will.unwrap();
+        assert_eq!(will.topic_name, "will/topic");
+        assert_eq!(will.payload, b"gone"[..]);
+        assert_eq!(*will_delay_interval, Duration::from_secs(5));
+
+        let packet = connect(ClientId::ServerGenerated);
+        let parts = packet.session_parts();
+        assert!(parts.clean_start);
+        assert_eq!(parts.session_expiry_interval, None);
+        assert!(parts.will.is_none());
+    }
+
+    #[test]
+    fn decode_roundtrip() {
+        let mut packet = connect(ClientId::IdWithCleanSession(byte_str("client1")));
+        packet.session_expiry_interval = Some(Duration::from_secs(1000));
+        packet.will = Some((publication("will/topic", b"gone"), Duration::from_secs(5)));
+
+        let pool = TestBufferPool;
+        let mut bytes = Owned::new(pool, pool.take(128));
+        crate::v5::encode(Packet::Connect(packet.clone()), &mut bytes).unwrap();
+
+        let decoded = crate::v5::decode(bytes.filled()[0], shared(&bytes.filled()[2..])).unwrap();
+        assert_eq!(decoded, Packet::Connect(packet));
+    }
+
+    #[test]
+    fn client_id_length_exceeds_packet() {
+        match crate::v5::decode(0x10, shared(b"\x00\x04MQTT\x05\x02\x00\x1E\x00\x00\x05ab")) {
+            Err(DecodeError::StringLengthExceedsPacket {
+                string_length: 5,
+                remaining_length: 2,
+            }) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn retain_raw_will_properties() {
+        // The user property comes before the will delay interval, unlike how they would be encoded
+        let will_properties = b"\x0C\x26\x00\x01k\x00\x01v\x18\x00\x00\x00\x05";
+        let mut body = b"\x00\x04MQTT\x05\x06\x00\x1E\x00\x00\x01c".to_vec();
+        body.extend_from_slice(will_properties);
+        body.extend_from_slice(b"\x00\x01t\x00\x04gone");
+
+        let ctx = DecodeContext::new(TestBufferPool);
+        let (packet, raw_will_properties) =
+            crate::v5::decode_connect_with_raw_will_properties(&ctx, shared(&body)).unwrap();
+        assert_eq!(raw_will_properties, Some(shared(will_properties)));
+
+        // The retained bytes don't affect the decoded packet
+        let decoded: Connect<_> = crate::v5::decode_expect(0x10, shared(&body)).unwrap();
+        assert_eq!(decoded, packet);
+
+        let (will, will_delay_interval) = packet.will.unwrap();
+        assert_eq!(will.user_properties, vec![(byte_str("k"), byte_str("v"))]);
+        assert_eq!(will.payload, b"gone"[..]);
+        assert_eq!(will_delay_interval, Duration::from_secs(5));
+
+        let body = b"\x00\x04MQTT\x05\x02\x00\x1E\x00\x00\x01c";
+        let (_, raw_will_properties) =
+            crate::v5::decode_connect_with_raw_will_properties(&ctx, shared(body)).unwrap();
+        assert_eq!(raw_will_properties, None);
+    }
+
+    #[test]
+    fn will_properties_empty_or_missing() {
+        let mut body = b"\x00\x04MQTT\x05\x06\x00\x1E\x00\x00\x01c".to_vec();
+        match crate::v5::decode_expect::<Connect<_>, _>(0x10, shared(&body)) {
+            Err(DecodeError::MissingWillPropertyLength) => (),
+            result => panic!("{:?}", result),
+        }
+
+        body.extend_from_slice(b"\x00\x00\x01t\x00\x04gone");
+        let packet: Connect<_> = crate::v5::decode_expect(0x10, shared(&body)).unwrap();
+        let mut expected = publication("t", b"gone");
+        expected.qos = QoS::AtMostOnce;
+        assert_eq!(packet.will, Some((expected, Duration::ZERO)));
+    }
+
+    #[test]
+    fn receive_maximum() {
+        let body = b"\x00\x04MQTT\x05\x02\x00\x1E\x00\x00\x01c";
+        let packet: Connect<_> = crate::v5::decode_expect(0x10, shared(body)).unwrap();
+        assert_eq!(packet.receive_maximum, None);
+
+        let body = b"\x00\x04MQTT\x05\x02\x00\x1E\x03\x21\xFF\xFF\x00\x01c";
+        let packet: Connect<_> = crate::v5::decode_expect(0x10, shared(body)).unwrap();
+        assert_eq!(packet.receive_maximum, NonZeroU16::new(65535));
+
+        let body = b"\x00\x04MQTT\x05\x02\x00\x1E\x03\x21\x00\x00\x00\x01c";
+        match crate::v5::decode_expect::<Connect<_>, _>(0x10, shared(body)) {
+            Err(DecodeError::InvalidReceiveMaximum(0)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn receive_maximum_roundtrip() {
+        let pool = TestBufferPool;
+
+        for &receive_maximum in &[None, NonZeroU16::new(10), NonZeroU16::new(65535)] {
+            let mut packet = connect(ClientId::IdWithCleanSession(byte_str("c")));
+            packet.receive_maximum = receive_maximum;
+
+            let mut bytes = Owned::new(pool, pool.take(32));
+            crate::v5::encode(Packet::Connect(packet.clone()), &mut bytes).unwrap();
+            let decoded =
+                crate::v5::decode(bytes.filled()[0], shared(&bytes.filled()[2..])).unwrap();
+            assert_eq!(decoded, Packet::Connect(packet));
+        }
+    }
+
+    #[test]
+    fn to_v3() {
+        let mut packet = connect(ClientId::IdWithCleanSession(byte_str("client1")));
+        packet.username = Some(byte_str("user"));
+        packet.password = Some(byte_str("pass"));
+        packet.session_expiry_interval = Some(Duration::from_secs(100));
+        packet.receive_maximum = NonZeroU16::new(10);
+        packet.user_properties = vec![(byte_str("k"), byte_str("v"))];
+        let mut will = publication("will/topic", b"gone");
+        will.retain = true;
+        will.content_type = Some(byte_str("text/plain"));
+        packet.set_will(will, Duration::from_secs(5));
+
+        assert_eq!(
+            packet.to_v3(),
+            Ok(crate::v3::Connect {
+                username: Some(byte_str("user")),
+                password: Some(byte_str("pass")),
+                will: Some(crate::v3::Publication {
+                    topic_name: byte_str("will/topic"),
+                    qos: QoS::AtLeastOnce,
+                    retain: true,
+                    payload: shared(b"gone"),
+                }),
+                client_id: ClientId::IdWithCleanSession(byte_str("client1")),
+                keep_alive: Duration::from_secs(30),
+            })
+        );
+
+        packet.username = None;
+        assert_eq!(packet.to_v3(), Err(DowngradeError::PasswordWithoutUsername));
+
+        packet.authentication_method = Some(byte_str("SCRAM-SHA-1"));
+        assert_eq!(packet.to_v3(), Err(DowngradeError::EnhancedAuthentication));
+    }
+
+    #[test]
+    fn connect_from_strs() {
+        let pool = TestBufferPool;
+
+        let packet = super::connect_from_strs(
+            pool,
+            "client1",
+            Duration::from_secs(30),
+            Some(("will/topic", b"gone", QoS::AtLeastOnce)),
+        )
+        .unwrap();
+        assert_eq!(
+            packet.client_id,
+            ClientId::IdWithCleanSession(byte_str("client1"))
+        );
+        let (will, will_delay_interval) = packet.will.as_ref().unwrap();
+        assert_eq!(will.topic_name, "will/topic");
+        assert_eq!(will.payload, b"gone"[..]);
+        assert_eq!(*will_delay_interval, Duration::from_secs(0));
+
+        let mut bytes = Owned::new(pool, pool.take(64));
+        crate::v5::encode(Packet::Connect(packet.clone()), &mut bytes).unwrap();
+        let decoded = crate::v5::decode(bytes.filled()[0], shared(&bytes.filled()[2..])).unwrap();
+        assert_eq!(decoded, Packet::Connect(packet));
+
+        let packet = super::connect_from_strs(pool, "", Duration::from_secs(30), None).unwrap();
+        assert_eq!(packet.client_id, ClientId::ServerGenerated);
+        assert!(packet.will.is_none());
+
+        // The client ID, will topic and will payload are all taken from the pool
+        let pool = std::sync::Arc::new(crate::ReusableBufferPool::new(16));
+        let packet = super::connect_from_strs(
+            pool.clone(),
+            "client1",
+            Duration::from_secs(30),
+            Some(("will/topic", b"gone", QoS::AtLeastOnce)),
+        )
+        .unwrap();
+        assert_eq!(pool.outstanding(), 3);
+        drop(packet);
+        assert_eq!(pool.outstanding(), 0);
+    }
+
+    #[test]
+    fn encode_request_problem_information() {
+        let packet = connect(ClientId::IdWithCleanSession(byte_str("c")));
+        assert!(packet.request_problem_information);
+
+        let pool = TestBufferPool;
+
+        let mut bytes = Owned::new(pool, pool.take(32));
+        crate::v5::encode(Packet::Connect(packet.clone()), &mut bytes).unwrap();
+        assert_eq!(
+            bytes.filled(),
+            b"\x10\x0E\x00\x04MQTT\x05\x02\x00\x1E\x00\x00\x01c"
+        );
+
+        let packet = packet.request_problem_information(false);
+        let mut bytes = Owned::new(pool, pool.take(32));
+        crate::v5::encode(Packet::Connect(packet.clone()), &mut bytes).unwrap();
+        assert_eq!(
+            bytes.filled(),
+            b"\x10\x10\x00\x04MQTT\x05\x02\x00\x1E\x02\x17\x00\x00\x01c"
+        );
+
+        let decoded = crate::v5::decode(bytes.filled()[0], shared(&bytes.filled()[2..])).unwrap();
+        assert_eq!(decoded, Packet::Connect(packet));
+    }
+
+    #[test]
+    fn property_length_exceeds_packet() {
+        let body = shared(
+            b"\
+                \x00\x04MQTT\x05\
+                \x02\
+                \x00\x1E\
+                \x0A\
+                \x00\x03abc\
+            ",
+        );
+        match crate::v5::decode(0x10, body) {
+            Err(DecodeError::PropertyLengthExceedsPacket {
+                property_length: 10,
+                remaining_length: 5,
+            }) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+}
@@ -3,8 +3,10 @@
 use std::convert::TryInto;
 use std::time::Duration;
 
-use super::{PacketMeta, Property};
-use crate::{BufferPool, ByteBuf, ByteStr, DecodeError, EncodeError, Shared};
+use super::{encode_remaining_length, PacketMeta, Property, RedirectReason, ServerRedirect};
+use crate::{
+    BufferPool, ByteBuf, ByteCounter, ByteStr, DecodeContext, DecodeError, EncodeError, Shared,
+};
 
 /// Ref: 3.14 DISCONNECT - Disconnect notification
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -32,6 +34,7 @@ define_u8_code! {
     NotAuthorized = 0x87,
     ServerBusy = 0x89,
     ServerShuttingDown = 0x8B,
+    BadAuthenticationMethod = 0x8C,
     KeepAliveTimeout = 0x8D,
     SessionTakenOver = 0x8E,
     TopicFilterInvalid = 0x8F,
@@ -54,19 +57,48 @@ define_u8_code! {
     WildcardSubscriptionsNotSupported = 0xA2,
 }
 
+impl<P> Disconnect<P>
+where
+    P: BufferPool,
+{
+    /// Constructs a DISCONNECT with the given reason code and no properties.
+    pub fn new(reason_code: DisconnectReasonCode) -> Self {
+        Disconnect {
+            reason_code,
+            session_expiry_interval: None,
+            reason_string: None,
+            user_properties: vec![],
+            server_reference: None,
+        }
+    }
+
+    /// Returns whether the client wants the server to publish its will even though it is disconnecting normally.
+    ///
+    /// Ref: 3.14.2.1 Disconnect Reason Code
+    pub fn requests_will(&self) -> bool {
+        self.reason_code == DisconnectReasonCode::DisconnectWithWillMessage
+    }
+}
+
 impl<P> PacketMeta<P> for Disconnect<P>
 where
     P: Clone + BufferPool,
 {
     const PACKET_TYPE: u8 = 0xE0;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         match src.try_get_u8() {
             Ok(reason_code) => {
                 let reason_code = reason_code.try_into()?;
 
                 decode_properties!(
+                    @optional_length
                     src,
+                    ctx,
                     session_expiry_interval: SessionExpiryInterval,
                     reason_string: ReasonString,
                     user_properties: Vec<UserProperty>,
@@ -82,19 +114,59 @@ where
                 })
             }
 
-            Err(DecodeError::IncompletePacket) => Ok(Disconnect {
-                reason_code: DisconnectReasonCode::Normal,
-                session_expiry_interval: None,
-                reason_string: None,
-                user_properties: vec![],
-                server_reference: None,
-            }),
+            Err(DecodeError::IncompletePacket) => Ok(Disconnect::new(DisconnectReasonCode::Normal)),
 
             Err(err) => Err(err),
         }
     }
 
     fn encode<B>(self, dst: &mut B) -> Result<(), EncodeError>
+    where
+        B: ByteBuf,
+    {
+        self.encode_body(dst, false)
+    }
+}
+
+impl<P> Disconnect<P>
+where
+    P: Clone + BufferPool,
+{
+    /// Returns the server that the client should connect to instead,
+    /// if this packet has a redirect reason code and a server reference.
+    ///
+    /// Ref: 4.11 Server redirection
+    pub fn server_redirect(&self) -> Option<ServerRedirect<P>> {
+        let reason = match self.reason_code {
+            DisconnectReasonCode::UseAnotherServer => RedirectReason::UseAnotherServer,
+            DisconnectReasonCode::ServerMoved => RedirectReason::ServerMoved,
+            _ => return None,
+        };
+
+        Some(ServerRedirect {
+            reason,
+            reference: self.server_reference.clone()?,
+        })
+    }
+
+    /// Encodes this packet like [`crate::v5::encode`], except that the reason code is always written
+    /// even if it's [`DisconnectReasonCode::Normal`] and there are no properties.
+    pub fn encode_explicit<B>(self, dst: &mut B) -> Result<(), EncodeError>
+    where
+        B: ByteBuf,
+    {
+        let mut counter: ByteCounter = Default::default();
+        self.clone().encode_body(&mut counter, true)?;
+        let body_len = counter.0;
+
+        dst.try_put_u8(<Self as PacketMeta<P>>::PACKET_TYPE)?;
+        encode_remaining_length(body_len, dst)?;
+        self.encode_body(dst, true)?;
+
+        Ok(())
+    }
+
+    fn encode_body<B>(self, dst: &mut B, force_reason_code: bool) -> Result<(), EncodeError>
     where
         B: ByteBuf,
     {
@@ -106,7 +178,8 @@ where
             server_reference,
         } = self;
 
-        let need_variable_header = reason_code != DisconnectReasonCode::Normal
+        let need_variable_header = force_reason_code
+            || reason_code != DisconnectReasonCode::Normal
             || session_expiry_interval.is_some()
             || reason_string.is_some()
             || !user_properties.is_empty()
@@ -126,3 +199,77 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Disconnect, DisconnectReasonCode};
+    use crate::tests::{byte_str, TestBufferPool};
+    use crate::v5::{RedirectReason, ServerRedirect};
+    use crate::Owned;
+
+    fn normal() -> Disconnect<TestBufferPool> {
+        Disconnect::new(DisconnectReasonCode::Normal)
+    }
+
+    #[test]
+    fn encode_explicit() {
+        let pool = TestBufferPool;
+
+        let mut compact = Owned::new(pool, pool.take(8));
+        crate::v5::encode(crate::v5::Packet::Disconnect(normal()), &mut compact).unwrap();
+        assert_eq!(compact.filled(), b"\xE0\x00");
+
+        let mut explicit = Owned::new(pool, pool.take(8));
+        normal().encode_explicit(&mut explicit).unwrap();
+        assert_eq!(explicit.filled(), b"\xE0\x02\x00\x00");
+
+        let body = crate::tests::shared(&explicit.filled()[2..]);
+        let decoded = crate::v5::decode(0xE0, body).unwrap();
+        assert_eq!(decoded, crate::v5::Packet::Disconnect(normal()));
+    }
+
+    #[test]
+    fn decode_reason_code_only() {
+        let body = crate::tests::shared(b"\x8B");
+        let decoded = crate::v5::decode(0xE0, body).unwrap();
+        assert_eq!(
+            decoded,
+            crate::v5::Packet::Disconnect(Disconnect {
+                reason_code: DisconnectReasonCode::ServerShuttingDown,
+                ..normal()
+            })
+        );
+    }
+
+    #[test]
+    fn requests_will() {
+        assert!(!normal().requests_will());
+
+        let packet = Disconnect {
+            reason_code: DisconnectReasonCode::DisconnectWithWillMessage,
+            ..normal()
+        };
+        assert!(packet.requests_will());
+    }
+
+    #[test]
+    fn server_redirect() {
+        let mut packet = Disconnect {
+            reason_code: DisconnectReasonCode::UseAnotherServer,
+            server_reference: Some(byte_str("other.example.com")),
+            ..normal()
+        };
+        assert_eq!(
+            packet.server_redirect(),
+            Some(ServerRedirect {
+                reason: RedirectReason::UseAnotherServer,
+                reference: byte_str("other.example.com"),
+            })
+        );
+
+        packet.server_reference = None;
+        assert_eq!(packet.server_redirect(), None);
+
+        assert_eq!(normal().server_redirect(), None);
+    }
+}
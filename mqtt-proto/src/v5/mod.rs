@@ -1,10 +1,12 @@
 // Copyright (c) Microsoft. All rights reserved.
 
+use std::convert::{TryFrom, TryInto};
 use std::time::Duration;
 
 use super::{decode_connect_start, decode_remaining_length, encode_remaining_length};
 use crate::{
-    BufferPool, ByteBuf, ByteCounter, ByteStr, DecodeError, EncodeError, PacketMeta, QoS, Shared,
+    BufferPool, ByteBuf, ByteCounter, ByteStr, DecodeContext, DecodeError, DecodeErrorAt,
+    DecodedPacket, EncodeError, Owned, PacketIdentifier, PacketMeta, QoS, Shared,
 };
 
 #[macro_use]
@@ -12,13 +14,16 @@ mod property;
 use property::Property;
 
 mod auth;
-pub use auth::{Auth, AuthenticateReasonCode};
+pub use auth::{validate_auth, Auth, AuthenticateReasonCode};
 
 mod connack;
-pub use connack::{ConnAck, ConnectReasonCode};
+pub use connack::{
+    clamp_server_keep_alive, ConnAck, ConnectReasonCode, ConnectionRefusedReason,
+    ServerCapabilities,
+};
 
 mod connect;
-pub use connect::Connect;
+pub use connect::{connect_from_strs, Connect, DowngradeError, SessionParts};
 
 mod disconnect;
 pub use disconnect::{Disconnect, DisconnectReasonCode};
@@ -44,11 +49,16 @@ pub use pubrel::{PubRel, PubRelReasonCode};
 mod publish;
 pub use publish::{PacketIdentifierDupQoS, Publish};
 
+mod stream;
+pub use stream::{BlockingReader, StreamDecoder};
+
 mod suback;
 pub use suback::{SubAck, SubscribeReasonCode};
 
 mod subscribe;
-pub use subscribe::{RetainHandling, Subscribe, SubscribeTo};
+pub use subscribe::{
+    ClassifiedFilter, RetainHandling, Subscribe, SubscribeTo, SubscriptionOptions,
+};
 
 mod unsuback;
 pub use unsuback::{UnsubAck, UnsubscribeReasonCode};
@@ -110,6 +120,222 @@ where
     Unsubscribe(Unsubscribe<P>),
 }
 
+impl<P> Packet<P>
+where
+    P: Clone + BufferPool,
+{
+    /// The packet type of this packet, ie the upper four bits of its first byte.
+    pub fn packet_type(&self) -> u8 {
+        match self {
+            Packet::Auth(_) => Auth::<P>::PACKET_TYPE,
+            Packet::ConnAck(_) => ConnAck::<P>::PACKET_TYPE,
+            Packet::Connect(_) => Connect::<P>::PACKET_TYPE,
+            Packet::Disconnect(_) => Disconnect::<P>::PACKET_TYPE,
+            Packet::PingReq(_) => <PingReq as PacketMeta<P>>::PACKET_TYPE,
+            Packet::PingResp(_) => <PingResp as PacketMeta<P>>::PACKET_TYPE,
+            Packet::PubAck(_) => PubAck::<P>::PACKET_TYPE,
+            Packet::PubComp(_) => PubComp::<P>::PACKET_TYPE,
+            Packet::Publish(_) => Publish::<P>::PACKET_TYPE,
+            Packet::PubRec(_) => PubRec::<P>::PACKET_TYPE,
+            Packet::PubRel(_) => PubRel::<P>::PACKET_TYPE,
+            Packet::SubAck(_) => SubAck::<P>::PACKET_TYPE,
+            Packet::Subscribe(_) => Subscribe::<P>::PACKET_TYPE,
+            Packet::UnsubAck(_) => UnsubAck::<P>::PACKET_TYPE,
+            Packet::Unsubscribe(_) => Unsubscribe::<P>::PACKET_TYPE,
+        }
+    }
+
+    /// A summary of this packet that is suitable for labeling metrics.
+    pub fn metrics_key(&self) -> PacketMetricsKey {
+        let packet_type = match self {
+            Packet::Auth(_) => PacketType::Auth,
+            Packet::ConnAck(_) => PacketType::ConnAck,
+            Packet::Connect(_) => PacketType::Connect,
+            Packet::Disconnect(_) => PacketType::Disconnect,
+            Packet::PingReq(_) => PacketType::PingReq,
+            Packet::PingResp(_) => PacketType::PingResp,
+            Packet::PubAck(_) => PacketType::PubAck,
+            Packet::PubComp(_) => PacketType::PubComp,
+            Packet::Publish(_) => PacketType::Publish,
+            Packet::PubRec(_) => PacketType::PubRec,
+            Packet::PubRel(_) => PacketType::PubRel,
+            Packet::SubAck(_) => PacketType::SubAck,
+            Packet::Subscribe(_) => PacketType::Subscribe,
+            Packet::UnsubAck(_) => PacketType::UnsubAck,
+            Packet::Unsubscribe(_) => PacketType::Unsubscribe,
+        };
+
+        let (qos, retain) = if let Packet::Publish(publish) = self {
+            (Some(publish.qos()), publish.retain)
+        } else {
+            (None, false)
+        };
+
+        PacketMetricsKey {
+            packet_type,
+            qos,
+            retain,
+        }
+    }
+}
+
+/// The kind of a [`Packet`], without its contents
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PacketType {
+    Auth,
+    ConnAck,
+    Connect,
+    Disconnect,
+    PingReq,
+    PingResp,
+    PubAck,
+    PubComp,
+    Publish,
+    PubRec,
+    PubRel,
+    SubAck,
+    Subscribe,
+    UnsubAck,
+    Unsubscribe,
+}
+
+impl std::fmt::Display for PacketType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            PacketType::Auth => "AUTH",
+            PacketType::ConnAck => "CONNACK",
+            PacketType::Connect => "CONNECT",
+            PacketType::Disconnect => "DISCONNECT",
+            PacketType::PingReq => "PINGREQ",
+            PacketType::PingResp => "PINGRESP",
+            PacketType::PubAck => "PUBACK",
+            PacketType::PubComp => "PUBCOMP",
+            PacketType::Publish => "PUBLISH",
+            PacketType::PubRec => "PUBREC",
+            PacketType::PubRel => "PUBREL",
+            PacketType::SubAck => "SUBACK",
+            PacketType::Subscribe => "SUBSCRIBE",
+            PacketType::UnsubAck => "UNSUBACK",
+            PacketType::Unsubscribe => "UNSUBSCRIBE",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The result of [`Packet::metrics_key`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PacketMetricsKey {
+    pub packet_type: PacketType,
+
+    /// The quality of service of a PUBLISH packet, or `None` for every other kind of packet.
+    pub qos: Option<QoS>,
+
+    /// The RETAIN flag of a PUBLISH packet, or `false` for every other kind of packet.
+    pub retain: bool,
+}
+
+macro_rules! impl_try_from_packet {
+    ($($variant:ident => $ty:ty ,)*) => {
+        $(
+            impl<P> TryFrom<Packet<P>> for $ty
+            where
+                P: Clone + BufferPool,
+            {
+                type Error = DecodeError;
+
+                fn try_from(packet: Packet<P>) -> Result<Self, Self::Error> {
+                    match packet {
+                        Packet::$variant(packet) => Ok(packet),
+                        packet => Err(DecodeError::UnexpectedPacketType {
+                            expected: <$ty as PacketMeta<P>>::PACKET_TYPE,
+                            got: packet.packet_type(),
+                        }),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_try_from_packet! {
+    Auth => Auth<P>,
+    ConnAck => ConnAck<P>,
+    Connect => Connect<P>,
+    Disconnect => Disconnect<P>,
+    PingReq => PingReq,
+    PingResp => PingResp,
+    PubAck => PubAck<P>,
+    PubComp => PubComp<P>,
+    Publish => Publish<P>,
+    PubRec => PubRec<P>,
+    PubRel => PubRel<P>,
+    SubAck => SubAck<P>,
+    Subscribe => Subscribe<P>,
+    UnsubAck => UnsubAck<P>,
+    Unsubscribe => Unsubscribe<P>,
+}
+
+/// The length of the body of a PUBACK, PUBREC, PUBREL or PUBCOMP, if it is just the packet identifier,
+/// ie if it has a success reason code and no properties.
+///
+/// Ref: 3.4.2.1 PUBACK Reason Code
+fn ack_encoded_len<P>(
+    is_success: bool,
+    reason_string: Option<&ByteStr<P>>,
+    user_properties: &[(ByteStr<P>, ByteStr<P>)],
+) -> Option<usize>
+where
+    P: BufferPool,
+{
+    if is_success && reason_string.is_none() && user_properties.is_empty() {
+        Some(std::mem::size_of::<u16>())
+    } else {
+        None
+    }
+}
+
+impl<P> Packet<P>
+where
+    P: BufferPool,
+{
+    /// Returns whether sending or receiving this packet ends the connection,
+    /// ie whether it's a DISCONNECT or a CONNACK that refuses the connection.
+    ///
+    /// AUTH never ends the connection by itself.
+    pub fn closes_connection(&self) -> bool {
+        match self {
+            Packet::ConnAck(packet) => matches!(packet.return_code, ConnectReasonCode::Refused(_)),
+            Packet::Disconnect(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the packet identifier of this packet, if it has one.
+    ///
+    /// A PUBLISH only has a packet identifier if its quality of service is higher than at-most-once.
+    pub fn packet_identifier(&self) -> Option<PacketIdentifier> {
+        match self {
+            Packet::Auth(_)
+            | Packet::ConnAck(_)
+            | Packet::Connect(_)
+            | Packet::Disconnect(_)
+            | Packet::PingReq(_)
+            | Packet::PingResp(_) => None,
+
+            Packet::Publish(packet) => packet.packet_identifier(),
+
+            Packet::PubAck(packet) => Some(packet.packet_identifier),
+            Packet::PubComp(packet) => Some(packet.packet_identifier),
+            Packet::PubRec(packet) => Some(packet.packet_identifier),
+            Packet::PubRel(packet) => Some(packet.packet_identifier),
+            Packet::SubAck(packet) => Some(packet.packet_identifier),
+            Packet::Subscribe(packet) => Some(packet.packet_identifier),
+            Packet::UnsubAck(packet) => Some(packet.packet_identifier),
+            Packet::Unsubscribe(packet) => Some(packet.packet_identifier),
+        }
+    }
+}
+
 /// A message that can be published to the server
 //  but not yet assigned a packet identifier.
 #[derive(Clone, Eq, PartialEq)]
@@ -144,53 +370,273 @@ where
     }
 }
 
+impl<P> Publication<P>
+where
+    P: BufferPool,
+{
+    /// Converts this publication into a PUBLISH packet, eg to deliver a will.
+    ///
+    /// The packet identifier is required unless the publication is [`QoS::AtMostOnce`], in which case it is ignored.
+    pub fn into_publish(
+        self,
+        packet_identifier: Option<PacketIdentifier>,
+    ) -> Result<Publish<P>, EncodeError> {
+        let Publication {
+            topic_name,
+            qos,
+            retain,
+            payload_is_utf8,
+            message_expiry_interval,
+            topic_alias,
+            response_topic,
+            correlation_data,
+            user_properties,
+            content_type,
+            payload,
+        } = self;
+
+        Ok(Publish {
+            topic_name,
+            packet_identifier_dup_qos: PacketIdentifierDupQoS::new(qos, packet_identifier)?,
+            retain,
+            payload_is_utf8,
+            message_expiry_interval,
+            topic_alias,
+            response_topic,
+            correlation_data,
+            user_properties,
+            subscription_identifiers: vec![],
+            content_type,
+            payload,
+        })
+    }
+}
+
+/// Why a server asked the client to connect to another server.
+///
+/// Ref: 4.11 Server redirection
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RedirectReason {
+    /// The client should temporarily use another server.
+    UseAnotherServer,
+
+    /// The client should permanently use another server.
+    ServerMoved,
+}
+
+/// A server redirection carried by a [`ConnAck`] or [`Disconnect`]
+///
+/// Ref: 4.11 Server redirection
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServerRedirect<P>
+where
+    P: BufferPool,
+{
+    pub reason: RedirectReason,
+    pub reference: ByteStr<P>,
+}
+
+/// Whether a server has accepted the CONNECT of a connection yet, for checking the order of inbound packets.
+///
+/// Ref: 3.1 CONNECT – Connection Request
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionPhase {
+    /// The connection has not completed a CONNECT yet.
+    /// Only a CONNECT, and AUTH packets for enhanced authentication, may be received.
+    AwaitingConnect,
+
+    /// The connection has completed its CONNECT. Any further CONNECT is a protocol error.
+    Connected,
+}
+
+impl ConnectionPhase {
+    /// Checks that the given packet may be received by a server while the connection is in this phase.
+    ///
+    /// Returns the reason code that the connection should be disconnected with if it may not.
+    pub fn check_inbound<P>(self, packet: &Packet<P>) -> Result<(), DisconnectReasonCode>
+    where
+        P: BufferPool,
+    {
+        match (self, packet) {
+            (ConnectionPhase::AwaitingConnect, Packet::Connect(_) | Packet::Auth(_)) => Ok(()),
+
+            (ConnectionPhase::AwaitingConnect, _)
+            | (ConnectionPhase::Connected, Packet::Connect(_)) => {
+                Err(DisconnectReasonCode::ProtocolError)
+            }
+
+            (ConnectionPhase::Connected, _) => Ok(()),
+        }
+    }
+}
+
+/// Checks that a packet with the given remaining length does not exceed the given Maximum Packet Size,
+/// so that it can be rejected without being decoded.
+///
+/// The packet size includes the fixed header, ie the first byte and the encoded remaining length.
+/// The first byte itself is not taken as a parameter, since it is always one byte long whatever its value.
+///
+/// Ref: 3.1.2.11.4 Maximum Packet Size
+pub fn check_packet_size(
+    remaining_length: usize,
+    maximum_packet_size: usize,
+) -> Result<(), DisconnectReasonCode> {
+    match crate::packet_size(remaining_length) {
+        Some(packet_size) if packet_size <= maximum_packet_size => Ok(()),
+        _ => Err(DisconnectReasonCode::PacketTooLarge),
+    }
+}
+
+/// Checks that a topic alias received in a PUBLISH is allowed by the given Topic Alias Maximum
+/// that the receiver advertised. A maximum of zero means the receiver does not accept topic aliases at all.
+///
+/// Ref: 3.3.2.3.4 Topic Alias
+pub fn validate_topic_alias(alias: u16, maximum: u16) -> Result<(), DisconnectReasonCode> {
+    if alias == 0 || alias > maximum {
+        Err(DisconnectReasonCode::TopicAliasInvalid)
+    } else {
+        Ok(())
+    }
+}
+
+/// Clears the RETAIN flag of the given PUBLISH if the peer it is about to be sent to does not support retained messages,
+/// ie if it advertised Retain Available as `false`.
+///
+/// Ref: 3.2.2.3.5 Retain Available
+pub fn apply_retain_available<P>(publish: &mut Publish<P>, retain_available: bool)
+where
+    P: BufferPool,
+{
+    if !retain_available {
+        publish.retain = false;
+    }
+}
+
+/// Checks that the RETAIN flag of a received PUBLISH is allowed by the Retain Available that the receiver advertised.
+///
+/// Ref: 3.3.1.3 RETAIN
+pub fn validate_retain<P>(
+    publish: &Publish<P>,
+    retain_available: bool,
+) -> Result<(), DisconnectReasonCode>
+where
+    P: BufferPool,
+{
+    if publish.retain && !retain_available {
+        Err(DisconnectReasonCode::RetainNotSupported)
+    } else {
+        Ok(())
+    }
+}
+
 /// Decode the body (variable header + payload) of an MQTT packet.
 ///
 /// Ref: 2 MQTT Control Packet format
-pub fn decode<P>(first_byte: u8, mut body: Shared<P>) -> Result<Packet<P>, DecodeError>
+pub fn decode<P>(first_byte: u8, body: Shared<P>) -> Result<Packet<P>, DecodeError>
 where
     P: Clone + BufferPool,
 {
+    let ctx = DecodeContext::new(body.pool().clone());
+    decode_with_context(&ctx, first_byte, body)
+}
+
+/// Decode the body (variable header + payload) of an MQTT packet like [`decode`],
+/// and convert it to the given kind of packet.
+///
+/// Fails with [`DecodeError::UnexpectedPacketType`] if the packet is of a different kind,
+/// eg when a client expects a CONNACK in response to its CONNECT.
+pub fn decode_expect<T, P>(first_byte: u8, body: Shared<P>) -> Result<T, DecodeError>
+where
+    T: TryFrom<Packet<P>, Error = DecodeError>,
+    P: Clone + BufferPool,
+{
+    let packet = decode(first_byte, body)?;
+    packet.try_into()
+}
+
+/// Decode the body (variable header + payload) of an MQTT packet like [`decode`],
+/// subject to the limits of the given context.
+pub fn decode_with_context<P>(
+    ctx: &DecodeContext<P>,
+    first_byte: u8,
+    mut body: Shared<P>,
+) -> Result<Packet<P>, DecodeError>
+where
+    P: Clone + BufferPool,
+{
+    decode_inner(ctx, first_byte, &mut body)
+}
+
+/// Decode the body (variable header + payload) of an MQTT packet like [`decode_with_context`],
+/// and on failure also report how many bytes of the body had been consumed when decoding failed.
+///
+/// This is meant for debugging malformed packets. The offset is that of the start of the field that failed to decode,
+/// except that an error inside a property is reported at the end of the property block.
+pub fn decode_debug<P>(
+    ctx: &DecodeContext<P>,
+    first_byte: u8,
+    mut body: Shared<P>,
+) -> Result<Packet<P>, DecodeErrorAt>
+where
+    P: Clone + BufferPool,
+{
+    let body_len = body.len();
+    decode_inner(ctx, first_byte, &mut body).map_err(|error| DecodeErrorAt {
+        error,
+        offset: body_len - body.len(),
+    })
+}
+
+fn decode_inner<P>(
+    ctx: &DecodeContext<P>,
+    first_byte: u8,
+    body: &mut Shared<P>,
+) -> Result<Packet<P>, DecodeError>
+where
+    P: Clone + BufferPool,
+{
+    ctx.check_packet_size(first_byte, body.len())?;
+
     let packet_type = first_byte & 0xF0;
     let flags = first_byte & 0x0F;
 
     let packet = match (packet_type, flags) {
-        (Auth::<P>::PACKET_TYPE, 0) => Packet::Auth(Auth::decode(flags, &mut body)?),
+        (Auth::<P>::PACKET_TYPE, 0) => Packet::Auth(Auth::decode(flags, body, ctx)?),
 
-        (ConnAck::<P>::PACKET_TYPE, 0) => Packet::ConnAck(ConnAck::decode(flags, &mut body)?),
+        (ConnAck::<P>::PACKET_TYPE, 0) => Packet::ConnAck(ConnAck::decode(flags, body, ctx)?),
 
-        (Connect::<P>::PACKET_TYPE, 0) => Packet::Connect(Connect::decode(flags, &mut body)?),
+        (Connect::<P>::PACKET_TYPE, 0) => Packet::Connect(Connect::decode(flags, body, ctx)?),
 
         (Disconnect::<P>::PACKET_TYPE, 0) => {
-            Packet::Disconnect(Disconnect::decode(flags, &mut body)?)
+            Packet::Disconnect(Disconnect::decode(flags, body, ctx)?)
         }
 
         (<PingReq as PacketMeta<P>>::PACKET_TYPE, 0) => {
-            Packet::PingReq(PingReq::decode(flags, &mut body)?)
+            Packet::PingReq(PingReq::decode(flags, body, ctx)?)
         }
 
         (<PingResp as PacketMeta<P>>::PACKET_TYPE, 0) => {
-            Packet::PingResp(PingResp::decode(flags, &mut body)?)
+            Packet::PingResp(PingResp::decode(flags, body, ctx)?)
         }
 
-        (PubAck::<P>::PACKET_TYPE, 0) => Packet::PubAck(PubAck::decode(flags, &mut body)?),
+        (PubAck::<P>::PACKET_TYPE, 0) => Packet::PubAck(PubAck::decode(flags, body, ctx)?),
 
-        (PubComp::<P>::PACKET_TYPE, 0) => Packet::PubComp(PubComp::decode(flags, &mut body)?),
+        (PubComp::<P>::PACKET_TYPE, 0) => Packet::PubComp(PubComp::decode(flags, body, ctx)?),
 
-        (Publish::<P>::PACKET_TYPE, flags) => Packet::Publish(Publish::decode(flags, &mut body)?),
+        (Publish::<P>::PACKET_TYPE, flags) => Packet::Publish(Publish::decode(flags, body, ctx)?),
 
-        (PubRec::<P>::PACKET_TYPE, 0) => Packet::PubRec(PubRec::decode(flags, &mut body)?),
+        (PubRec::<P>::PACKET_TYPE, 0) => Packet::PubRec(PubRec::decode(flags, body, ctx)?),
 
-        (PubRel::<P>::PACKET_TYPE, 2) => Packet::PubRel(PubRel::decode(flags, &mut body)?),
+        (PubRel::<P>::PACKET_TYPE, 2) => Packet::PubRel(PubRel::decode(flags, body, ctx)?),
 
-        (SubAck::<P>::PACKET_TYPE, 0) => Packet::SubAck(SubAck::decode(flags, &mut body)?),
+        (SubAck::<P>::PACKET_TYPE, 0) => Packet::SubAck(SubAck::decode(flags, body, ctx)?),
 
-        (Subscribe::<P>::PACKET_TYPE, 2) => Packet::Subscribe(Subscribe::decode(flags, &mut body)?),
+        (Subscribe::<P>::PACKET_TYPE, 2) => Packet::Subscribe(Subscribe::decode(flags, body, ctx)?),
 
-        (UnsubAck::<P>::PACKET_TYPE, 0) => Packet::UnsubAck(UnsubAck::decode(flags, &mut body)?),
+        (UnsubAck::<P>::PACKET_TYPE, 0) => Packet::UnsubAck(UnsubAck::decode(flags, body, ctx)?),
 
         (Unsubscribe::<P>::PACKET_TYPE, 2) => {
-            Packet::Unsubscribe(Unsubscribe::decode(flags, &mut body)?)
+            Packet::Unsubscribe(Unsubscribe::decode(flags, body, ctx)?)
         }
 
         (packet_type, flags) => {
@@ -203,65 +649,988 @@ where
     };
 
     if !body.is_empty() {
-        return Err(DecodeError::TrailingGarbage);
+        return Err(match packet {
+            Packet::PingReq(_) | Packet::PingResp(_) => {
+                DecodeError::FixedSizePacketTooLong { packet_type }
+            }
+            _ => DecodeError::TrailingGarbage,
+        });
     }
 
     Ok(packet)
 }
 
+/// Decode the body (variable header + payload) of an MQTT packet like [`decode`],
+/// and also return the original body so that it can be forwarded verbatim.
+pub fn decode_with_raw_body<P>(
+    first_byte: u8,
+    body: Shared<P>,
+) -> Result<DecodedPacket<Packet<P>, P>, DecodeError>
+where
+    P: Clone + BufferPool,
+{
+    let raw_body = body.clone();
+    let packet = decode(first_byte, body)?;
+    Ok(DecodedPacket {
+        packet,
+        first_byte,
+        raw_body,
+    })
+}
+
+/// Decode the body (variable header + payload) of a CONNECT like [`decode_with_context`],
+/// and also return the properties of its will, if it has one, exactly as they were decoded.
+///
+/// This lets eg a bridge forward the will without reordering its properties.
+pub fn decode_connect_with_raw_will_properties<P>(
+    ctx: &DecodeContext<P>,
+    mut body: Shared<P>,
+) -> Result<(Connect<P>, Option<Shared<P>>), DecodeError>
+where
+    P: Clone + BufferPool,
+{
+    ctx.check_packet_size(Connect::<P>::PACKET_TYPE, body.len())?;
+
+    let result = Connect::decode_with_raw_will_properties(0, &mut body, ctx)?;
+
+    if !body.is_empty() {
+        return Err(DecodeError::TrailingGarbage);
+    }
+
+    Ok(result)
+}
+
+/// The limits a peer has advertised for the packets it is willing to receive.
+///
+/// The default value does not restrict anything, ie encoding with it is equivalent to [`encode`].
+///
+/// Ref: 3.1.2.11 CONNECT Properties, 3.2.2.3 CONNACK Properties
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct EncodeContext {
+    /// The maximum size of a packet, including its fixed header.
+    pub maximum_packet_size: Option<usize>,
+
+    /// The highest topic alias the peer accepts. Zero means the peer does not accept topic aliases.
+    pub topic_alias_maximum: u16,
+
+    /// The highest quality of service of PUBLISH packets the peer accepts.
+    pub maximum_qos: QoS,
+
+    /// Whether the peer accepts PUBLISH packets with the retain flag set.
+    pub retain_available: bool,
+}
+
+impl Default for EncodeContext {
+    fn default() -> Self {
+        EncodeContext {
+            maximum_packet_size: None,
+            topic_alias_maximum: u16::MAX,
+            maximum_qos: QoS::ExactlyOnce,
+            retain_available: true,
+        }
+    }
+}
+
+impl EncodeContext {
+    /// Checks the size of a packet with the given body length against [`EncodeContext::maximum_packet_size`]
+    fn check_packet_size(&self, body_len: usize) -> Result<(), EncodeError> {
+        if let Some(maximum_packet_size) = self.maximum_packet_size {
+            let packet_size = crate::packet_size(body_len)
+                .ok_or(EncodeError::RemainingLengthTooHigh(body_len))?;
+            if packet_size > maximum_packet_size {
+                return Err(EncodeError::PacketTooLarge(packet_size));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 pub fn encode<B, P>(item: Packet<P>, dst: &mut B) -> Result<(), EncodeError>
 where
     B: ByteBuf,
     P: Clone + BufferPool,
 {
-    fn encode_inner<B, P, TPacket>(
+    encode_with_context(&Default::default(), item, dst)
+}
+
+/// Encode an MQTT packet like [`encode`], failing without writing anything if the packet
+/// violates the limits of the given context.
+pub fn encode_with_context<B, P>(
+    ctx: &EncodeContext,
+    item: Packet<P>,
+    dst: &mut B,
+) -> Result<(), EncodeError>
+where
+    B: ByteBuf,
+    P: Clone + BufferPool,
+{
+    encode_packet::<CountedFraming, _, _>(ctx, item, dst)
+}
+
+/// Encode an MQTT packet like [`encode`], into a new `Vec`.
+///
+/// This is convenient for tests and simple clients. Encoding into a reused buffer avoids allocating for every packet.
+pub fn encode_to_vec<P>(item: Packet<P>) -> Result<Vec<u8>, EncodeError>
+where
+    P: Clone + BufferPool,
+{
+    let mut dst = vec![];
+    encode(item, &mut dst)?;
+    Ok(dst)
+}
+
+/// Encode an MQTT packet like [`encode`], but without first computing the length of its body.
+///
+/// This reserves room for the longest possible fixed header (five bytes) at the end of `dst`, encodes the body after it,
+/// and then writes the fixed header in front of the body once its length is known.
+/// If the fixed header turns out to be shorter, the unused reserved bytes are dropped from the start of `dst` when it was empty,
+/// or else the body is shifted back over them.
+///
+/// This saves encoding the packet a second time to count its bytes, which [`encode`] does for most packets,
+/// at the cost of needing up to four bytes more room in `dst` than the packet takes and of possibly moving the body
+/// within `dst`. So it is most useful for large packets encoded into an empty buffer.
+///
+/// Like [`encode_with_context`], the packet must not violate the limits of the given context.
+/// Since those can only be checked once the body has been encoded, `dst` is truncated back to its original length on failure.
+pub fn encode_framed<P, Q>(
+    ctx: &EncodeContext,
+    item: Packet<P>,
+    dst: &mut Owned<Q>,
+) -> Result<(), EncodeError>
+where
+    P: Clone + BufferPool,
+    Q: BufferPool,
+{
+    encode_packet::<BackfilledFraming, _, _>(ctx, item, dst)
+}
+
+/// How the fixed header of a packet is put in front of its body
+trait Framing<B> {
+    fn encode<P, TPacket>(
+        ctx: &EncodeContext,
+        packet: TPacket,
+        flags: u8,
+        dst: &mut B,
+    ) -> Result<(), EncodeError>
+    where
+        P: Clone + BufferPool,
+        TPacket: PacketMeta<P>;
+}
+
+/// Computes the length of the body before writing the fixed header and then the body.
+struct CountedFraming;
+
+impl<B> Framing<B> for CountedFraming
+where
+    B: ByteBuf,
+{
+    fn encode<P, TPacket>(
+        ctx: &EncodeContext,
         packet: TPacket,
         flags: u8,
         dst: &mut B,
     ) -> Result<(), EncodeError>
     where
-        B: ByteBuf,
         P: Clone + BufferPool,
         TPacket: PacketMeta<P>,
     {
-        let mut counter: ByteCounter = Default::default();
-        packet.clone().encode(&mut counter)?;
-        let body_len = counter.0;
+        let body_len = if let Some(body_len) = packet.encoded_len() {
+            body_len
+        } else {
+            let mut counter: ByteCounter = Default::default();
+            packet.clone().encode(&mut counter)?;
+            counter.0
+        };
+
+        ctx.check_packet_size(body_len)?;
 
         dst.try_put_u8(TPacket::PACKET_TYPE | flags)?;
+
+        // Packets with an empty body like PINGREQ are just the packet type and a zero remaining length
+        if body_len == 0 {
+            dst.try_put_u8(0x00)?;
+            return Ok(());
+        }
+
         encode_remaining_length(body_len, dst)?;
         packet.encode(dst)?;
 
         Ok(())
     }
+}
+
+/// Writes the body after room reserved for the fixed header, then backfills the fixed header. See [`encode_framed`]
+struct BackfilledFraming;
+
+// One byte for the packet type and up to four bytes for the remaining length
+const MAX_FIXED_HEADER_LEN: usize = 5;
+
+/// Encodes the body of the packet after the fixed header reserved at `start`, and returns the fixed header for it.
+fn encode_body<P, TPacket, Q>(
+    ctx: &EncodeContext,
+    packet: TPacket,
+    flags: u8,
+    dst: &mut Owned<Q>,
+    start: usize,
+) -> Result<FixedHeader, EncodeError>
+where
+    P: Clone + BufferPool,
+    TPacket: PacketMeta<P>,
+    Q: BufferPool,
+{
+    packet.encode(dst)?;
+    let body_len = dst.filled_len() - start - MAX_FIXED_HEADER_LEN;
+    ctx.check_packet_size(body_len)?;
+
+    let mut fixed_header = FixedHeader::default();
+    fixed_header.try_put_u8(TPacket::PACKET_TYPE | flags)?;
+    encode_remaining_length(body_len, &mut fixed_header)?;
+    Ok(fixed_header)
+}
+
+impl<Q> Framing<Owned<Q>> for BackfilledFraming
+where
+    Q: BufferPool,
+{
+    fn encode<P, TPacket>(
+        ctx: &EncodeContext,
+        packet: TPacket,
+        flags: u8,
+        dst: &mut Owned<Q>,
+    ) -> Result<(), EncodeError>
+    where
+        P: Clone + BufferPool,
+        TPacket: PacketMeta<P>,
+    {
+        let start = dst.filled_len();
+        if dst.unfilled().len() < MAX_FIXED_HEADER_LEN {
+            return Err(EncodeError::InsufficientBuffer);
+        }
+        dst.fill(MAX_FIXED_HEADER_LEN);
+
+        let fixed_header = match encode_body(ctx, packet, flags, dst, start) {
+            Ok(fixed_header) => fixed_header,
+            Err(err) => {
+                let written = dst.filled_len() - start;
+                dst.unfill(written);
+                return Err(err);
+            }
+        };
+        let fixed_header = &fixed_header.bytes[..fixed_header.len];
+        let unused = MAX_FIXED_HEADER_LEN - fixed_header.len();
+
+        dst.filled_mut()[(start + unused)..(start + MAX_FIXED_HEADER_LEN)]
+            .copy_from_slice(fixed_header);
+
+        if start == 0 {
+            dst.drain(unused);
+        } else {
+            let filled_len = dst.filled_len();
+            dst.filled_mut()
+                .copy_within((start + unused)..filled_len, start);
+            dst.unfill(unused);
+        }
+
+        Ok(())
+    }
+}
+
+/// A buffer for the bytes of a fixed header
+#[derive(Default)]
+struct FixedHeader {
+    bytes: [u8; 5],
+    len: usize,
+}
+
+impl ByteBuf for FixedHeader {
+    fn try_put_slice(&mut self, src: &[u8]) -> Result<(), EncodeError> {
+        let dst = self
+            .bytes
+            .get_mut(self.len..(self.len + src.len()))
+            .ok_or(EncodeError::InsufficientBuffer)?;
+        dst.copy_from_slice(src);
+        self.len += src.len();
+        Ok(())
+    }
+}
 
+fn encode_packet<F, B, P>(
+    ctx: &EncodeContext,
+    item: Packet<P>,
+    dst: &mut B,
+) -> Result<(), EncodeError>
+where
+    F: Framing<B>,
+    P: Clone + BufferPool,
+{
     match item {
-        Packet::Auth(packet) => encode_inner::<_, P, _>(packet, 0, dst),
-        Packet::ConnAck(packet) => encode_inner::<_, P, _>(packet, 0, dst),
-        Packet::Connect(packet) => encode_inner(packet, 0, dst),
-        Packet::Disconnect(packet) => encode_inner::<_, P, _>(packet, 0, dst),
-        Packet::PingReq(packet) => encode_inner::<_, P, _>(packet, 0, dst),
-        Packet::PingResp(packet) => encode_inner::<_, P, _>(packet, 0, dst),
-        Packet::PubAck(packet) => encode_inner::<_, P, _>(packet, 0, dst),
-        Packet::PubComp(packet) => encode_inner::<_, P, _>(packet, 0, dst),
+        Packet::Auth(packet) => F::encode::<P, _>(ctx, packet, 0, dst),
+        Packet::ConnAck(packet) => F::encode::<P, _>(ctx, packet, 0, dst),
+        Packet::Connect(packet) => F::encode::<P, _>(ctx, packet, 0, dst),
+        Packet::Disconnect(packet) => F::encode::<P, _>(ctx, packet, 0, dst),
+        Packet::PingReq(packet) => F::encode::<P, _>(ctx, packet, 0, dst),
+        Packet::PingResp(packet) => F::encode::<P, _>(ctx, packet, 0, dst),
+        Packet::PubAck(packet) => F::encode::<P, _>(ctx, packet, 0, dst),
+        Packet::PubComp(packet) => F::encode::<P, _>(ctx, packet, 0, dst),
         Packet::Publish(packet) => {
-            let mut flags = match packet.packet_identifier_dup_qos {
-                PacketIdentifierDupQoS::AtMostOnce => 0x00,
-                PacketIdentifierDupQoS::AtLeastOnce(_, true) => 0x0A,
-                PacketIdentifierDupQoS::AtLeastOnce(_, false) => 0x02,
-                PacketIdentifierDupQoS::ExactlyOnce(_, true) => 0x0C,
-                PacketIdentifierDupQoS::ExactlyOnce(_, false) => 0x04,
+            let (mut flags, qos) = match packet.packet_identifier_dup_qos {
+                PacketIdentifierDupQoS::AtMostOnce => (0x00, QoS::AtMostOnce),
+                PacketIdentifierDupQoS::AtLeastOnce(_, true) => (0x0A, QoS::AtLeastOnce),
+                PacketIdentifierDupQoS::AtLeastOnce(_, false) => (0x02, QoS::AtLeastOnce),
+                PacketIdentifierDupQoS::ExactlyOnce(_, true) => (0x0C, QoS::ExactlyOnce),
+                PacketIdentifierDupQoS::ExactlyOnce(_, false) => (0x04, QoS::ExactlyOnce),
             };
+            if u8::from(qos) > u8::from(ctx.maximum_qos) {
+                return Err(EncodeError::QoSNotSupported(qos));
+            }
             if packet.retain {
+                if !ctx.retain_available {
+                    return Err(EncodeError::RetainNotSupported);
+                }
                 flags |= 0x01;
             };
-            encode_inner(packet, flags, dst)
-        }
-        Packet::PubRec(packet) => encode_inner::<_, P, _>(packet, 0, dst),
-        Packet::PubRel(packet) => encode_inner::<_, P, _>(packet, 0x02, dst),
-        Packet::SubAck(packet) => encode_inner::<_, P, _>(packet, 0, dst),
-        Packet::Subscribe(packet) => encode_inner(packet, 0x02, dst),
-        Packet::UnsubAck(packet) => encode_inner::<_, P, _>(packet, 0, dst),
-        Packet::Unsubscribe(packet) => encode_inner(packet, 0x02, dst),
+            if let Some(topic_alias) = packet.topic_alias {
+                if topic_alias > ctx.topic_alias_maximum {
+                    return Err(EncodeError::InvalidTopicAlias(topic_alias));
+                }
+            }
+            F::encode::<P, _>(ctx, packet, flags, dst)
+        }
+        Packet::PubRec(packet) => F::encode::<P, _>(ctx, packet, 0, dst),
+        Packet::PubRel(packet) => F::encode::<P, _>(ctx, packet, 0x02, dst),
+        Packet::SubAck(packet) => F::encode::<P, _>(ctx, packet, 0, dst),
+        Packet::Subscribe(packet) => F::encode::<P, _>(ctx, packet, 0x02, dst),
+        Packet::UnsubAck(packet) => F::encode::<P, _>(ctx, packet, 0, dst),
+        Packet::Unsubscribe(packet) => F::encode::<P, _>(ctx, packet, 0x02, dst),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{
+        check_packet_size, Auth, AuthenticateReasonCode, ConnAck, ConnectReasonCode,
+        ConnectionPhase, ConnectionRefusedReason, Disconnect, DisconnectReasonCode, EncodeContext,
+        Packet, PacketIdentifierDupQoS, PacketMetricsKey, PacketType, PingReq, PubAck,
+        PubAckReasonCode, PubComp, PubCompReasonCode, PubRec, PubRecReasonCode, PubRel,
+        PubRelReasonCode, Publish, ServerCapabilities,
+    };
+    use crate::tests::{byte_str, shared, TestBufferPool};
+    use crate::{DecodeContext, DecodeError, EncodeError, Owned, PacketIdentifier, QoS};
+
+    pub(crate) fn publish(retain: bool) -> Publish<TestBufferPool> {
+        Publish {
+            topic_name: byte_str("foo"),
+            packet_identifier_dup_qos: PacketIdentifierDupQoS::AtMostOnce,
+            retain,
+            payload_is_utf8: false,
+            message_expiry_interval: None,
+            topic_alias: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: vec![],
+            subscription_identifiers: vec![],
+            content_type: None,
+            payload: shared(b"hello"),
+        }
+    }
+
+    shared_publish_tests!();
+
+    #[test]
+    fn fixed_size_packet_too_long() {
+        match super::decode::<TestBufferPool>(0xC0, shared(b"\x00")) {
+            Err(DecodeError::FixedSizePacketTooLong { packet_type: 0xC0 }) => (),
+            result => panic!("{:?}", result),
+        }
+
+        match super::decode::<TestBufferPool>(0xD0, shared(b"\x00\x01")) {
+            Err(DecodeError::FixedSizePacketTooLong { packet_type: 0xD0 }) => (),
+            result => panic!("{:?}", result),
+        }
+
+        // Other packets still fail with the generic error
+        match super::decode::<TestBufferPool>(0x40, shared(b"\x00\x01\x00\x00\xFF")) {
+            Err(DecodeError::TrailingGarbage) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn metrics_key() {
+        let packet = Packet::Publish(Publish {
+            packet_identifier_dup_qos: PacketIdentifierDupQoS::AtLeastOnce(
+                PacketIdentifier::new(1).unwrap(),
+                false,
+            ),
+            ..publish(true)
+        });
+        assert_eq!(
+            packet.metrics_key(),
+            PacketMetricsKey {
+                packet_type: PacketType::Publish,
+                qos: Some(QoS::AtLeastOnce),
+                retain: true,
+            }
+        );
+
+        let packet: Packet<TestBufferPool> = Packet::PingReq(PingReq);
+        assert_eq!(
+            packet.metrics_key(),
+            PacketMetricsKey {
+                packet_type: PacketType::PingReq,
+                qos: None,
+                retain: false,
+            }
+        );
+    }
+
+    #[test]
+    fn apply_retain_available() {
+        let mut packet = publish(true);
+        super::apply_retain_available(&mut packet, true);
+        assert!(packet.retain);
+
+        super::apply_retain_available(&mut packet, false);
+        assert!(!packet.retain);
+        assert_eq!(packet, publish(false));
+    }
+
+    #[test]
+    fn validate_retain() {
+        assert_eq!(super::validate_retain(&publish(true), true), Ok(()));
+        assert_eq!(super::validate_retain(&publish(false), true), Ok(()));
+        assert_eq!(super::validate_retain(&publish(false), false), Ok(()));
+        assert_eq!(
+            super::validate_retain(&publish(true), false),
+            Err(DisconnectReasonCode::RetainNotSupported)
+        );
+    }
+
+    #[test]
+    fn connection_phase() {
+        let connect = Packet::Connect(crate::v5::connect::tests::connect(
+            crate::ClientId::ServerGenerated,
+        ));
+        let auth = Packet::Auth(Auth {
+            reason_code: AuthenticateReasonCode::ContinueAuthentication,
+            authentication_method: Some(byte_str("SCRAM-SHA-1")),
+            authentication_data: None,
+            reason_string: None,
+            user_properties: vec![],
+        });
+        let publish = Packet::Publish(publish(false));
+
+        let phase = ConnectionPhase::AwaitingConnect;
+        assert_eq!(phase.check_inbound(&connect), Ok(()));
+        assert_eq!(phase.check_inbound(&auth), Ok(()));
+        assert_eq!(
+            phase.check_inbound(&publish),
+            Err(DisconnectReasonCode::ProtocolError),
+        );
+
+        let phase = ConnectionPhase::Connected;
+        assert_eq!(
+            phase.check_inbound(&connect),
+            Err(DisconnectReasonCode::ProtocolError),
+        );
+        assert_eq!(phase.check_inbound(&auth), Ok(()));
+        assert_eq!(phase.check_inbound(&publish), Ok(()));
+    }
+
+    #[test]
+    fn encode_framed() {
+        let pool = TestBufferPool;
+
+        let mut large = publish(false);
+        large.payload = shared(&[0x55; 200]);
+
+        for packet in [
+            Packet::PingReq(PingReq),
+            Packet::Publish(publish(true)),
+            Packet::Publish(large),
+        ] {
+            let mut expected = Owned::new(pool, pool.take(256));
+            super::encode(packet.clone(), &mut expected).unwrap();
+
+            let mut actual = Owned::new(pool, pool.take(256));
+            super::encode_framed(&Default::default(), packet.clone(), &mut actual).unwrap();
+            assert_eq!(actual.filled(), expected.filled());
+
+            // Already-filled bytes in front of the packet are kept
+            let mut actual = Owned::new(pool, pool.take(256));
+            crate::ByteBuf::try_put_slice(&mut actual, b"xy").unwrap();
+            super::encode_framed(&Default::default(), packet, &mut actual).unwrap();
+            assert_eq!(&actual.filled()[..2], b"xy");
+            assert_eq!(&actual.filled()[2..], expected.filled());
+        }
+    }
+
+    #[test]
+    fn encode_framed_failure_truncates() {
+        let pool = TestBufferPool;
+        let ctx = EncodeContext {
+            maximum_packet_size: Some(8),
+            ..Default::default()
+        };
+
+        let mut dst = Owned::new(pool, pool.take(256));
+        crate::ByteBuf::try_put_slice(&mut dst, b"xy").unwrap();
+        match super::encode_framed(&ctx, Packet::Publish(publish(false)), &mut dst) {
+            Err(EncodeError::PacketTooLarge(_)) => (),
+            result => panic!("{:?}", result),
+        }
+        assert_eq!(dst.filled(), b"xy");
+
+        // Running out of room partway through the body
+        let mut dst = Owned::new(pool, pool.take(8));
+        match super::encode_framed(
+            &Default::default(),
+            Packet::Publish(publish(false)),
+            &mut dst,
+        ) {
+            Err(EncodeError::InsufficientBuffer) => (),
+            result => panic!("{:?}", result),
+        }
+        assert!(dst.filled().is_empty());
+    }
+
+    #[test]
+    fn encode_into_hasher() {
+        // 64-bit FNV-1a, so that the hash of a packet is the same for every build
+        struct Fnv1a(u64);
+
+        impl std::hash::Hasher for Fnv1a {
+            fn write(&mut self, bytes: &[u8]) {
+                for &b in bytes {
+                    self.0 = (self.0 ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01B3);
+                }
+            }
+
+            fn finish(&self) -> u64 {
+                self.0
+            }
+        }
+
+        fn hash(packet: Packet<TestBufferPool>) -> u64 {
+            let mut hasher = crate::Hasher(Fnv1a(0xCBF2_9CE4_8422_2325));
+            super::encode(packet, &mut hasher).unwrap();
+            std::hash::Hasher::finish(&hasher.0)
+        }
+
+        // The hash of the encoded bytes, regardless of how encoding splits them up
+        let bytes = super::encode_to_vec(Packet::Publish(publish(true))).unwrap();
+        let mut expected = Fnv1a(0xCBF2_9CE4_8422_2325);
+        std::hash::Hasher::write(&mut expected, &bytes);
+        assert_eq!(hash(Packet::Publish(publish(true))), expected.0);
+
+        assert_eq!(
+            hash(Packet::Publish(publish(true))),
+            hash(Packet::Publish(publish(true))),
+        );
+        assert_ne!(
+            hash(Packet::Publish(publish(true))),
+            hash(Packet::Publish(publish(false))),
+        );
+    }
+
+    #[test]
+    fn packet_size() {
+        // 1 byte for the packet type + 1 byte for the remaining length
+        assert_eq!(check_packet_size(127, 129), Ok(()));
+        assert_eq!(
+            check_packet_size(127, 128),
+            Err(DisconnectReasonCode::PacketTooLarge)
+        );
+
+        // 1 byte for the packet type + 2 bytes for the remaining length
+        assert_eq!(check_packet_size(128, 131), Ok(()));
+        assert_eq!(
+            check_packet_size(128, 130),
+            Err(DisconnectReasonCode::PacketTooLarge)
+        );
+
+        assert_eq!(
+            check_packet_size(0x1000_0000, usize::MAX),
+            Err(DisconnectReasonCode::PacketTooLarge)
+        );
+    }
+
+    #[test]
+    fn validate_topic_alias() {
+        assert_eq!(super::validate_topic_alias(1, 10), Ok(()));
+        assert_eq!(super::validate_topic_alias(10, 10), Ok(()));
+        assert_eq!(
+            super::validate_topic_alias(11, 10),
+            Err(DisconnectReasonCode::TopicAliasInvalid)
+        );
+        assert_eq!(
+            super::validate_topic_alias(0, 10),
+            Err(DisconnectReasonCode::TopicAliasInvalid)
+        );
+        assert_eq!(
+            super::validate_topic_alias(1, 0),
+            Err(DisconnectReasonCode::TopicAliasInvalid)
+        );
+    }
+
+    #[test]
+    fn decode_with_context_maximum_properties() {
+        // PUBACK with a reason string and a user property
+        let body = b"\
+            \x00\x01\
+            \x10\
+            \x0D\
+            \x1F\x00\x03foo\
+            \x26\x00\x01a\x00\x01b\
+        ";
+
+        let mut ctx = DecodeContext::new(TestBufferPool);
+        ctx.maximum_properties = Some(1);
+        match super::decode_with_context(&ctx, 0x40, shared(body)) {
+            Err(DecodeError::TooManyProperties(1)) => (),
+            result => panic!("{:?}", result),
+        }
+
+        ctx.maximum_properties = Some(2);
+        let packet = super::decode_with_context(&ctx, 0x40, shared(body)).unwrap();
+        assert!(matches!(packet, Packet::PubAck(_)));
+
+        ctx.maximum_packet_size = Some(body.len() + 1);
+        match super::decode_with_context(&ctx, 0x40, shared(body)) {
+            Err(DecodeError::PacketTooLarge {
+                packet_type: 0x40,
+                remaining_length,
+            }) => assert_eq!(remaining_length, body.len()),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn encode_with_context_maximum_packet_size() {
+        // 1 byte for the packet type + 1 byte for the remaining length + 5 bytes of topic name + 1 byte of properties + 5 bytes of payload
+        let mut ctx = EncodeContext::default();
+
+        let pool = TestBufferPool;
+
+        ctx.maximum_packet_size = Some(13);
+        let mut bytes = Owned::new(pool, pool.take(32));
+        super::encode_with_context(&ctx, Packet::Publish(publish(false)), &mut bytes).unwrap();
+        assert_eq!(bytes.filled().len(), 13);
+
+        ctx.maximum_packet_size = Some(12);
+        let mut bytes = Owned::new(pool, pool.take(32));
+        match super::encode_with_context(&ctx, Packet::Publish(publish(false)), &mut bytes) {
+            Err(EncodeError::PacketTooLarge(13)) => (),
+            result => panic!("{:?}", result),
+        }
+        assert!(bytes.filled().is_empty());
+    }
+
+    #[test]
+    fn encode_with_context_retain_not_available() {
+        let ctx = EncodeContext {
+            retain_available: false,
+            ..Default::default()
+        };
+
+        let pool = TestBufferPool;
+
+        let mut bytes = Owned::new(pool, pool.take(32));
+        match super::encode_with_context(&ctx, Packet::Publish(publish(true)), &mut bytes) {
+            Err(EncodeError::RetainNotSupported) => (),
+            result => panic!("{:?}", result),
+        }
+        assert!(bytes.filled().is_empty());
+
+        let mut bytes = Owned::new(pool, pool.take(32));
+        super::encode_with_context(&ctx, Packet::Publish(publish(false)), &mut bytes).unwrap();
+    }
+
+    #[test]
+    fn puback_family_roundtrip() {
+        fn roundtrip(packet: &Packet<TestBufferPool>, expected: &[u8]) {
+            let pool = TestBufferPool;
+            let mut bytes = Owned::new(pool, pool.take(32));
+            super::encode(packet.clone(), &mut bytes).unwrap();
+            assert_eq!(bytes.filled(), expected);
+
+            let body = shared(&bytes.filled()[2..]);
+            let decoded = super::decode(bytes.filled()[0], body).unwrap();
+            assert_eq!(&decoded, packet);
+        }
+
+        let packet_identifier = PacketIdentifier::new(1).unwrap();
+
+        roundtrip(
+            &Packet::PubAck(PubAck {
+                packet_identifier,
+                reason_code: PubAckReasonCode::Success,
+                reason_string: None,
+                user_properties: vec![],
+            }),
+            b"\x40\x02\x00\x01",
+        );
+        roundtrip(
+            &Packet::PubAck(PubAck {
+                packet_identifier,
+                reason_code: PubAckReasonCode::Success,
+                reason_string: Some(byte_str("foo")),
+                user_properties: vec![],
+            }),
+            b"\x40\x0A\x00\x01\x00\x06\x1F\x00\x03foo",
+        );
+
+        roundtrip(
+            &Packet::PubRec(PubRec {
+                packet_identifier,
+                reason_code: PubRecReasonCode::Success,
+                reason_string: None,
+                user_properties: vec![],
+            }),
+            b"\x50\x02\x00\x01",
+        );
+        roundtrip(
+            &Packet::PubRec(PubRec {
+                packet_identifier,
+                reason_code: PubRecReasonCode::NoMatchingSubscribers,
+                reason_string: None,
+                user_properties: vec![],
+            }),
+            b"\x50\x04\x00\x01\x10\x00",
+        );
+
+        roundtrip(
+            &Packet::PubRel(PubRel {
+                packet_identifier,
+                reason_code: PubRelReasonCode::Success,
+                reason_string: None,
+                user_properties: vec![],
+            }),
+            b"\x62\x02\x00\x01",
+        );
+        roundtrip(
+            &Packet::PubRel(PubRel {
+                packet_identifier,
+                reason_code: PubRelReasonCode::Success,
+                reason_string: None,
+                user_properties: vec![(byte_str("a"), byte_str("b"))],
+            }),
+            b"\x62\x0B\x00\x01\x00\x07\x26\x00\x01a\x00\x01b",
+        );
+
+        roundtrip(
+            &Packet::PubComp(PubComp {
+                packet_identifier,
+                reason_code: PubCompReasonCode::Success,
+                reason_string: None,
+                user_properties: vec![],
+            }),
+            b"\x70\x02\x00\x01",
+        );
+        roundtrip(
+            &Packet::PubComp(PubComp {
+                packet_identifier,
+                reason_code: PubCompReasonCode::PacketIdentifierNotFound,
+                reason_string: None,
+                user_properties: vec![],
+            }),
+            b"\x70\x04\x00\x01\x92\x00",
+        );
+    }
+
+    #[test]
+    fn packet_identifier() {
+        for (first_byte, body, expected) in [
+            (0x40, &b"\x00\x01"[..], Some(1)),
+            (0x50, b"\x00\x02", Some(2)),
+            (0x62, b"\x00\x03", Some(3)),
+            (0x70, b"\x00\x04", Some(4)),
+            (0x90, b"\x00\x05\x00\x00", Some(5)),
+            (0xB0, b"\x00\x06\x00\x00", Some(6)),
+            (0x82, b"\x00\x07\x00\x00\x01a\x00", Some(7)),
+            (0xA2, b"\x00\x08\x00\x00\x01a", Some(8)),
+            (0x32, b"\x00\x01a\x00\x09\x00", Some(9)),
+            (0x30, b"\x00\x01a\x00", None),
+            (0xC0, b"", None),
+            (0xE0, b"", None),
+            (0xF0, b"", None),
+        ] {
+            let packet = super::decode(first_byte, shared(body)).unwrap();
+            assert_eq!(
+                packet.packet_identifier(),
+                expected.map(|id| PacketIdentifier::new(id).unwrap()),
+                "{packet:?}",
+            );
+        }
+    }
+
+    #[test]
+    fn closes_connection() {
+        let connack = |return_code| {
+            Packet::ConnAck(ConnAck::from_capabilities(
+                return_code,
+                &ServerCapabilities::default(),
+            ))
+        };
+
+        let disconnect = |reason_code| Packet::Disconnect(Disconnect::new(reason_code));
+
+        let cases: Vec<(Packet<TestBufferPool>, bool)> = vec![
+            (disconnect(DisconnectReasonCode::Normal), true),
+            (disconnect(DisconnectReasonCode::ServerShuttingDown), true),
+            (
+                connack(ConnectReasonCode::Refused(
+                    ConnectionRefusedReason::NotAuthorized,
+                )),
+                true,
+            ),
+            (
+                connack(ConnectReasonCode::Success {
+                    session_present: true,
+                }),
+                false,
+            ),
+            (
+                Packet::Auth(Auth {
+                    reason_code: AuthenticateReasonCode::ContinueAuthentication,
+                    authentication_method: Some(byte_str("SCRAM-SHA-1")),
+                    authentication_data: None,
+                    reason_string: None,
+                    user_properties: vec![],
+                }),
+                false,
+            ),
+            (Packet::PingReq(PingReq), false),
+            (Packet::Publish(publish(false)), false),
+        ];
+
+        for (packet, expected) in cases {
+            assert_eq!(packet.closes_connection(), expected, "{packet:?}");
+        }
+    }
+
+    #[test]
+    fn decode_with_context_duplicate_properties() {
+        // DISCONNECT with the session expiry interval property twice
+        let identical = b"\
+            \x00\
+            \x0A\
+            \x11\x00\x00\x00\x1E\
+            \x11\x00\x00\x00\x1E\
+        ";
+        let differing = b"\
+            \x00\
+            \x0A\
+            \x11\x00\x00\x00\x1E\
+            \x11\x00\x00\x00\x3C\
+        ";
+
+        let mut ctx = DecodeContext::new(TestBufferPool);
+
+        match super::decode_with_context(&ctx, 0xE0, shared(identical)) {
+            Err(DecodeError::DuplicateProperty("SessionExpiryInterval")) => (),
+            result => panic!("{:?}", result),
+        }
+        match super::decode_with_context(&ctx, 0xE0, shared(differing)) {
+            Err(DecodeError::DuplicateProperty("SessionExpiryInterval")) => (),
+            result => panic!("{:?}", result),
+        }
+
+        ctx.allow_identical_duplicate_properties = true;
+
+        let single = b"\x00\x05\x11\x00\x00\x00\x1E";
+        assert_eq!(
+            super::decode_with_context(&ctx, 0xE0, shared(identical)).unwrap(),
+            super::decode_with_context(&ctx, 0xE0, shared(single)).unwrap(),
+        );
+        match super::decode_with_context(&ctx, 0xE0, shared(differing)) {
+            Err(DecodeError::ConflictingDuplicateProperty("SessionExpiryInterval")) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn encode_pingreq() {
+        let pool = TestBufferPool;
+
+        let mut bytes = Owned::new(pool, pool.take(2));
+        super::encode::<_, TestBufferPool>(Packet::PingReq(PingReq), &mut bytes).unwrap();
+        assert_eq!(bytes.filled(), [0xC0, 0x00]);
+    }
+
+    #[test]
+    fn publication_into_publish() {
+        let mut will = crate::v5::connect::tests::publication("will/topic", b"gone");
+        will.qos = QoS::AtMostOnce;
+        will.retain = true;
+
+        let publish = will.clone().into_publish(None).unwrap();
+        assert_eq!(
+            publish.packet_identifier_dup_qos,
+            PacketIdentifierDupQoS::AtMostOnce
+        );
+        assert_eq!(publish.topic_name, will.topic_name);
+        assert!(publish.retain);
+        assert_eq!(publish.payload, will.payload);
+
+        will.qos = QoS::AtLeastOnce;
+        match will.clone().into_publish(None) {
+            Err(EncodeError::MissingPacketIdentifier(QoS::AtLeastOnce)) => (),
+            result => panic!("{:?}", result),
+        }
+
+        let packet_identifier = PacketIdentifier::new(5).unwrap();
+        let publish = will.into_publish(Some(packet_identifier)).unwrap();
+        assert_eq!(
+            publish.packet_identifier_dup_qos,
+            PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, false)
+        );
+    }
+
+    #[test]
+    fn decode_expect() {
+        let body = b"\x00\x0Bfoo/bar/baz\x00hello";
+
+        let publish: Publish<TestBufferPool> = super::decode_expect(0x30, shared(body)).unwrap();
+        assert_eq!(publish.topic_name, "foo/bar/baz");
+
+        match super::decode_expect::<ConnAck<_>, _>(0x30, shared(body)) {
+            Err(DecodeError::UnexpectedPacketType {
+                expected: 0x20,
+                got: 0x30,
+            }) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn property_length_required() {
+        for &(first_byte, body) in &[
+            (0x30_u8, &b"\x00\x03a/b"[..]),
+            (0x20, b"\x00\x00"),
+            (0x90, b"\x00\x01"),
+            (0x10, b"\x00\x04MQTT\x05\x02\x00\x1E"),
+        ] {
+            match super::decode(first_byte, shared(body)) {
+                Err(DecodeError::IncompletePacket) => (),
+                result => panic!("{:#04X}: {:?}", first_byte, result),
+            }
+        }
+
+        // Only these may end after the reason code
+        for &(first_byte, body) in &[
+            (0x40_u8, &b"\x00\x01\x10"[..]),
+            (0x50, b"\x00\x01\x10"),
+            (0x62, b"\x00\x01\x92"),
+            (0x70, b"\x00\x01\x92"),
+        ] {
+            super::decode(first_byte, shared(body)).unwrap();
+        }
+        super::decode(0xE0, shared(b"\x8E")).unwrap();
+        match super::decode(0xF0, shared(b"\x18")) {
+            Err(DecodeError::MissingRequiredProperty("authentication method")) => (),
+            result => panic!("{:?}", result),
+        }
     }
 }
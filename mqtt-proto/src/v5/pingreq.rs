@@ -1,7 +1,7 @@
 // Copyright (c) Microsoft. All rights reserved.
 
 use super::PacketMeta;
-use crate::{BufferPool, ByteBuf, DecodeError, EncodeError, Shared};
+use crate::{BufferPool, ByteBuf, DecodeContext, DecodeError, EncodeError, Shared};
 
 /// Ref: 3.12 PINGREQ – PING request
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -13,7 +13,11 @@ where
 {
     const PACKET_TYPE: u8 = 0xC0;
 
-    fn decode(_flags: u8, _src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        _src: &mut Shared<P>,
+        _ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         Ok(PingReq)
     }
 
@@ -23,4 +27,8 @@ where
     {
         Ok(())
     }
+
+    fn encoded_len(&self) -> Option<usize> {
+        Some(0)
+    }
 }
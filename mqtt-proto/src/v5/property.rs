@@ -5,7 +5,9 @@ use std::mem::size_of;
 use std::time::Duration;
 
 use super::{decode_remaining_length, encode_remaining_length};
-use crate::{BufferPool, ByteBuf, ByteCounter, ByteStr, DecodeError, EncodeError, QoS, Shared};
+use crate::{
+    BufferPool, ByteBuf, ByteCounter, ByteStr, DecodeContext, DecodeError, EncodeError, QoS, Shared,
+};
 
 /// Ref: 2.2.2.2 Property
 #[allow(clippy::enum_variant_names)] // clippy wants `UserProperty` to not end with `Property`
@@ -104,8 +106,29 @@ impl<P> Property<P>
 where
     P: BufferPool,
 {
+    /// Like [`Property::decode_all`], for the packets whose property length may be omitted when there are no properties.
+    /// An empty `src` is then the same as a property length of 0.
+    ///
+    /// Ref: 3.4.2.2.1 Property Length, 3.5.2.2.1 Property Length, 3.6.2.2.1 Property Length, 3.7.2.2.1 Property Length,
+    /// 3.14.2.2.1 Property Length, 3.15.2.2.1 Property Length
+    pub(super) fn decode_all_optional_length(
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<impl Iterator<Item = Result<Self, DecodeError>>, DecodeError>
+    where
+        P: Clone,
+    {
+        let properties = if src.is_empty() {
+            None
+        } else {
+            Some(Self::decode_all(src, ctx)?)
+        };
+        Ok(properties.into_iter().flatten())
+    }
+
     pub(super) fn decode_all(
         src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
     ) -> Result<impl Iterator<Item = Result<Self, DecodeError>>, DecodeError>
     where
         P: Clone,
@@ -115,6 +138,8 @@ where
             P: BufferPool,
         {
             src: Shared<P>,
+            num_decoded: usize,
+            maximum_properties: Option<usize>,
         }
 
         impl<P> Iterator for PropertyDecodeIter<P>
@@ -128,6 +153,15 @@ where
                     return None;
                 }
 
+                if let Some(maximum_properties) = self.maximum_properties {
+                    if self.num_decoded == maximum_properties {
+                        // Stop after this error instead of yielding it again for every remaining byte
+                        self.src.drain(self.src.len());
+                        return Some(Err(DecodeError::TooManyProperties(maximum_properties)));
+                    }
+                }
+                self.num_decoded += 1;
+
                 Some(Property::decode(&mut self.src))
             }
         }
@@ -142,12 +176,27 @@ where
         };
         src.drain(remaining_length_len);
 
+        if let Some(maximum_properties_len) = ctx.maximum_properties_len {
+            if remaining_length > maximum_properties_len {
+                return Err(DecodeError::PropertiesTooLarge(remaining_length));
+            }
+        }
+
+        // The packet body has already been framed by its remaining length, so a property length that runs past the end of it
+        // is a malformed packet rather than a truncated one.
         if src.len() < remaining_length {
-            return Err(DecodeError::IncompletePacket);
+            return Err(DecodeError::PropertyLengthExceedsPacket {
+                property_length: remaining_length,
+                remaining_length: src.len(),
+            });
         }
         let src = src.split_to(remaining_length);
 
-        Ok(PropertyDecodeIter { src })
+        Ok(PropertyDecodeIter {
+            src,
+            num_decoded: 0,
+            maximum_properties: ctx.maximum_properties,
+        })
     }
 
     fn decode(src: &mut Shared<P>) -> Result<Self, DecodeError>
@@ -175,26 +224,17 @@ where
             }
 
             0x03 => {
-                let content_type = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
+                let content_type = ByteStr::decode_in_packet(src)?;
                 Property::ContentType(content_type)
             }
 
             0x08 => {
-                let response_topic = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
+                let response_topic = ByteStr::decode_in_packet(src)?;
                 Property::ResponseTopic(response_topic)
             }
 
             0x09 => {
-                let len: usize = match src.as_ref().get(..size_of::<u16>()) {
-                    Some(src) => u16::from_be_bytes(src.try_into().unwrap()).into(),
-                    None => return Err(DecodeError::IncompletePacket),
-                };
-
-                if src.len() < size_of::<u16>() + len {
-                    return Err(DecodeError::IncompletePacket);
-                }
-
-                let correlation_data = src.split_to(size_of::<u16>() + len);
+                let correlation_data = decode_binary_data(src)?;
                 Property::CorrelationData(correlation_data)
             }
 
@@ -202,8 +242,14 @@ where
                 let (remaining_length, remaining_length_len) = {
                     let mut src = &src[..];
                     let original_src_len = src.len();
-                    let remaining_length =
-                        decode_remaining_length(&mut src)?.ok_or(DecodeError::IncompletePacket)?;
+                    let remaining_length = decode_remaining_length(&mut src)
+                        .map_err(|err| match err {
+                            DecodeError::RemainingLengthTooHigh => {
+                                DecodeError::SubscriptionIdentifierTooLarge
+                            }
+                            err => err,
+                        })?
+                        .ok_or(DecodeError::IncompletePacket)?;
                     let new_src_len = src.len();
                     (remaining_length, original_src_len - new_src_len)
                 };
@@ -217,7 +263,7 @@ where
             }
 
             0x12 => {
-                let client_id = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
+                let client_id = ByteStr::decode_in_packet(src)?;
                 Property::AssignedClientIdentifier(client_id)
             }
 
@@ -227,21 +273,12 @@ where
             }
 
             0x15 => {
-                let method = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
+                let method = ByteStr::decode_in_packet(src)?;
                 Property::AuthenticationMethod(method)
             }
 
             0x16 => {
-                let len: usize = match src.as_ref().get(..size_of::<u16>()) {
-                    Some(src) => u16::from_be_bytes(src.try_into().unwrap()).into(),
-                    None => return Err(DecodeError::IncompletePacket),
-                };
-
-                if src.len() < size_of::<u16>() + len {
-                    return Err(DecodeError::IncompletePacket);
-                }
-
-                let authentication_data = src.split_to(size_of::<u16>() + len);
+                let authentication_data = decode_binary_data(src)?;
                 Property::AuthenticationData(authentication_data)
             }
 
@@ -271,24 +308,25 @@ where
             }
 
             0x1A => {
-                let response_information =
-                    ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
+                let response_information = ByteStr::decode_in_packet(src)?;
                 Property::ResponseInformation(response_information)
             }
 
             0x1C => {
-                let server_reference =
-                    ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
+                let server_reference = ByteStr::decode_in_packet(src)?;
                 Property::ServerReference(server_reference)
             }
 
             0x1F => {
-                let reason_string = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
+                let reason_string = ByteStr::decode_in_packet(src)?;
                 Property::ReasonString(reason_string)
             }
 
             0x21 => {
                 let value = src.try_get_u16_be()?;
+                if value == 0 {
+                    return Err(DecodeError::InvalidReceiveMaximum(value));
+                }
                 Property::ReceiveMaximum(usize::from(value))
             }
 
@@ -321,8 +359,8 @@ where
             }
 
             0x26 => {
-                let name = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
-                let value = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
+                let name = ByteStr::decode_in_packet(src)?;
+                let value = ByteStr::decode_in_packet(src)?;
                 Property::UserProperty(name, value)
             }
 
@@ -445,7 +483,7 @@ where
                 let value: u32 = value
                     .try_into()
                     .map_err(|_| EncodeError::InvalidMaximumPacketSize(value))?;
-                dst.try_put_u8(0x21)?;
+                dst.try_put_u8(0x27)?;
                 dst.try_put_u32_be(value)?;
             }
 
@@ -484,10 +522,8 @@ where
                 let value: u16 = value
                     .try_into()
                     .map_err(|_| EncodeError::InvalidReceiveMaximum(value))?;
-                if value < u16::max_value() {
-                    dst.try_put_u8(0x21)?;
-                    dst.try_put_u16_be(value)?;
-                }
+                dst.try_put_u8(0x21)?;
+                dst.try_put_u16_be(value)?;
             }
 
             Property::RequestProblemInformation(requested) => {
@@ -609,16 +645,37 @@ where
     }
 }
 
+/// Decodes binary data, ie a two-byte big-endian length followed by that many bytes.
+///
+/// The returned value includes the length prefix, so that it can be encoded again as-is.
+///
+/// Ref: 1.5.6 Binary Data
+fn decode_binary_data<P>(src: &mut Shared<P>) -> Result<Shared<P>, DecodeError>
+where
+    P: Clone + BufferPool,
+{
+    let len: usize = match src.as_ref().get(..size_of::<u16>()) {
+        Some(src) => u16::from_be_bytes(src.try_into().unwrap()).into(),
+        None => return Err(DecodeError::IncompletePacket),
+    };
+
+    if src.len() < size_of::<u16>() + len {
+        return Err(DecodeError::IncompletePacket);
+    }
+
+    Ok(src.split_to(size_of::<u16>() + len))
+}
+
 macro_rules! decode_properties {
     (
         @inner
         { $($bindings_decl:tt)* }
         { $($match_body:tt)* }
-        { $src:ident }
+        { $src:ident $ctx:ident $decode_all:ident }
         { }
     ) => {
         $($bindings_decl)*
-        for property in Property::decode_all($src)? {
+        for property in Property::$decode_all($src, $ctx)? {
             match property? {
                 $($match_body)*
                 // TODO: Include at least the variant name of the unexpected property in the error
@@ -631,7 +688,7 @@ macro_rules! decode_properties {
         @inner
         { $($bindings_decl:tt)* }
         { $($match_body:tt)* }
-        { $src:ident }
+        { $src:ident $ctx:ident $decode_all:ident }
         { $binding:ident : Vec<SubscriptionIdentifier> , $($bindings:tt)* }
     ) => {
         decode_properties! {
@@ -646,7 +703,7 @@ macro_rules! decode_properties {
                     $binding.push(value);
                 },
             }
-            { $src }
+            { $src $ctx $decode_all }
             { $($bindings)* }
         }
     };
@@ -655,7 +712,7 @@ macro_rules! decode_properties {
         @inner
         { $($bindings_decl:tt)* }
         { $($match_body:tt)* }
-        { $src:ident }
+        { $src:ident $ctx:ident $decode_all:ident }
         { $binding:ident : Vec<UserProperty> , $($bindings:tt)* }
     ) => {
         decode_properties! {
@@ -670,16 +727,44 @@ macro_rules! decode_properties {
                     $binding.push((name, value));
                 },
             }
-            { $src }
+            { $src $ctx $decode_all }
             { $($bindings)* }
         }
     };
 
+    // A packet sent by a client may only have a single subscription identifier,
+    // so duplicates get their own error rather than the generic DuplicateProperty
     (
         @inner
         { $($bindings_decl:tt)* }
         { $($match_body:tt)* }
-        { $src:ident }
+        { $src:ident $ctx:ident $decode_all:ident }
+        { $binding:ident : SubscriptionIdentifier , $($bindings:tt)* }
+    ) => {
+        decode_properties! {
+            @inner
+            {
+                $($bindings_decl)*
+                let mut $binding = None;
+            }
+            {
+                $($match_body)*
+                Property::SubscriptionIdentifier(value) => {
+                    if $binding.replace(value).is_some() {
+                        return Err(DecodeError::DuplicateSubscriptionIdentifier);
+                    }
+                },
+            }
+            { $src $ctx $decode_all }
+            { $($bindings)* }
+        }
+    };
+
+    (
+        @inner
+        { $($bindings_decl:tt)* }
+        { $($match_body:tt)* }
+        { $src:ident $ctx:ident $decode_all:ident }
         { $binding:ident : $variant:ident , $($bindings:tt)* }
     ) => {
         decode_properties! {
@@ -691,25 +776,48 @@ macro_rules! decode_properties {
             {
                 $($match_body)*
                 Property::$variant(value) => {
-                    if $binding.replace(value).is_some() {
-                        return Err(DecodeError::DuplicateProperty(stringify!($variant)));
+                    if let Some(previous) = $binding.replace(value) {
+                        if !$ctx.allow_identical_duplicate_properties {
+                            return Err(DecodeError::DuplicateProperty(stringify!($variant)));
+                        }
+
+                        if $binding.as_ref() != Some(&previous) {
+                            return Err(DecodeError::ConflictingDuplicateProperty(stringify!($variant)));
+                        }
                     }
                 },
             }
-            { $src }
+            { $src $ctx $decode_all }
+            { $($bindings)* }
+        }
+    };
+
+    // For the packets whose property length may be omitted when there are no properties
+    (
+        @optional_length
+        $src:ident,
+        $ctx:ident,
+        $($bindings:tt)*
+    ) => {
+        decode_properties! {
+            @inner
+            { }
+            { }
+            { $src $ctx decode_all_optional_length }
             { $($bindings)* }
         }
     };
 
     (
         $src:ident,
+        $ctx:ident,
         $($bindings:tt)*
     ) => {
         decode_properties! {
             @inner
             { }
             { }
-            { $src }
+            { $src $ctx decode_all }
             { $($bindings)* }
         }
     };
@@ -812,3 +920,111 @@ macro_rules! encode_properties {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Property;
+    use crate::tests::{shared, TestBufferPool};
+    use crate::{DecodeContext, DecodeError};
+
+    #[test]
+    fn decode_binary_data() {
+        for &identifier in &[0x09_u8, 0x16] {
+            let mut src = shared(&[identifier, 0x00, 0x03, b'a', b'b', b'c', b'd']);
+            let data = match Property::decode(&mut src).unwrap() {
+                Property::CorrelationData(data) if identifier == 0x09 => data,
+                Property::AuthenticationData(data) if identifier == 0x16 => data,
+                _ => panic!("0x{:02X}: unexpected property", identifier),
+            };
+            assert_eq!(data, shared(b"\x00\x03abc"));
+            assert_eq!(src, shared(b"d"));
+
+            let mut src = shared(&[identifier, 0x00, 0x00]);
+            match Property::decode(&mut src).unwrap() {
+                Property::CorrelationData(data) | Property::AuthenticationData(data) => {
+                    assert_eq!(data, shared(b"\x00\x00"));
+                }
+                _ => panic!("0x{:02X}: unexpected property", identifier),
+            }
+
+            for truncated in &[
+                &[identifier][..],
+                &[identifier, 0x00],
+                &[identifier, 0x00, 0x03, b'a', b'b'],
+            ] {
+                match Property::<TestBufferPool>::decode(&mut shared(truncated)) {
+                    Err(DecodeError::IncompletePacket) => (),
+                    Err(err) => panic!("0x{:02X}: {:?}", identifier, err),
+                    Ok(_) => panic!("0x{:02X}: truncated binary data decoded", identifier),
+                }
+            }
+        }
+
+        // Also through a property block, to check that the length prefix delimits the data
+        let ctx = DecodeContext::new(TestBufferPool);
+        let mut src = shared(b"\x09\x09\x00\x01x\x16\x00\x02yz");
+        let properties: Vec<_> = Property::decode_all(&mut src, &ctx)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        match &properties[..] {
+            [Property::CorrelationData(correlation_data), Property::AuthenticationData(authentication_data)] =>
+            {
+                assert_eq!(*correlation_data, shared(b"\x00\x01x"));
+                assert_eq!(*authentication_data, shared(b"\x00\x02yz"));
+            }
+            _ => panic!("unexpected properties"),
+        }
+    }
+
+    #[test]
+    fn encode_maximum_packet_size() {
+        let property: Property<TestBufferPool> = Property::MaximumPacketSize(1024);
+        let mut bytes = vec![];
+        property.encode(&mut bytes).unwrap();
+        assert_eq!(bytes, b"\x27\x00\x00\x04\x00");
+
+        match Property::decode(&mut shared(&bytes)).unwrap() {
+            Property::<TestBufferPool>::MaximumPacketSize(1024) => (),
+            _ => panic!("expected MaximumPacketSize(1024)"),
+        }
+    }
+
+    #[test]
+    fn decode_all_limits() {
+        // The densest property block of a repeatable property is a run of user properties with empty names and values
+        let mut body = vec![50];
+        for _ in 0..10 {
+            body.extend_from_slice(b"\x26\x00\x00\x00\x00");
+        }
+
+        let mut ctx = DecodeContext::new(TestBufferPool);
+        ctx.maximum_properties = Some(4);
+        let results: Vec<_> = Property::decode_all(&mut shared(&body), &ctx)
+            .unwrap()
+            .collect();
+        assert_eq!(results.len(), 5);
+        assert!(results[..4].iter().all(Result::is_ok));
+        match &results[4] {
+            Err(DecodeError::TooManyProperties(4)) => (),
+            Err(err) => panic!("{:?}", err),
+            Ok(_) => panic!("property beyond the maximum decoded"),
+        }
+
+        let mut ctx = DecodeContext::new(TestBufferPool);
+        ctx.maximum_properties_len = Some(49);
+        match Property::decode_all(&mut shared(&body), &ctx) {
+            Err(DecodeError::PropertiesTooLarge(50)) => (),
+            Err(err) => panic!("{:?}", err),
+            Ok(_) => panic!("property block longer than the maximum decoded"),
+        }
+
+        ctx.maximum_properties_len = Some(50);
+        assert_eq!(
+            Property::decode_all(&mut shared(&body), &ctx)
+                .unwrap()
+                .count(),
+            10
+        );
+    }
+}
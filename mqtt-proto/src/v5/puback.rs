@@ -3,7 +3,9 @@
 use std::convert::TryInto;
 
 use super::{PacketMeta, Property};
-use crate::{BufferPool, ByteBuf, ByteStr, DecodeError, EncodeError, PacketIdentifier, Shared};
+use crate::{
+    BufferPool, ByteBuf, ByteStr, DecodeContext, DecodeError, EncodeError, PacketIdentifier, Shared,
+};
 
 /// Ref: 3.4 PUBACK – Publish acknowledgement
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -38,36 +40,39 @@ where
 {
     const PACKET_TYPE: u8 = 0x40;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let packet_identifier = src.try_get_packet_identifier()?;
 
-        match src.try_get_u8() {
-            Ok(reason_code) => {
-                let reason_code = reason_code.try_into()?;
-
-                decode_properties!(
-                    src,
-                    reason_string: ReasonString,
-                    user_properties: Vec<UserProperty>,
-                );
-
-                Ok(PubAck {
-                    packet_identifier,
-                    reason_code,
-                    reason_string,
-                    user_properties,
-                })
-            }
-
-            Err(DecodeError::IncompletePacket) => Ok(PubAck {
+        // The common case of a successful acknowledgement without properties is just the packet identifier
+        if src.is_empty() {
+            return Ok(PubAck {
                 packet_identifier,
                 reason_code: PubAckReasonCode::Success,
                 reason_string: None,
                 user_properties: vec![],
-            }),
-
-            Err(err) => Err(err),
+            });
         }
+
+        let reason_code = src.try_get_u8()?.try_into()?;
+
+        decode_properties!(
+            @optional_length
+            src,
+            ctx,
+            reason_string: ReasonString,
+            user_properties: Vec<UserProperty>,
+        );
+
+        Ok(PubAck {
+            packet_identifier,
+            reason_code,
+            reason_string,
+            user_properties,
+        })
     }
 
     fn encode<B>(self, dst: &mut B) -> Result<(), EncodeError>
@@ -98,4 +103,40 @@ where
 
         Ok(())
     }
+
+    fn encoded_len(&self) -> Option<usize> {
+        super::ack_encoded_len(
+            self.reason_code == PubAckReasonCode::Success,
+            self.reason_string.as_ref(),
+            &self.user_properties,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PubAck, PubAckReasonCode};
+    use crate::tests::{count_allocations, shared, TestBufferPool};
+    use crate::v5::Packet;
+    use crate::PacketIdentifier;
+
+    #[test]
+    fn decode_bare_does_not_allocate() {
+        // Sanity check that allocations are counted at all
+        let (_, allocations) = count_allocations(|| vec![0_u8; 1]);
+        assert_eq!(allocations, 1);
+
+        let body = shared(b"\x00\x01");
+
+        let (packet, allocations) = count_allocations(|| crate::v5::decode(0x40, body).unwrap());
+        assert_eq!(allocations, 0);
+
+        let expected: PubAck<TestBufferPool> = PubAck {
+            packet_identifier: PacketIdentifier::new(1).unwrap(),
+            reason_code: PubAckReasonCode::Success,
+            reason_string: None,
+            user_properties: vec![],
+        };
+        assert_eq!(packet, Packet::PubAck(expected));
+    }
 }
@@ -2,8 +2,10 @@
 
 use std::convert::TryInto;
 
-use super::{PacketMeta, Property};
-use crate::{BufferPool, ByteBuf, ByteStr, DecodeError, EncodeError, PacketIdentifier, Shared};
+use super::{PacketMeta, Property, PubRel};
+use crate::{
+    BufferPool, ByteBuf, ByteStr, DecodeContext, DecodeError, EncodeError, PacketIdentifier, Shared,
+};
 
 #[allow(clippy::doc_markdown)]
 /// Ref: 3.7 PUBCOMP – Publish complete (QoS 2 publish received, part 3)
@@ -26,42 +28,63 @@ define_u8_code! {
     PacketIdentifierNotFound = 0x92,
 }
 
+impl<P> PubComp<P>
+where
+    P: BufferPool,
+{
+    #[allow(clippy::doc_markdown)]
+    /// Constructs the PUBCOMP that responds to the given PUBREL.
+    ///
+    /// Ref: 4.3.3 QoS 2: Exactly once delivery
+    pub fn for_pubrel(pubrel: &PubRel<P>, reason_code: PubCompReasonCode) -> Self {
+        PubComp {
+            packet_identifier: pubrel.packet_identifier,
+            reason_code,
+            reason_string: None,
+            user_properties: vec![],
+        }
+    }
+}
+
 impl<P> PacketMeta<P> for PubComp<P>
 where
     P: Clone + BufferPool,
 {
     const PACKET_TYPE: u8 = 0x70;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let packet_identifier = src.try_get_packet_identifier()?;
 
-        match src.try_get_u8() {
-            Ok(reason_code) => {
-                let reason_code = reason_code.try_into()?;
-
-                decode_properties!(
-                    src,
-                    reason_string: ReasonString,
-                    user_properties: Vec<UserProperty>,
-                );
-
-                Ok(PubComp {
-                    packet_identifier,
-                    reason_code,
-                    reason_string,
-                    user_properties,
-                })
-            }
-
-            Err(DecodeError::IncompletePacket) => Ok(PubComp {
+        // The common case of a successful acknowledgement without properties is just the packet identifier
+        if src.is_empty() {
+            return Ok(PubComp {
                 packet_identifier,
                 reason_code: PubCompReasonCode::Success,
                 reason_string: None,
                 user_properties: vec![],
-            }),
-
-            Err(err) => Err(err),
+            });
         }
+
+        let reason_code = src.try_get_u8()?.try_into()?;
+
+        decode_properties!(
+            @optional_length
+            src,
+            ctx,
+            reason_string: ReasonString,
+            user_properties: Vec<UserProperty>,
+        );
+
+        Ok(PubComp {
+            packet_identifier,
+            reason_code,
+            reason_string,
+            user_properties,
+        })
     }
 
     fn encode<B>(self, dst: &mut B) -> Result<(), EncodeError>
@@ -92,4 +115,55 @@ where
 
         Ok(())
     }
+
+    fn encoded_len(&self) -> Option<usize> {
+        super::ack_encoded_len(
+            self.reason_code == PubCompReasonCode::Success,
+            self.reason_string.as_ref(),
+            &self.user_properties,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PubComp, PubCompReasonCode};
+    use crate::tests::TestBufferPool;
+    use crate::v5::{
+        PacketIdentifierDupQoS, PubRec, PubRecReasonCode, PubRel, PubRelReasonCode, Publish,
+    };
+    use crate::PacketIdentifier;
+
+    #[test]
+    fn qos2_response_chain() {
+        let packet_identifier = PacketIdentifier::new(9).unwrap();
+        let publish = Publish {
+            packet_identifier_dup_qos: PacketIdentifierDupQoS::ExactlyOnce(
+                packet_identifier,
+                false,
+            ),
+            ..crate::v5::tests::publish(false)
+        };
+
+        let received = PubRec::for_publish(&publish, PubRecReasonCode::Success).unwrap();
+        assert_eq!(received.packet_identifier, packet_identifier);
+
+        let release: PubRel<TestBufferPool> = PubRel {
+            packet_identifier: received.packet_identifier,
+            reason_code: PubRelReasonCode::Success,
+            reason_string: None,
+            user_properties: vec![],
+        };
+
+        let pubcomp = PubComp::for_pubrel(&release, PubCompReasonCode::PacketIdentifierNotFound);
+        assert_eq!(
+            pubcomp,
+            PubComp {
+                packet_identifier,
+                reason_code: PubCompReasonCode::PacketIdentifierNotFound,
+                reason_string: None,
+                user_properties: vec![],
+            },
+        );
+    }
 }
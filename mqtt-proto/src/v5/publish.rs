@@ -1,9 +1,13 @@
 // Copyright (c) Microsoft. All rights reserved.
 
+use std::mem::size_of;
 use std::time::Duration;
 
-use super::{PacketMeta, Property};
-use crate::{BufferPool, ByteBuf, ByteStr, DecodeError, EncodeError, PacketIdentifier, Shared};
+use super::{DisconnectReasonCode, PacketMeta, Property};
+use crate::{
+    BufferPool, ByteBuf, ByteStr, DecodeContext, DecodeError, EncodeError, PacketIdentifier, QoS,
+    Shared, TopicName,
+};
 
 /// 3.3 PUBLISH – Publish message
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -18,6 +22,7 @@ where
     pub message_expiry_interval: Option<Duration>,
     pub topic_alias: Option<u16>,
     pub response_topic: Option<ByteStr<P>>,
+    /// Note: Value includes the two-byte length prefix. Use [`Publish::correlation_data_bytes`] to get just the data.
     pub correlation_data: Option<Shared<P>>,
     pub user_properties: Vec<(ByteStr<P>, ByteStr<P>)>,
     pub subscription_identifiers: Vec<usize>,
@@ -35,17 +40,272 @@ pub enum PacketIdentifierDupQoS {
     ExactlyOnce(PacketIdentifier, bool),
 }
 
+impl PacketIdentifierDupQoS {
+    /// A new, ie not DUP, combination of the given quality of service and packet identifier.
+    ///
+    /// The packet identifier is required unless the quality of service is [`QoS::AtMostOnce`], in which case it is ignored.
+    pub(super) fn new(
+        qos: QoS,
+        packet_identifier: Option<PacketIdentifier>,
+    ) -> Result<Self, EncodeError> {
+        match (qos, packet_identifier) {
+            (QoS::AtMostOnce, _) => Ok(PacketIdentifierDupQoS::AtMostOnce),
+            (QoS::AtLeastOnce, Some(packet_identifier)) => Ok(PacketIdentifierDupQoS::AtLeastOnce(
+                packet_identifier,
+                false,
+            )),
+            (QoS::ExactlyOnce, Some(packet_identifier)) => Ok(PacketIdentifierDupQoS::ExactlyOnce(
+                packet_identifier,
+                false,
+            )),
+            (qos, None) => Err(EncodeError::MissingPacketIdentifier(qos)),
+        }
+    }
+
+    /// Returns this value with the DUP flag cleared.
+    fn without_dup(self) -> Self {
+        match self {
+            PacketIdentifierDupQoS::AtMostOnce => PacketIdentifierDupQoS::AtMostOnce,
+            PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, _) => {
+                PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, false)
+            }
+            PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, _) => {
+                PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, false)
+            }
+        }
+    }
+}
+
+impl<P> Publish<P>
+where
+    P: BufferPool,
+{
+    /// The correlation data of this packet, without its two-byte length prefix.
+    ///
+    /// Returns `None` if the value is too short to even have the length prefix, eg if it was constructed without one.
+    pub fn correlation_data_bytes(&self) -> Option<&[u8]> {
+        self.correlation_data
+            .as_ref()
+            .and_then(|correlation_data| correlation_data.as_ref().get(size_of::<u16>()..))
+    }
+
+    /// The quality of service this message is published with.
+    pub fn qos(&self) -> QoS {
+        match self.packet_identifier_dup_qos {
+            PacketIdentifierDupQoS::AtMostOnce => QoS::AtMostOnce,
+            PacketIdentifierDupQoS::AtLeastOnce(_, _) => QoS::AtLeastOnce,
+            PacketIdentifierDupQoS::ExactlyOnce(_, _) => QoS::ExactlyOnce,
+        }
+    }
+
+    /// The packet identifier of this packet, or `None` if it is published with [`QoS::AtMostOnce`].
+    pub fn packet_identifier(&self) -> Option<PacketIdentifier> {
+        match self.packet_identifier_dup_qos {
+            PacketIdentifierDupQoS::AtMostOnce => None,
+            PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, _)
+            | PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, _) => Some(packet_identifier),
+        }
+    }
+
+    /// Whether the DUP flag of this packet is set, ie whether it may be a redelivery.
+    pub fn is_dup(&self) -> bool {
+        match self.packet_identifier_dup_qos {
+            PacketIdentifierDupQoS::AtMostOnce => false,
+            PacketIdentifierDupQoS::AtLeastOnce(_, dup)
+            | PacketIdentifierDupQoS::ExactlyOnce(_, dup) => dup,
+        }
+    }
+
+    /// Whether this packet asks the server to delete the retained message of its topic,
+    /// ie whether it has the RETAIN flag set and an empty payload.
+    ///
+    /// Ref: 3.3.1.3 RETAIN
+    pub fn is_retained_delete(&self) -> bool {
+        self.retain && self.payload.is_empty()
+    }
+
+    /// The payload of this packet as a string, if its payload format indicator says it is utf-8 and it actually is.
+    ///
+    /// An empty payload is the empty string.
+    pub fn payload_str(&self) -> Option<&str> {
+        if !self.payload_is_utf8 {
+            return None;
+        }
+
+        std::str::from_utf8(self.payload.as_ref()).ok()
+    }
+
+    /// Checks that the payload of this packet is valid utf-8 if its payload format indicator says it is.
+    ///
+    /// Returns the reason code that the packet should be rejected with if it isn't.
+    ///
+    /// Ref: 3.3.2.3.2 Payload Format Indicator
+    pub fn validate_payload_format(&self) -> Result<(), DisconnectReasonCode> {
+        if self.payload_is_utf8 && std::str::from_utf8(self.payload.as_ref()).is_err() {
+            return Err(DisconnectReasonCode::PayloadFormatInvalid);
+        }
+
+        Ok(())
+    }
+
+    /// Appends a user property to this packet, eg for a broker to add a trace id to a publication it forwards.
+    ///
+    /// The added property counts towards the size of the packet, so [`crate::v5::encode_with_context`] still fails with
+    /// [`EncodeError::PacketTooLarge`] if it makes the packet exceed the peer's maximum packet size.
+    pub fn push_user_property(&mut self, name: ByteStr<P>, value: ByteStr<P>) {
+        self.user_properties.push((name, value));
+    }
+
+    /// Appends several user properties to this packet, like [`Publish::push_user_property`].
+    pub fn extend_user_properties<I>(&mut self, user_properties: I)
+    where
+        I: IntoIterator<Item = (ByteStr<P>, ByteStr<P>)>,
+    {
+        self.user_properties.extend(user_properties);
+    }
+
+    /// Returns whether this and the other `Publish` are the same message, ie they are equal
+    /// except possibly for the DUP flag. Useful for recognizing redeliveries.
+    pub fn same_message(&self, other: &Self) -> bool {
+        let Publish {
+            topic_name,
+            packet_identifier_dup_qos,
+            retain,
+            payload_is_utf8,
+            message_expiry_interval,
+            topic_alias,
+            response_topic,
+            correlation_data,
+            user_properties,
+            subscription_identifiers,
+            content_type,
+            payload,
+        } = self;
+
+        *topic_name == other.topic_name
+            && packet_identifier_dup_qos.without_dup()
+                == other.packet_identifier_dup_qos.without_dup()
+            && *retain == other.retain
+            && *payload_is_utf8 == other.payload_is_utf8
+            && *message_expiry_interval == other.message_expiry_interval
+            && *topic_alias == other.topic_alias
+            && *response_topic == other.response_topic
+            && *correlation_data == other.correlation_data
+            && *user_properties == other.user_properties
+            && *subscription_identifiers == other.subscription_identifiers
+            && *content_type == other.content_type
+            && *payload == other.payload
+    }
+}
+
+impl<P> Publish<P>
+where
+    P: Clone + BufferPool,
+{
+    /// Constructs a `Publish` to the given topic name with default properties.
+    pub fn new(
+        topic_name: TopicName<P>,
+        packet_identifier_dup_qos: PacketIdentifierDupQoS,
+        retain: bool,
+        payload: Shared<P>,
+    ) -> Self {
+        Publish {
+            topic_name: topic_name.into_byte_str(),
+            packet_identifier_dup_qos,
+            retain,
+            payload_is_utf8: false,
+            message_expiry_interval: None,
+            topic_alias: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: vec![],
+            subscription_identifiers: vec![],
+            content_type: None,
+            payload,
+        }
+    }
+
+    /// Constructs a `Publish` with an empty topic name that relies on a topic alias
+    /// previously established with the receiver.
+    ///
+    /// Ref: 3.3.2.3.4 Topic Alias
+    pub fn with_alias(
+        topic_alias: u16,
+        payload: Shared<P>,
+        packet_identifier_dup_qos: PacketIdentifierDupQoS,
+        retain: bool,
+    ) -> Result<Self, EncodeError> {
+        if topic_alias == 0 {
+            return Err(EncodeError::InvalidTopicAlias(topic_alias));
+        }
+
+        Ok(Publish {
+            topic_name: ByteStr::empty(payload.pool().clone()),
+            packet_identifier_dup_qos,
+            retain,
+            payload_is_utf8: false,
+            message_expiry_interval: None,
+            topic_alias: Some(topic_alias),
+            response_topic: None,
+            correlation_data: None,
+            user_properties: vec![],
+            subscription_identifiers: vec![],
+            content_type: None,
+            payload,
+        })
+    }
+
+    /// Returns a copy of this `Publish` with the given payload.
+    ///
+    /// The other fields are cloned, which only increments the reference counts of their buffers.
+    #[must_use]
+    pub fn with_payload(&self, payload: Shared<P>) -> Self {
+        Publish {
+            payload,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of this `Publish` with the given subscription identifiers,
+    /// eg to forward the same message to several subscribers.
+    ///
+    /// The other fields are cloned, which only increments the reference counts of their buffers.
+    #[must_use]
+    pub fn with_subscription_identifiers(&self, subscription_identifiers: Vec<usize>) -> Self {
+        Publish {
+            subscription_identifiers,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of this `Publish` with the given quality of service,
+    /// eg to downgrade it to the maximum quality of service of a subscription.
+    ///
+    /// The packet identifier is required unless the quality of service is [`QoS::AtMostOnce`], in which case it is ignored.
+    /// The DUP flag of the copy is cleared. The other fields are cloned, which only increments the reference counts of their buffers.
+    pub fn with_qos(
+        &self,
+        qos: QoS,
+        packet_identifier: Option<PacketIdentifier>,
+    ) -> Result<Self, EncodeError> {
+        Ok(Publish {
+            packet_identifier_dup_qos: PacketIdentifierDupQoS::new(qos, packet_identifier)?,
+            ..self.clone()
+        })
+    }
+}
+
 impl<P> PacketMeta<P> for Publish<P>
 where
     P: Clone + BufferPool,
 {
     const PACKET_TYPE: u8 = 0x30;
 
-    fn decode(flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(flags: u8, src: &mut Shared<P>, ctx: &DecodeContext<P>) -> Result<Self, DecodeError> {
         let dup = (flags & 0b0000_1000) != 0;
         let retain = (flags & 0b0000_0001) != 0;
 
-        let topic_name = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
+        let topic_name = ByteStr::decode_in_packet(src)?;
 
         let packet_identifier_dup_qos = match (flags & 0b0000_0110) >> 1 {
             0x00 if dup => return Err(DecodeError::PublishDupAtMostOnce),
@@ -67,6 +327,7 @@ where
 
         decode_properties!(
             src,
+            ctx,
             payload_is_utf8: PayloadIsUtf8,
             message_expiry_interval: MessageExpiryInterval,
             topic_alias: TopicAlias,
@@ -77,6 +338,12 @@ where
             content_type: ContentType,
         );
 
+        // An empty topic name is only allowed when the topic alias stands in for it.
+        if topic_name.is_empty() && topic_alias.is_none() {
+            return Err(DecodeError::EmptyTopicName);
+        }
+
+        ctx.check_payload_len(src.len())?;
         let payload = src.split_to(src.len());
 
         Ok(Publish {
@@ -142,3 +409,246 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{PacketIdentifierDupQoS, Publish};
+    use crate::tests::{byte_str, shared, TestBufferPool};
+    use crate::v5::tests::publish;
+    use crate::v5::DisconnectReasonCode;
+    use crate::{DecodeContext, DecodeError, EncodeError, Owned, PacketIdentifier, QoS};
+
+    #[test]
+    fn new() {
+        let topic_name = crate::TopicName::new(byte_str("a/b")).unwrap();
+        let packet = Publish::new(
+            topic_name,
+            PacketIdentifierDupQoS::AtMostOnce,
+            true,
+            shared(b"hello"),
+        );
+        assert_eq!(packet.topic_name, byte_str("a/b"));
+        assert_eq!(packet.topic_alias, None);
+        assert!(packet.retain);
+
+        let pool = TestBufferPool;
+        let mut bytes = Owned::new(pool, pool.take(16));
+        crate::v5::encode(crate::v5::Packet::Publish(packet), &mut bytes).unwrap();
+        assert_eq!(bytes.filled(), b"\x31\x0B\x00\x03a/b\x00hello");
+    }
+
+    #[test]
+    fn with_alias() {
+        let packet = Publish::with_alias(
+            7,
+            shared(b"hello"),
+            PacketIdentifierDupQoS::AtMostOnce,
+            false,
+        )
+        .unwrap();
+        assert!(packet.topic_name.is_empty());
+        assert_eq!(packet.topic_alias, Some(7));
+
+        let pool = TestBufferPool;
+        let mut bytes = Owned::new(pool, pool.take(32));
+        crate::v5::encode(crate::v5::Packet::Publish(packet), &mut bytes).unwrap();
+        assert_eq!(bytes.filled(), b"\x30\x0B\x00\x00\x03\x23\x00\x07hello");
+
+        match Publish::with_alias(
+            0,
+            shared(b"hello"),
+            PacketIdentifierDupQoS::AtMostOnce,
+            false,
+        ) {
+            Err(EncodeError::InvalidTopicAlias(0)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn decode_empty_topic_name() {
+        match crate::v5::decode_expect::<Publish<_>, _>(
+            0x30,
+            shared(b"\x00\x00\x03\x23\x00\x07hello"),
+        ) {
+            Ok(packet) => {
+                assert!(packet.topic_name.is_empty());
+                assert_eq!(packet.topic_alias, Some(7));
+            }
+            result => panic!("{:?}", result),
+        }
+
+        match crate::v5::decode(0x30, shared(b"\x00\x00\x00hello")) {
+            Err(DecodeError::EmptyTopicName) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn decode_missing_property_length() {
+        // The first byte of the payload is taken to be the property length, which runs past the end of the packet
+        match crate::v5::decode(0x30, shared(b"\x00\x03a/bhello")) {
+            Err(DecodeError::PropertyLengthExceedsPacket {
+                property_length: 0x68,
+                remaining_length: 4,
+            }) => (),
+            result => panic!("{:?}", result),
+        }
+
+        match crate::v5::decode(0x32, shared(b"\x00\x03a/b\x00\x01\x81\x01\xFF\xFF")) {
+            Err(DecodeError::PropertyLengthExceedsPacket {
+                property_length: 0x81,
+                remaining_length: 2,
+            }) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn payload_format() {
+        let mut packet = Publish {
+            payload: shared(b""),
+            ..publish(false)
+        };
+        packet.payload_is_utf8 = true;
+        assert_eq!(packet.payload_str(), Some(""));
+        assert_eq!(packet.validate_payload_format(), Ok(()));
+
+        packet.payload = shared(b"caf\xC3\xA9");
+        assert_eq!(packet.payload_str(), Some("caf\u{e9}"));
+        assert_eq!(packet.validate_payload_format(), Ok(()));
+
+        packet.payload = shared(b"caf\xC3");
+        assert_eq!(packet.payload_str(), None);
+        assert_eq!(
+            packet.validate_payload_format(),
+            Err(DisconnectReasonCode::PayloadFormatInvalid)
+        );
+
+        // Without the indicator, the payload is opaque bytes
+        packet.payload_is_utf8 = false;
+        assert_eq!(packet.payload_str(), None);
+        assert_eq!(packet.validate_payload_format(), Ok(()));
+    }
+
+    #[test]
+    fn with_qos() {
+        let packet = publish(false);
+        let packet_identifier = PacketIdentifier::new(3).unwrap();
+
+        match packet.with_qos(QoS::AtLeastOnce, None) {
+            Err(EncodeError::MissingPacketIdentifier(QoS::AtLeastOnce)) => (),
+            result => panic!("{:?}", result),
+        }
+
+        let upgraded = packet
+            .with_qos(QoS::AtLeastOnce, Some(packet_identifier))
+            .unwrap();
+        assert_eq!(
+            upgraded.packet_identifier_dup_qos,
+            PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, false)
+        );
+        assert_eq!(upgraded.payload, packet.payload);
+
+        let downgraded = upgraded.with_qos(QoS::AtMostOnce, None).unwrap();
+        assert_eq!(downgraded, packet);
+    }
+
+    #[test]
+    fn push_user_property() {
+        let mut packet = publish(false);
+        packet.push_user_property(byte_str("trace-id"), byte_str("1234"));
+        packet.extend_user_properties(vec![
+            (byte_str("hop"), byte_str("1")),
+            (byte_str("hop"), byte_str("2")),
+        ]);
+        assert_eq!(
+            packet.user_properties,
+            vec![
+                (byte_str("trace-id"), byte_str("1234")),
+                (byte_str("hop"), byte_str("1")),
+                (byte_str("hop"), byte_str("2")),
+            ]
+        );
+
+        let pool = TestBufferPool;
+        let mut bytes = Owned::new(pool, pool.take(64));
+        crate::v5::encode(crate::v5::Packet::Publish(packet.clone()), &mut bytes).unwrap();
+        let decoded = crate::v5::decode(bytes.filled()[0], shared(&bytes.filled()[2..])).unwrap();
+        assert_eq!(decoded, crate::v5::Packet::Publish(packet.clone()));
+
+        let packet_size = bytes.filled().len();
+        let ctx = crate::v5::EncodeContext {
+            maximum_packet_size: Some(packet_size - 1),
+            ..Default::default()
+        };
+        let mut bytes = Owned::new(pool, pool.take(64));
+        match crate::v5::encode_with_context(&ctx, crate::v5::Packet::Publish(packet), &mut bytes) {
+            Err(EncodeError::PacketTooLarge(size)) if size == packet_size => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn same_message() {
+        let message = |dup| Publish {
+            packet_identifier_dup_qos: PacketIdentifierDupQoS::AtLeastOnce(
+                PacketIdentifier::new(5).unwrap(),
+                dup,
+            ),
+            ..publish(false)
+        };
+
+        assert!(message(true).same_message(&message(false)));
+        assert_ne!(message(true), message(false));
+
+        let mut other = message(false);
+        other.retain = true;
+        assert!(!message(false).same_message(&other));
+    }
+
+    #[test]
+    fn with_payload_and_subscription_identifiers() {
+        let original = publish(false);
+
+        let packet = original.with_payload(shared(b"world"));
+        assert_eq!(packet.payload, shared(b"world"));
+        assert_eq!(packet.topic_name, original.topic_name);
+        assert_eq!(original.payload, shared(b"hello"));
+
+        let packet = original.with_subscription_identifiers(vec![2, 3]);
+        assert_eq!(packet.subscription_identifiers, vec![2, 3]);
+        assert_eq!(packet.payload, shared(b"hello"));
+        assert!(original.subscription_identifiers.is_empty());
+    }
+
+    #[test]
+    fn correlation_data_bytes() {
+        let mut packet = publish(false);
+        assert_eq!(packet.correlation_data_bytes(), None);
+
+        packet.correlation_data = Some(shared(b"\x00\x02id"));
+        assert_eq!(packet.correlation_data_bytes(), Some(&b"id"[..]));
+
+        packet.correlation_data = Some(shared(b"\x00"));
+        assert_eq!(packet.correlation_data_bytes(), None);
+    }
+
+    #[test]
+    fn maximum_payload_len() {
+        let body = b"\x00\x03a/b\x00hello";
+
+        let mut ctx = DecodeContext::new(TestBufferPool);
+        ctx.maximum_payload_len = Some(5);
+        match crate::v5::decode_with_context(&ctx, 0x30, shared(body)) {
+            Ok(crate::v5::Packet::Publish(packet)) => assert_eq!(packet.payload, shared(b"hello")),
+            result => panic!("{:?}", result),
+        }
+
+        ctx.maximum_payload_len = Some(4);
+        match crate::v5::decode_with_context(&ctx, 0x30, shared(body)) {
+            Err(DecodeError::PayloadTooLarge(5)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+}
@@ -2,8 +2,10 @@
 
 use std::convert::TryInto;
 
-use super::{PacketMeta, Property};
-use crate::{BufferPool, ByteBuf, ByteStr, DecodeError, EncodeError, PacketIdentifier, Shared};
+use super::{PacketIdentifierDupQoS, PacketMeta, Property, Publish};
+use crate::{
+    BufferPool, ByteBuf, ByteStr, DecodeContext, DecodeError, EncodeError, PacketIdentifier, Shared,
+};
 
 #[allow(clippy::doc_markdown)]
 /// Ref: 3.5 PUBREC – Publish received (QoS 2 publish received, part 1)
@@ -33,42 +35,70 @@ define_u8_code! {
     PayloadFormatInvalid = 0x99,
 }
 
+impl<P> PubRec<P>
+where
+    P: BufferPool,
+{
+    #[allow(clippy::doc_markdown)]
+    /// Constructs the PUBREC that acknowledges the given PUBLISH, or `None` if the PUBLISH
+    /// is not [`QoS::ExactlyOnce`](crate::QoS::ExactlyOnce) and so does not get one.
+    ///
+    /// Ref: 4.3.3 QoS 2: Exactly once delivery
+    pub fn for_publish(publish: &Publish<P>, reason_code: PubRecReasonCode) -> Option<Self> {
+        if let PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, _) =
+            publish.packet_identifier_dup_qos
+        {
+            Some(PubRec {
+                packet_identifier,
+                reason_code,
+                reason_string: None,
+                user_properties: vec![],
+            })
+        } else {
+            None
+        }
+    }
+}
+
 impl<P> PacketMeta<P> for PubRec<P>
 where
     P: Clone + BufferPool,
 {
     const PACKET_TYPE: u8 = 0x50;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let packet_identifier = src.try_get_packet_identifier()?;
 
-        match src.try_get_u8() {
-            Ok(reason_code) => {
-                let reason_code = reason_code.try_into()?;
-
-                decode_properties!(
-                    src,
-                    reason_string: ReasonString,
-                    user_properties: Vec<UserProperty>,
-                );
-
-                Ok(PubRec {
-                    packet_identifier,
-                    reason_code,
-                    reason_string,
-                    user_properties,
-                })
-            }
-
-            Err(DecodeError::IncompletePacket) => Ok(PubRec {
+        // The common case of a successful acknowledgement without properties is just the packet identifier
+        if src.is_empty() {
+            return Ok(PubRec {
                 packet_identifier,
                 reason_code: PubRecReasonCode::Success,
                 reason_string: None,
                 user_properties: vec![],
-            }),
-
-            Err(err) => Err(err),
+            });
         }
+
+        let reason_code = src.try_get_u8()?.try_into()?;
+
+        decode_properties!(
+            @optional_length
+            src,
+            ctx,
+            reason_string: ReasonString,
+            user_properties: Vec<UserProperty>,
+        );
+
+        Ok(PubRec {
+            packet_identifier,
+            reason_code,
+            reason_string,
+            user_properties,
+        })
     }
 
     fn encode<B>(self, dst: &mut B) -> Result<(), EncodeError>
@@ -99,4 +129,50 @@ where
 
         Ok(())
     }
+
+    fn encoded_len(&self) -> Option<usize> {
+        super::ack_encoded_len(
+            self.reason_code == PubRecReasonCode::Success,
+            self.reason_string.as_ref(),
+            &self.user_properties,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PubRec, PubRecReasonCode};
+    use crate::v5::{PacketIdentifierDupQoS, Publish};
+    use crate::PacketIdentifier;
+
+    #[test]
+    fn for_publish() {
+        let packet_identifier = PacketIdentifier::new(3).unwrap();
+
+        for (packet_identifier_dup_qos, expected) in [
+            (PacketIdentifierDupQoS::AtMostOnce, None),
+            (
+                PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, false),
+                None,
+            ),
+            (
+                PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, true),
+                Some(PubRec {
+                    packet_identifier,
+                    reason_code: PubRecReasonCode::Success,
+                    reason_string: None,
+                    user_properties: vec![],
+                }),
+            ),
+        ] {
+            let publish = Publish {
+                packet_identifier_dup_qos,
+                ..crate::v5::tests::publish(false)
+            };
+            assert_eq!(
+                PubRec::for_publish(&publish, PubRecReasonCode::Success),
+                expected,
+            );
+        }
+    }
 }
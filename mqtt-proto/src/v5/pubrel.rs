@@ -3,7 +3,9 @@
 use std::convert::TryInto;
 
 use super::{PacketMeta, Property};
-use crate::{BufferPool, ByteBuf, ByteStr, DecodeError, EncodeError, PacketIdentifier, Shared};
+use crate::{
+    BufferPool, ByteBuf, ByteStr, DecodeContext, DecodeError, EncodeError, PacketIdentifier, Shared,
+};
 
 #[allow(clippy::doc_markdown)]
 /// Ref: 3.6 PUBREL – Publish release (QoS 2 publish received, part 2)
@@ -32,36 +34,39 @@ where
 {
     const PACKET_TYPE: u8 = 0x60;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let packet_identifier = src.try_get_packet_identifier()?;
 
-        match src.try_get_u8() {
-            Ok(reason_code) => {
-                let reason_code = reason_code.try_into()?;
-
-                decode_properties!(
-                    src,
-                    reason_string: ReasonString,
-                    user_properties: Vec<UserProperty>,
-                );
-
-                Ok(PubRel {
-                    packet_identifier,
-                    reason_code,
-                    reason_string,
-                    user_properties,
-                })
-            }
-
-            Err(DecodeError::IncompletePacket) => Ok(PubRel {
+        // The common case of a successful acknowledgement without properties is just the packet identifier
+        if src.is_empty() {
+            return Ok(PubRel {
                 packet_identifier,
                 reason_code: PubRelReasonCode::Success,
                 reason_string: None,
                 user_properties: vec![],
-            }),
-
-            Err(err) => Err(err),
+            });
         }
+
+        let reason_code = src.try_get_u8()?.try_into()?;
+
+        decode_properties!(
+            @optional_length
+            src,
+            ctx,
+            reason_string: ReasonString,
+            user_properties: Vec<UserProperty>,
+        );
+
+        Ok(PubRel {
+            packet_identifier,
+            reason_code,
+            reason_string,
+            user_properties,
+        })
     }
 
     fn encode<B>(self, dst: &mut B) -> Result<(), EncodeError>
@@ -92,4 +97,12 @@ where
 
         Ok(())
     }
+
+    fn encoded_len(&self) -> Option<usize> {
+        super::ack_encoded_len(
+            self.reason_code == PubRelReasonCode::Success,
+            self.reason_string.as_ref(),
+            &self.user_properties,
+        )
+    }
 }
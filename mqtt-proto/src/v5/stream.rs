@@ -0,0 +1,357 @@
+// Copyright (c) Microsoft. All rights reserved.
+
+use std::io::Read;
+
+use super::{decode_with_context, Packet};
+use crate::{BufferPool, DecodeContext, DecodeError, Owned};
+
+/// Decodes packets from a stream of bytes that arrive in arbitrarily-sized chunks, eg from a TCP socket.
+///
+/// Bytes are accumulated with [`StreamDecoder::push`], and complete packets are taken out with [`StreamDecoder::next_packet`].
+/// The bodies of decoded packets share the decoder's buffer. When the buffer runs out of space, the bytes that have not been
+/// decoded yet are moved to a new, larger buffer. The old buffer is put back into the pool once all packets decoded from it are dropped.
+pub struct StreamDecoder<P>
+where
+    P: BufferPool,
+{
+    ctx: DecodeContext<P>,
+    buffer: Owned<P>,
+    capacity: usize,
+    poisoned: bool,
+}
+
+impl<P> StreamDecoder<P>
+where
+    P: Clone + BufferPool,
+{
+    /// Constructs a decoder whose buffer starts out with the given capacity.
+    pub fn new(pool: P, capacity: usize) -> Self {
+        Self::with_context(DecodeContext::new(pool), capacity)
+    }
+
+    /// Like [`StreamDecoder::new`], but packets are decoded subject to the limits of the given context.
+    pub fn with_context(ctx: DecodeContext<P>, capacity: usize) -> Self {
+        let buffer = Owned::new(ctx.pool.clone(), ctx.pool.take(capacity));
+        StreamDecoder {
+            ctx,
+            buffer,
+            capacity,
+            poisoned: false,
+        }
+    }
+
+    /// Appends the given newly-read bytes to the end of the stream.
+    ///
+    /// The bytes are discarded if the decoder has been poisoned by [`StreamDecoder::next_packet`].
+    pub fn push(&mut self, data: &[u8]) {
+        if self.poisoned {
+            return;
+        }
+
+        if self.buffer.unfilled().len() < data.len() {
+            self.compact(data.len());
+        }
+
+        self.buffer.unfilled_mut()[..data.len()].copy_from_slice(data);
+        self.buffer.fill(data.len());
+    }
+
+    /// Decodes the next packet from the stream, or returns `None` if the stream does not have a complete packet yet.
+    ///
+    /// A packet whose body fails to decode is still removed from the stream, so decoding can continue with the next packet.
+    /// But a malformed fixed header or a packet that is too large leaves the rest of the stream unframed, so the decoder is poisoned:
+    /// the error is returned once, and every later call fails with [`DecodeError::StreamPoisoned`].
+    pub fn next_packet(&mut self) -> Result<Option<Packet<P>>, DecodeError> {
+        if self.poisoned {
+            return Err(DecodeError::StreamPoisoned);
+        }
+
+        let mut src = self.buffer.filled();
+        let header = match crate::decode_fixed_header(&mut src) {
+            Ok(header) => header,
+            Err(err) => return Err(self.poison(err)),
+        };
+        let Some((first_byte, remaining_length)) = header else {
+            return Ok(None);
+        };
+
+        // Reject a packet that is too large before waiting for the rest of it
+        if let Err(err) = self.ctx.check_packet_size(first_byte, remaining_length) {
+            return Err(self.poison(err));
+        }
+
+        if src.len() < remaining_length {
+            return Ok(None);
+        }
+
+        let header_len = self.buffer.filled_len() - src.len();
+        let mut packet = self.buffer.split_to(header_len + remaining_length);
+        packet.drain(header_len);
+        let packet = decode_with_context(&self.ctx, first_byte, packet.freeze())?;
+        Ok(Some(packet))
+    }
+
+    /// Discards the rest of the stream, since it can no longer be split into packets after the given error.
+    fn poison(&mut self, err: DecodeError) -> DecodeError {
+        self.poisoned = true;
+        let filled_len = self.buffer.filled_len();
+        self.buffer.drain(filled_len);
+        err
+    }
+
+    /// Appends as many bytes as the given reader returns from one read to the end of the stream.
+    ///
+    /// Returns the number of bytes that were read, which is 0 if the reader has reached its end.
+    fn read_from<R>(&mut self, reader: &mut R) -> std::io::Result<usize>
+    where
+        R: Read,
+    {
+        if self.buffer.unfilled().is_empty() {
+            self.compact(1);
+        }
+
+        let read = loop {
+            match reader.read(self.buffer.unfilled_mut()) {
+                Ok(read) => break read,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        };
+        self.buffer.fill(read);
+        Ok(read)
+    }
+
+    /// Moves the bytes that have not been decoded yet into a new buffer with room for at least `additional` more bytes.
+    fn compact(&mut self, additional: usize) {
+        let filled_len = self.buffer.filled_len();
+        let required = filled_len + additional;
+        if required > self.capacity {
+            self.capacity = std::cmp::max(self.capacity * 2, required);
+        }
+
+        let mut buffer = Owned::new(self.ctx.pool.clone(), self.ctx.pool.take(self.capacity));
+        buffer.unfilled_mut()[..filled_len].copy_from_slice(self.buffer.filled());
+        buffer.fill(filled_len);
+        self.buffer = buffer;
+    }
+}
+
+/// Decodes packets one at a time from a blocking reader, eg a `std::net::TcpStream`.
+///
+/// Bytes are read into the buffer of an internal [`StreamDecoder`] as they are needed, so a read may return
+/// the start of the next packet along with the end of the current one.
+pub struct BlockingReader<R, P>
+where
+    P: BufferPool,
+{
+    reader: R,
+    decoder: StreamDecoder<P>,
+}
+
+impl<R, P> BlockingReader<R, P>
+where
+    R: Read,
+    P: Clone + BufferPool,
+{
+    /// Constructs a reader whose buffer starts out with the given capacity.
+    pub fn new(reader: R, pool: P, capacity: usize) -> Self {
+        Self::with_context(reader, DecodeContext::new(pool), capacity)
+    }
+
+    /// Like [`BlockingReader::new`], but packets are decoded subject to the limits of the given context.
+    pub fn with_context(reader: R, ctx: DecodeContext<P>, capacity: usize) -> Self {
+        BlockingReader {
+            reader,
+            decoder: StreamDecoder::with_context(ctx, capacity),
+        }
+    }
+
+    /// Decodes the next packet, reading from the reader until a complete packet is available.
+    ///
+    /// Fails with an [`std::io::ErrorKind::UnexpectedEof`] error if the reader ends first, even if it ends cleanly between packets.
+    /// Like [`StreamDecoder::next_packet`], a malformed fixed header or a packet that is too large poisons the reader,
+    /// and it does not read any more after that.
+    #[allow(clippy::should_implement_trait)] // A reader never runs out of packets, it fails, so this is not an `Iterator`.
+    pub fn next(&mut self) -> Result<Packet<P>, DecodeError> {
+        loop {
+            if let Some(packet) = self.decoder.next_packet()? {
+                return Ok(packet);
+            }
+
+            if self.decoder.read_from(&mut self.reader)? == 0 {
+                return Err(DecodeError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+
+    /// Returns the reader, discarding any bytes that were read from it but not decoded yet.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockingReader, StreamDecoder};
+    use crate::tests::TestBufferPool;
+    use crate::v5::{Packet, PingReq};
+    use crate::{DecodeContext, DecodeError, Owned};
+
+    fn publish() -> Packet<TestBufferPool> {
+        Packet::Publish(crate::v5::tests::publish(false))
+    }
+
+    fn encode(packet: Packet<TestBufferPool>) -> Vec<u8> {
+        let pool = TestBufferPool;
+        let mut bytes = Owned::new(pool, pool.take(64));
+        crate::v5::encode(packet, &mut bytes).unwrap();
+        bytes.filled().to_vec()
+    }
+
+    #[test]
+    fn packet_split_across_pushes() {
+        let bytes = encode(publish());
+
+        let mut decoder = StreamDecoder::new(TestBufferPool, 4);
+        assert!(decoder.next_packet().unwrap().is_none());
+
+        decoder.push(&bytes[..1]);
+        assert!(decoder.next_packet().unwrap().is_none());
+
+        decoder.push(&bytes[1..5]);
+        assert!(decoder.next_packet().unwrap().is_none());
+
+        decoder.push(&bytes[5..]);
+        assert_eq!(decoder.next_packet().unwrap(), Some(publish()));
+        assert!(decoder.next_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn multiple_packets() {
+        let mut bytes = encode(Packet::PingReq(PingReq));
+        bytes.extend(encode(publish()));
+        bytes.extend(encode(Packet::PingReq(PingReq)));
+
+        let mut decoder = StreamDecoder::new(TestBufferPool, 8);
+        for chunk in bytes.chunks(5) {
+            decoder.push(chunk);
+        }
+
+        assert_eq!(
+            decoder.next_packet().unwrap(),
+            Some(Packet::PingReq(PingReq))
+        );
+        assert_eq!(decoder.next_packet().unwrap(), Some(publish()));
+        assert_eq!(
+            decoder.next_packet().unwrap(),
+            Some(Packet::PingReq(PingReq))
+        );
+        assert!(decoder.next_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn packet_too_large() {
+        let bytes = encode(publish());
+
+        let mut ctx = DecodeContext::new(TestBufferPool);
+        ctx.maximum_packet_size = Some(4);
+        let mut decoder = StreamDecoder::with_context(ctx, 4);
+
+        // Fails as soon as the fixed header is available
+        decoder.push(&bytes[..2]);
+        match decoder.next_packet() {
+            Err(DecodeError::PacketTooLarge {
+                packet_type: 0x30, ..
+            }) => (),
+            result => panic!("{:?}", result),
+        }
+
+        // The rest of the stream can't be framed, so the decoder is poisoned
+        decoder.push(&bytes[2..]);
+        decoder.push(&encode(Packet::PingReq(PingReq)));
+        match decoder.next_packet() {
+            Err(DecodeError::StreamPoisoned) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn buffers_taken_from_pool() {
+        let pool = std::sync::Arc::new(crate::ReusableBufferPool::new(64));
+
+        let mut decoder = StreamDecoder::new(pool.clone(), 64);
+        assert_eq!(pool.outstanding(), 1);
+
+        decoder.push(&encode(publish()));
+        let packet = decoder.next_packet().unwrap().unwrap();
+        assert!(matches!(packet, Packet::Publish(_)));
+
+        // Packets share the decoder's buffer, so it is put back once both are dropped
+        drop(decoder);
+        assert_eq!(pool.outstanding(), 1);
+        drop(packet);
+        assert_eq!(pool.outstanding(), 0);
+    }
+
+    #[test]
+    fn blocking_reader() {
+        let mut bytes = encode(publish());
+        bytes.extend(encode(Packet::PingReq(PingReq)));
+
+        // The buffer has to grow to fit the PUBLISH
+        let mut reader = BlockingReader::new(std::io::Cursor::new(bytes), TestBufferPool, 4);
+        assert_eq!(reader.next().unwrap(), publish());
+        assert_eq!(reader.next().unwrap(), Packet::PingReq(PingReq));
+        match reader.next() {
+            Err(DecodeError::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn blocking_reader_one_byte_per_read() {
+        struct OneByteReader(std::io::Cursor<Vec<u8>>);
+
+        impl std::io::Read for OneByteReader {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = std::cmp::min(buf.len(), 1);
+                self.0.read(&mut buf[..len])
+            }
+        }
+
+        let mut bytes = encode(publish());
+        bytes.extend(encode(Packet::PingReq(PingReq)));
+
+        let mut reader = BlockingReader::new(
+            OneByteReader(std::io::Cursor::new(bytes)),
+            TestBufferPool,
+            4,
+        );
+        assert_eq!(reader.next().unwrap(), publish());
+        assert_eq!(reader.next().unwrap(), Packet::PingReq(PingReq));
+        match reader.next() {
+            Err(DecodeError::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn blocking_reader_packet_too_large() {
+        let mut bytes = encode(publish());
+        bytes.extend(encode(Packet::PingReq(PingReq)));
+
+        let mut ctx = DecodeContext::new(TestBufferPool);
+        ctx.maximum_packet_size = Some(4);
+        let mut reader = BlockingReader::with_context(std::io::Cursor::new(bytes), ctx, 4);
+        match reader.next() {
+            Err(DecodeError::PacketTooLarge {
+                packet_type: 0x30, ..
+            }) => (),
+            result => panic!("{:?}", result),
+        }
+        match reader.next() {
+            Err(DecodeError::StreamPoisoned) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+}
@@ -3,7 +3,9 @@
 use std::convert::TryInto;
 
 use super::{PacketMeta, Property};
-use crate::{BufferPool, ByteBuf, ByteStr, DecodeError, EncodeError, PacketIdentifier, Shared};
+use crate::{
+    BufferPool, ByteBuf, ByteStr, DecodeContext, DecodeError, EncodeError, PacketIdentifier, Shared,
+};
 
 /// Ref: 3.9 SUBACK – Subscribe acknowledgement
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -35,27 +37,37 @@ define_u8_code! {
     WildcardSubscriptionsNotSupported = 0xA2,
 }
 
-impl<P> PacketMeta<P> for SubAck<P>
+impl<P> SubAck<P>
 where
     P: Clone + BufferPool,
 {
-    const PACKET_TYPE: u8 = 0x90;
+    /// Decodes the body of a SUBACK like [`crate::v5::decode`] would, but puts its reason codes into the given `Vec`
+    /// so that one `Vec` can be reused to decode many packets.
+    ///
+    /// `reason_codes` is cleared before it's filled. The `reason_codes` field of the returned packet is always empty.
+    pub fn decode_into(
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+        reason_codes: &mut Vec<SubscribeReasonCode>,
+    ) -> Result<Self, DecodeError> {
+        reason_codes.clear();
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
         let packet_identifier = src.try_get_packet_identifier()?;
 
         decode_properties!(
             src,
+            ctx,
             reason_string: ReasonString,
             user_properties: Vec<UserProperty>,
         );
 
-        let reason_codes: Result<Vec<_>, _> = src
-            .as_ref()
-            .iter()
-            .map(|&reason_code| reason_code.try_into())
-            .collect();
-        let reason_codes = reason_codes?;
+        ctx.check_reason_codes(src.len())?;
+
+        // `SubscribeReasonCode` only has the codes that are legal in a SUBACK, so converting each byte
+        // is enough to reject codes that belong to other packets' reason code sets.
+        for &reason_code in src.as_ref() {
+            reason_codes.push(reason_code.try_into()?);
+        }
         src.drain(reason_codes.len());
 
         if reason_codes.is_empty() {
@@ -66,7 +78,27 @@ where
             packet_identifier,
             reason_string,
             user_properties,
+            reason_codes: vec![],
+        })
+    }
+}
+
+impl<P> PacketMeta<P> for SubAck<P>
+where
+    P: Clone + BufferPool,
+{
+    const PACKET_TYPE: u8 = 0x90;
+
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
+        let mut reason_codes = vec![];
+        let packet = Self::decode_into(src, ctx, &mut reason_codes)?;
+        Ok(SubAck {
             reason_codes,
+            ..packet
         })
     }
 
@@ -96,3 +128,48 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{SubAck, SubscribeReasonCode};
+    use crate::tests::{shared, TestBufferPool};
+    use crate::{DecodeContext, DecodeError, PacketIdentifier};
+
+    #[test]
+    fn decode_into_reuses_vec() {
+        let ctx = DecodeContext::new(TestBufferPool);
+        let mut reason_codes = vec![
+            SubscribeReasonCode::UnspecifiedError,
+            SubscribeReasonCode::NotAuthorized,
+        ];
+
+        let packet =
+            SubAck::decode_into(&mut shared(b"\x00\x01\x00\x01"), &ctx, &mut reason_codes).unwrap();
+        assert_eq!(packet.packet_identifier, PacketIdentifier::new(1).unwrap());
+        assert!(packet.reason_codes.is_empty());
+        assert_eq!(reason_codes, [SubscribeReasonCode::GrantedQoS1]);
+    }
+
+    #[test]
+    fn decode_reason_codes() {
+        // Ref: 3.9.3 SUBACK Payload
+        //
+        // Codes from other packets' sets, like 0x92 Packet Identifier Not Found, must be rejected.
+        const LEGAL: &[u8] = &[
+            0x00, 0x01, 0x02, 0x80, 0x83, 0x87, 0x8F, 0x91, 0x97, 0x9E, 0xA1, 0xA2,
+        ];
+
+        for code in 0..=u8::MAX {
+            let body = [0x00, 0x01, 0x00, code];
+            match crate::v5::decode(0x90, shared(&body)) {
+                Ok(crate::v5::Packet::SubAck(packet)) if LEGAL.contains(&code) => {
+                    assert_eq!(packet.reason_codes.len(), 1);
+                    assert_eq!(u8::from(packet.reason_codes[0]), code);
+                }
+                Err(DecodeError::UnrecognizedSubscribeReasonCode(value))
+                    if value == code && !LEGAL.contains(&code) => {}
+                result => panic!("{:#04x}: {:?}", code, result),
+            }
+        }
+    }
+}
@@ -4,7 +4,8 @@ use std::convert::TryInto;
 
 use super::{PacketMeta, Property};
 use crate::{
-    BufferPool, ByteBuf, ByteStr, DecodeError, EncodeError, PacketIdentifier, QoS, Shared,
+    BufferPool, ByteBuf, ByteStr, DecodeContext, DecodeError, EncodeError, PacketIdentifier, QoS,
+    Shared,
 };
 
 /// Ref: 3.8 SUBSCRIBE - Subscribe to topics
@@ -32,6 +33,70 @@ where
     pub retain_handling: RetainHandling,
 }
 
+/// A topic filter of a [`Subscribe`], classified by whether it is a shared subscription.
+///
+/// Ref: 4.8.2 Shared Subscriptions
+#[derive(Debug, Eq, PartialEq)]
+pub enum ClassifiedFilter<'a, P>
+where
+    P: BufferPool,
+{
+    Normal(&'a ByteStr<P>, &'a SubscribeTo<P>),
+    Shared {
+        group: &'a str,
+        filter: &'a str,
+        options: &'a SubscribeTo<P>,
+    },
+}
+
+/// The options byte of a [`SubscribeTo`].
+///
+/// Ref: 3.8.3.1 Subscription Options
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SubscriptionOptions {
+    pub maximum_qos: QoS,
+    pub no_local: bool,
+    pub retain_as_published: bool,
+    pub retain_handling: RetainHandling,
+}
+
+impl SubscriptionOptions {
+    /// Parses a subscription options byte, rejecting unrecognized values and set reserved bits.
+    pub fn from_byte(options: u8) -> Result<Self, DecodeError> {
+        let maximum_qos = (options & 0b0000_0011).try_into()?;
+
+        let no_local = (options & 0b0000_0100) != 0;
+
+        let retain_as_published = (options & 0b0000_1000) != 0;
+
+        let retain_handling = ((options & 0b0011_0000) >> 4).try_into()?;
+
+        if (options & 0b1100_0000) != 0 {
+            return Err(DecodeError::SubscriptionOptionsReservedSet);
+        }
+
+        Ok(SubscriptionOptions {
+            maximum_qos,
+            no_local,
+            retain_as_published,
+            retain_handling,
+        })
+    }
+
+    pub fn to_byte(self) -> u8 {
+        let mut options = 0_u8;
+        options |= u8::from(self.maximum_qos);
+        if self.no_local {
+            options |= 0b0000_0100;
+        }
+        if self.retain_as_published {
+            options |= 0b0000_1000;
+        }
+        options |= u8::from(self.retain_handling) << 4;
+        options
+    }
+}
+
 define_u8_code! {
     /// Ref: 3.8.3.1 Subscription Options
     RetainHandling,
@@ -41,17 +106,123 @@ define_u8_code! {
     DoNotSend = 0x02,
 }
 
+impl<P> SubscribeTo<P>
+where
+    P: BufferPool,
+{
+    pub fn new(topic_filter: ByteStr<P>, options: SubscriptionOptions) -> Self {
+        let SubscriptionOptions {
+            maximum_qos,
+            no_local,
+            retain_as_published,
+            retain_handling,
+        } = options;
+
+        SubscribeTo {
+            topic_filter,
+            maximum_qos,
+            no_local,
+            retain_as_published,
+            retain_handling,
+        }
+    }
+
+    pub fn options(&self) -> SubscriptionOptions {
+        SubscriptionOptions {
+            maximum_qos: self.maximum_qos,
+            no_local: self.no_local,
+            retain_as_published: self.retain_as_published,
+            retain_handling: self.retain_handling,
+        }
+    }
+
+    /// Whether this subscription request has the same options as the given one, regardless of their topic filters,
+    /// eg to tell whether a re-subscription to an existing filter changes the subscription.
+    pub fn options_eq(&self, other: &Self) -> bool {
+        self.options() == other.options()
+    }
+}
+
+impl<P> Subscribe<P>
+where
+    P: BufferPool,
+{
+    /// Iterates over the topic filters of this packet as strings.
+    pub fn topic_filters(&self) -> impl Iterator<Item = &str> {
+        self.subscribe_to
+            .iter()
+            .map(|subscribe_to| subscribe_to.topic_filter.as_ref())
+    }
+
+    /// Iterates over the topic filters of this packet, splitting shared subscriptions into their share name and topic filter.
+    ///
+    /// Filters that start with `$share/` but do not have a valid share name followed by a non-empty topic filter
+    /// are returned as [`ClassifiedFilter::Normal`].
+    pub fn classified_filters(&self) -> impl Iterator<Item = ClassifiedFilter<'_, P>> {
+        self.subscribe_to.iter().map(|subscribe_to| {
+            match split_shared_subscription(subscribe_to.topic_filter.as_ref()) {
+                Some((group, filter)) => ClassifiedFilter::Shared {
+                    group,
+                    filter,
+                    options: subscribe_to,
+                },
+                None => ClassifiedFilter::Normal(&subscribe_to.topic_filter, subscribe_to),
+            }
+        })
+    }
+
+    /// Converts this packet into a 3.1.1 SUBSCRIBE with the same packet identifier, topic filters and maximum qualities of service,
+    /// eg to forward it over a bridge to a 3.1.1 server.
+    ///
+    /// The subscription identifier, user properties, and the subscription options that 3.1.1 doesn't have are dropped.
+    pub fn to_v3(&self) -> crate::v3::Subscribe<P>
+    where
+        P: Clone,
+    {
+        crate::v3::Subscribe {
+            packet_identifier: self.packet_identifier,
+            subscribe_to: self
+                .subscribe_to
+                .iter()
+                .map(|subscribe_to| crate::v3::SubscribeTo {
+                    topic_filter: subscribe_to.topic_filter.clone(),
+                    qos: subscribe_to.maximum_qos,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Splits a `$share/{ShareName}/{filter}` topic filter into its share name and topic filter.
+///
+/// Ref: 4.8.2 Shared Subscriptions
+fn split_shared_subscription(topic_filter: &str) -> Option<(&str, &str)> {
+    let rest = topic_filter.strip_prefix("$share/")?;
+    let separator = rest.find('/')?;
+    let (group, filter) = (&rest[..separator], &rest[(separator + 1)..]);
+    if group.is_empty() || group.contains(&['+', '#'][..]) || filter.is_empty() {
+        return None;
+    }
+
+    Some((group, filter))
+}
+
 impl<P> PacketMeta<P> for Subscribe<P>
 where
     P: Clone + BufferPool,
 {
     const PACKET_TYPE: u8 = 0x80;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let packet_identifier = src.try_get_packet_identifier()?;
 
         decode_properties!(
             src,
+            ctx,
             subscription_identifier: SubscriptionIdentifier,
             user_properties: Vec<UserProperty>,
         );
@@ -59,29 +230,17 @@ where
         let mut subscribe_to = vec![];
 
         while !src.is_empty() {
-            let topic_filter = ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?;
-
-            let options = src.try_get_u8()?;
+            let topic_filter = ByteStr::decode_in_packet(src)?;
 
-            let maximum_qos = (options & 0b0000_0011).try_into()?;
+            let options = SubscriptionOptions::from_byte(src.try_get_u8()?)?;
 
-            let no_local = (options & 0b0000_0100) != 0;
-
-            let retain_as_published = (options & 0b0000_1000) != 0;
-
-            let retain_handling = ((options & 0b0011_0000) >> 4).try_into()?;
-
-            if (options & 0b1100_0000) != 0 {
-                return Err(DecodeError::SubscriptionOptionsReservedSet);
+            // Ref: 3.8.3.1 Subscription Options
+            if options.no_local && split_shared_subscription(topic_filter.as_ref()).is_some() {
+                return Err(DecodeError::NoLocalOnSharedSubscription);
             }
 
-            subscribe_to.push(SubscribeTo {
-                topic_filter,
-                maximum_qos,
-                no_local,
-                retain_as_published,
-                retain_handling,
-            });
+            subscribe_to.push(SubscribeTo::new(topic_filter, options));
+            ctx.check_topics(subscribe_to.len())?;
         }
 
         if subscribe_to.is_empty() {
@@ -115,29 +274,295 @@ where
             user_properties: Vec<UserProperty>,
         );
 
-        for SubscribeTo {
-            topic_filter,
-            maximum_qos,
-            no_local,
-            retain_as_published,
-            retain_handling,
-        } in subscribe_to
-        {
-            topic_filter.encode(dst)?;
-
-            let mut subscription_options = 0_u8;
-            subscription_options |= u8::from(maximum_qos);
-            if no_local {
-                subscription_options |= 0b0000_0100;
+        for subscribe_to in subscribe_to {
+            let options = subscribe_to.options();
+            subscribe_to.topic_filter.encode(dst)?;
+            dst.try_put_u8(options.to_byte())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClassifiedFilter, RetainHandling, Subscribe, SubscribeTo, SubscriptionOptions};
+    use crate::tests::{byte_str, shared, TestBufferPool};
+    use crate::{DecodeContext, DecodeError, PacketIdentifier, QoS};
+
+    #[test]
+    fn to_v3() {
+        let packet = Subscribe {
+            packet_identifier: PacketIdentifier::new(5).unwrap(),
+            subscription_identifier: Some(3),
+            user_properties: vec![(byte_str("k"), byte_str("v"))],
+            subscribe_to: vec![
+                SubscribeTo {
+                    topic_filter: byte_str("a/+"),
+                    maximum_qos: QoS::ExactlyOnce,
+                    no_local: true,
+                    retain_as_published: true,
+                    retain_handling: RetainHandling::DoNotSend,
+                },
+                SubscribeTo {
+                    topic_filter: byte_str("$share/group/b/#"),
+                    maximum_qos: QoS::AtMostOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: RetainHandling::Send,
+                },
+            ],
+        };
+
+        assert_eq!(
+            packet.to_v3(),
+            crate::v3::Subscribe {
+                packet_identifier: PacketIdentifier::new(5).unwrap(),
+                subscribe_to: vec![
+                    crate::v3::SubscribeTo {
+                        topic_filter: byte_str("a/+"),
+                        qos: QoS::ExactlyOnce,
+                    },
+                    crate::v3::SubscribeTo {
+                        topic_filter: byte_str("$share/group/b/#"),
+                        qos: QoS::AtMostOnce,
+                    },
+                ],
             }
-            if retain_as_published {
-                subscription_options |= 0b0000_1000;
+        );
+    }
+
+    #[test]
+    fn options_eq() {
+        let options = SubscriptionOptions {
+            maximum_qos: QoS::AtLeastOnce,
+            no_local: true,
+            retain_as_published: false,
+            retain_handling: RetainHandling::SendOnlyIfSubscriptionDoesNotCurrentlyExist,
+        };
+        let subscribe_to = SubscribeTo::<TestBufferPool>::new(byte_str("a/b"), options);
+
+        assert!(subscribe_to.options_eq(&SubscribeTo::new(byte_str("c/#"), options)));
+
+        let other = SubscribeTo::new(
+            byte_str("a/b"),
+            SubscriptionOptions {
+                maximum_qos: QoS::ExactlyOnce,
+                ..options
+            },
+        );
+        assert!(!subscribe_to.options_eq(&other));
+    }
+
+    #[test]
+    fn topic_filters() {
+        let subscribe_to = |topic_filter| SubscribeTo {
+            topic_filter: byte_str(topic_filter),
+            maximum_qos: QoS::AtLeastOnce,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::Send,
+        };
+        let packet = Subscribe {
+            packet_identifier: PacketIdentifier::new(5).unwrap(),
+            subscription_identifier: None,
+            user_properties: vec![],
+            subscribe_to: vec![subscribe_to("a/+"), subscribe_to("$share/group/b/#")],
+        };
+
+        assert_eq!(
+            packet.topic_filters().collect::<Vec<_>>(),
+            ["a/+", "$share/group/b/#"]
+        );
+    }
+
+    #[test]
+    fn classified_filters() {
+        let subscribe_to = |topic_filter| SubscribeTo {
+            topic_filter: byte_str(topic_filter),
+            maximum_qos: QoS::AtLeastOnce,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: RetainHandling::Send,
+        };
+
+        let packet = Subscribe {
+            packet_identifier: PacketIdentifier::new(1).unwrap(),
+            subscription_identifier: None,
+            user_properties: vec![],
+            subscribe_to: vec![
+                subscribe_to("a/b"),
+                subscribe_to("$share/group1/a/+"),
+                subscribe_to("$share/group2/#"),
+                subscribe_to("$share/group3"),
+                subscribe_to("$share//a"),
+                subscribe_to("$SYS/#"),
+            ],
+        };
+
+        let filters: Vec<_> = packet.classified_filters().collect();
+        assert_eq!(
+            filters,
+            vec![
+                ClassifiedFilter::Normal(
+                    &packet.subscribe_to[0].topic_filter,
+                    &packet.subscribe_to[0],
+                ),
+                ClassifiedFilter::Shared {
+                    group: "group1",
+                    filter: "a/+",
+                    options: &packet.subscribe_to[1],
+                },
+                ClassifiedFilter::Shared {
+                    group: "group2",
+                    filter: "#",
+                    options: &packet.subscribe_to[2],
+                },
+                ClassifiedFilter::Normal(
+                    &packet.subscribe_to[3].topic_filter,
+                    &packet.subscribe_to[3],
+                ),
+                ClassifiedFilter::Normal(
+                    &packet.subscribe_to[4].topic_filter,
+                    &packet.subscribe_to[4],
+                ),
+                ClassifiedFilter::Normal(
+                    &packet.subscribe_to[5].topic_filter,
+                    &packet.subscribe_to[5],
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn subscription_options_round_trip() {
+        let mut legal = 0;
+        for byte in 0..=u8::MAX {
+            let qos_legal = (byte & 0b0000_0011) != 0b0000_0011;
+            let retain_handling_legal = (byte & 0b0011_0000) != 0b0011_0000;
+            if !qos_legal || !retain_handling_legal {
+                continue;
+            }
+
+            if (byte & 0b1100_0000) == 0 {
+                let options = SubscriptionOptions::from_byte(byte).unwrap();
+                assert_eq!(options.to_byte(), byte);
+
+                let subscribe_to = SubscribeTo::new(byte_str("a/b"), options);
+                assert_eq!(subscribe_to.options(), options);
+
+                legal += 1;
+            } else {
+                match SubscriptionOptions::from_byte(byte) {
+                    Err(DecodeError::SubscriptionOptionsReservedSet) => (),
+                    result => panic!("{:#04x}: {:?}", byte, result),
+                }
             }
-            subscription_options |= u8::from(retain_handling) << 4;
+        }
+        assert_eq!(legal, 36);
+    }
 
-            dst.try_put_u8(subscription_options)?;
+    #[test]
+    fn no_local_on_shared_subscription() {
+        let body = shared(
+            b"\
+                \x00\x01\
+                \x00\
+                \x00\x10$share/group/a/b\x05\
+            ",
+        );
+        match crate::v5::decode(0x82, body) {
+            Err(DecodeError::NoLocalOnSharedSubscription) => (),
+            result => panic!("{:?}", result),
         }
 
-        Ok(())
+        let body = shared(
+            b"\
+                \x00\x01\
+                \x00\
+                \x00\x03a/b\x05\
+            ",
+        );
+        crate::v5::decode(0x82, body).unwrap();
+    }
+
+    #[test]
+    fn decode_debug_truncated_topic_filter() {
+        let body = shared(
+            b"\
+                \x00\x01\
+                \x00\
+                \x00\x03a/\
+            ",
+        );
+        let ctx = DecodeContext::new(TestBufferPool);
+        match crate::v5::decode_debug(&ctx, 0x82, body) {
+            Err(crate::DecodeErrorAt {
+                error:
+                    DecodeError::StringLengthExceedsPacket {
+                        string_length: 3,
+                        remaining_length: 2,
+                    },
+                offset: 3,
+            }) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn too_many_topics() {
+        let body = b"\
+            \x00\x01\
+            \x00\
+            \x00\x01a\x01\
+            \x00\x01b\x01\
+            \x00\x01c\x01\
+        ";
+
+        let mut ctx = DecodeContext::new(TestBufferPool);
+        ctx.maximum_topics = Some(3);
+        match crate::v5::decode_with_context(&ctx, 0x82, shared(body)) {
+            Ok(crate::v5::Packet::Subscribe(packet)) => assert_eq!(packet.subscribe_to.len(), 3),
+            result => panic!("{:?}", result),
+        }
+
+        ctx.maximum_topics = Some(2);
+        match crate::v5::decode_with_context(&ctx, 0x82, shared(body)) {
+            Err(DecodeError::TooManyTopics(2)) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn duplicate_subscription_identifier() {
+        let body = shared(
+            b"\
+                \x00\x01\
+                \x04\
+                \x0B\x01\
+                \x0B\x02\
+                \x00\x03a/b\x01\
+            ",
+        );
+        match crate::v5::decode(0x82, body) {
+            Err(DecodeError::DuplicateSubscriptionIdentifier) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn subscription_identifier_too_large() {
+        let body = shared(
+            b"\
+                \x00\x01\
+                \x06\
+                \x0B\xFF\xFF\xFF\xFF\x01\
+                \x00\x03a/b\x01\
+            ",
+        );
+        match crate::v5::decode(0x82, body) {
+            Err(DecodeError::SubscriptionIdentifierTooLarge) => (),
+            result => panic!("{:?}", result),
+        }
     }
 }
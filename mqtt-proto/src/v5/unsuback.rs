@@ -3,7 +3,9 @@
 use std::convert::TryInto;
 
 use super::{PacketMeta, Property};
-use crate::{BufferPool, ByteBuf, ByteStr, DecodeError, EncodeError, PacketIdentifier, Shared};
+use crate::{
+    BufferPool, ByteBuf, ByteStr, DecodeContext, DecodeError, EncodeError, PacketIdentifier, Shared,
+};
 
 /// Ref: 3.11 UNSUBACK – Unsubscribe acknowledgement
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -36,15 +38,22 @@ where
 {
     const PACKET_TYPE: u8 = 0xB0;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let packet_identifier = src.try_get_packet_identifier()?;
 
         decode_properties!(
             src,
+            ctx,
             reason_string: ReasonString,
             user_properties: Vec<UserProperty>,
         );
 
+        ctx.check_reason_codes(src.len())?;
+
         let reason_codes: Result<Vec<_>, _> = src
             .as_ref()
             .iter()
@@ -1,7 +1,9 @@
 // Copyright (c) Microsoft. All rights reserved.
 
 use super::{PacketMeta, Property};
-use crate::{BufferPool, ByteBuf, ByteStr, DecodeError, EncodeError, PacketIdentifier, Shared};
+use crate::{
+    BufferPool, ByteBuf, ByteStr, DecodeContext, DecodeError, EncodeError, PacketIdentifier, Shared,
+};
 
 /// Ref: 3.10 UNSUBSCRIBE – Unsubscribe from topics
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -14,21 +16,55 @@ where
     pub unsubscribe_from: Vec<ByteStr<P>>,
 }
 
+impl<P> Unsubscribe<P>
+where
+    P: BufferPool,
+{
+    /// Constructs an UNSUBSCRIBE from the given topic filters.
+    ///
+    /// Returns an error if there are no topic filters.
+    pub fn new(
+        packet_identifier: PacketIdentifier,
+        filters: impl IntoIterator<Item = ByteStr<P>>,
+    ) -> Result<Self, EncodeError> {
+        let unsubscribe_from: Vec<_> = filters.into_iter().collect();
+        if unsubscribe_from.is_empty() {
+            return Err(EncodeError::NoTopics);
+        }
+
+        Ok(Unsubscribe {
+            packet_identifier,
+            user_properties: vec![],
+            unsubscribe_from,
+        })
+    }
+
+    /// Iterates over the topic filters of this packet as strings.
+    pub fn topic_filters(&self) -> impl Iterator<Item = &str> {
+        self.unsubscribe_from.iter().map(AsRef::as_ref)
+    }
+}
+
 impl<P> PacketMeta<P> for Unsubscribe<P>
 where
     P: Clone + BufferPool,
 {
     const PACKET_TYPE: u8 = 0xA0;
 
-    fn decode(_flags: u8, src: &mut Shared<P>) -> Result<Self, DecodeError> {
+    fn decode(
+        _flags: u8,
+        src: &mut Shared<P>,
+        ctx: &DecodeContext<P>,
+    ) -> Result<Self, DecodeError> {
         let packet_identifier = src.try_get_packet_identifier()?;
 
-        decode_properties!(src, user_properties: Vec<UserProperty>,);
+        decode_properties!(src, ctx, user_properties: Vec<UserProperty>,);
 
         let mut unsubscribe_from = vec![];
 
         while !src.is_empty() {
-            unsubscribe_from.push(ByteStr::decode(src)?.ok_or(DecodeError::IncompletePacket)?);
+            unsubscribe_from.push(ByteStr::decode_in_packet(src)?);
+            ctx.check_topics(unsubscribe_from.len())?;
         }
 
         if unsubscribe_from.is_empty() {
@@ -63,3 +99,33 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Unsubscribe;
+    use crate::tests::{byte_str, TestBufferPool};
+    use crate::v5::Packet;
+    use crate::{EncodeError, Owned, PacketIdentifier};
+
+    #[test]
+    fn new() {
+        let packet_identifier = PacketIdentifier::new(5).unwrap();
+
+        let packet =
+            Unsubscribe::new(packet_identifier, vec![byte_str("a/b"), byte_str("c/#")]).unwrap();
+        assert_eq!(packet.topic_filters().collect::<Vec<_>>(), ["a/b", "c/#"]);
+
+        let pool = TestBufferPool;
+        let mut bytes = Owned::new(pool, pool.take(32));
+        crate::v5::encode(Packet::Unsubscribe(packet), &mut bytes).unwrap();
+        assert_eq!(
+            bytes.filled(),
+            b"\xA2\x0D\x00\x05\x00\x00\x03a/b\x00\x03c/#"
+        );
+
+        match Unsubscribe::<TestBufferPool>::new(packet_identifier, vec![]) {
+            Err(EncodeError::NoTopics) => (),
+            result => panic!("{:?}", result),
+        }
+    }
+}